@@ -0,0 +1,41 @@
+//! Runs a CHIP-8 program without a window for a number of frames, then prints the screen.
+//!
+//! ```bash
+//! $ cargo run --release --example run_headless -- ibm.ch8 60
+//! ```
+
+use std::{env, fs, process};
+
+use cpu_emulator::{ascii, Chip8, Headless};
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let (Some(path), Some(frames)) = (args.next(), args.next()) else {
+        eprintln!("Usage: run_headless <program.ch8> <frames>");
+        process::exit(1);
+    };
+    let frames: usize = frames.parse().expect("The frame count must be a number.");
+
+    // Reads the program and loads it into a machine with no window.
+    let program = fs::read(path).unwrap();
+    let mut chip8 = Chip8::new(&program, Headless);
+
+    // Runs the program until the frames are used up or the program stops.
+    for _ in 0..frames {
+        match chip8.run_frame() {
+            Ok(None) => (),
+            Ok(Some(outcome)) => {
+                println!("Stopped: {:?}", outcome);
+                break;
+            },
+            Err(error) => {
+                println!("Error: {}", error);
+                break;
+            },
+        }
+    }
+
+    // Prints the framebuffer, with '#' for pixels that are on.
+    println!("{}", ascii::framebuffer(chip8.framebuffer()));
+}
@@ -0,0 +1,38 @@
+#![no_main]
+
+use cpu_emulator::CPU;
+use cpu_emulator::instruction::Variant;
+use cpu_emulator::quirks::ShiftSource;
+use libfuzzer_sys::fuzz_target;
+
+/// The most instructions run for each input, so that programs which loop forever still finish.
+const MAX_CYCLES: usize = 10_000;
+
+// The first byte of the input chooses the settings and the rest is copied over the whole of
+// memory from address 0, font included, so any opcode can be reached from anywhere. The cpu is
+// stepped until it stops, errors or runs out of cycles. Errors are fine, panics are not.
+fuzz_target!(|data: &[u8]| {
+    let Some((&settings, image)) = data.split_first() else {
+        return;
+    };
+
+    let mut cpu = CPU::new(&[]);
+    let len = image.len().min(cpu.memory.len());
+    cpu.memory[..len].copy_from_slice(&image[..len]);
+    cpu.strict = settings & 1 != 0;
+    cpu.variant = if settings & 2 != 0 { Variant::Chip8E } else { Variant::Chip8 };
+    cpu.quirks.shift = if settings & 4 != 0 { ShiftSource::Vy } else { ShiftSource::Vx };
+    cpu.quirks.display_wait = settings & 8 != 0;
+
+    for cycle in 0..MAX_CYCLES {
+        // Presses and lets go of a key every so often, so that FX0A is not stuck waiting forever.
+        cpu.set_keys(if cycle % 64 < 32 { 1 << (cycle / 64 % 16) } else { 0 });
+        match cpu.step() {
+            Ok(None) => {},
+            Ok(Some(_)) | Err(_) => break,
+        }
+        if cycle % 10 == 0 {
+            cpu.tick_timers();
+        }
+    }
+});
@@ -0,0 +1,34 @@
+/* C interface to the emulator, built with the ffi feature. See src/ffi.rs. */
+#ifndef CHIP8_H
+#define CHIP8_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define CHIP8_WIDTH 64
+#define CHIP8_HEIGHT 32
+
+/* Results of chip8_step. */
+#define CHIP8_RUNNING 0
+#define CHIP8_STOPPED 1
+#define CHIP8_ERROR (-1)
+
+typedef struct Chip8Handle Chip8Handle;
+
+/* Returns NULL if the program does not fit in memory. */
+Chip8Handle *chip8_create(const uint8_t *program, size_t len);
+void chip8_destroy(Chip8Handle *handle);
+
+int32_t chip8_step(Chip8Handle *handle);
+/* Call 60 times a second. */
+void chip8_tick_timers(Chip8Handle *handle);
+
+/* key is 0x0 to 0xF. */
+void chip8_key_down(Chip8Handle *handle, uint8_t key);
+void chip8_key_up(Chip8Handle *handle, uint8_t key);
+
+/* Copies up to len bytes, one per pixel, row by row, and returns the number copied. Returns 0 if
+   out is null. */
+size_t chip8_get_framebuffer(const Chip8Handle *handle, uint8_t *out, size_t len);
+
+#endif
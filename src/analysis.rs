@@ -0,0 +1,497 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::cpu::LOAD_ADDRESS;
+use crate::instruction::{decode, Instruction};
+
+/// Whether a byte of a program is part of an instruction that can be reached, or is data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// The first byte of an instruction that can be reached from the entry point.
+    Code,
+    /// The second byte of a reachable instruction.
+    Operand,
+    /// A byte that is never reached as an instruction, such as a sprite.
+    Data,
+}
+
+/// Walks the control flow of a program loaded at load_address from its first instruction, following
+/// jumps, calls and both sides of skips, and classifies each byte of the program as code or data.
+///
+/// Targets of `JP V0, nnn` depend on V0 at run time, so only nnn itself is followed.
+pub fn classify(program: &[u8], load_address: usize) -> Vec<Region> {
+    let mut regions = vec![Region::Data; program.len()];
+    let mut to_visit = vec![load_address];
+
+    while let Some(address) = to_visit.pop() {
+        // Skips addresses outside of the program, and instructions that have already been walked.
+        let Some(offset) = address.checked_sub(load_address) else { continue };
+        if offset + 1 >= program.len() || regions[offset] == Region::Code {
+            continue;
+        }
+
+        regions[offset] = Region::Code;
+        regions[offset + 1] = Region::Operand;
+
+        // F000 NNNN is followed by its two-byte address.
+        let opcode = (program[offset] as u16) << 8 | program[offset + 1] as u16;
+        if decode(opcode) == Instruction::LdILong {
+            for operand in regions.iter_mut().skip(offset + 2).take(2) {
+                *operand = Region::Operand;
+            }
+        }
+
+        to_visit.extend(successors(program, load_address, address, true));
+    }
+
+    regions
+}
+
+/// The addresses that the instruction at the address can go to next. Calls go to both the
+/// subroutine and the instruction after the call if follow_calls is true, and only to the
+/// instruction after it otherwise, as if the subroutine had returned. The address must be inside
+/// the program.
+fn successors(program: &[u8], load_address: usize, address: usize, follow_calls: bool) -> Vec<usize> {
+    let offset = address - load_address;
+    let opcode = (program[offset] as u16) << 8 | program[offset + 1] as u16;
+    // F000 NNNN is four bytes long.
+    let next = address + if decode(opcode) == Instruction::LdILong { 4 } else { 2 };
+
+    match decode(opcode) {
+        Instruction::Empty | Instruction::Ret | Instruction::Exit => vec![],
+        Instruction::Jp(nnn) | Instruction::JpV0(nnn) => vec![nnn as usize],
+        Instruction::Call(nnn) if follow_calls => vec![nnn as usize, next],
+        Instruction::SeVxByte(..)
+        | Instruction::SneVxByte(..)
+        | Instruction::SeVxVy(..)
+        | Instruction::SneVxVy(..)
+        | Instruction::Skp(_)
+        | Instruction::Sknp(_) => {
+            // Skipping over F000 NNNN skips all four of its bytes.
+            let skipped = next - load_address;
+            let long = program.get(skipped..skipped + 2) == Some(&[0xF0, 0x00]);
+            vec![next, next + if long { 4 } else { 2 }]
+        },
+        _ => vec![next],
+    }
+}
+
+/// Classifies a program loaded at load_address and formats a listing with one line per reachable instruction and one
+/// line per byte of data.
+pub fn listing(program: &[u8], load_address: usize) -> String {
+    let regions = classify(program, load_address);
+    let mut lines = Vec::new();
+
+    for (offset, region) in regions.iter().enumerate() {
+        let address = load_address + offset;
+        match region {
+            Region::Code => {
+                let opcode = (program[offset] as u16) << 8 | program[offset + 1] as u16;
+                match program.get(offset + 2..offset + 4) {
+                    Some(&[high, low]) if decode(opcode) == Instruction::LdILong => {
+                        let nnnn = (high as u16) << 8 | low as u16;
+                        lines.push(format!("0x{:03X} code F000 {:04X} LD I, 0x{:04X}", address, nnnn, nnnn));
+                    },
+                    _ => lines.push(format!("0x{:03X} code {:04X} {}", address, opcode, decode(opcode))),
+                }
+            },
+            Region::Operand => (),
+            Region::Data => lines.push(format!("0x{:03X} data {:02X}", address, program[offset])),
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Disassembles a program loaded at load_address from start to end, one line per instruction in
+/// the form `addr | bytes | mnemonic`. Unlike listing, this does not follow the control flow, so
+/// the bytes in data are written as DB only if they are inside one of the data ranges, which are
+/// addresses in memory. A byte that would start an instruction ending inside a data range or past
+/// the end of the program is also written as DB.
+pub fn disassemble(program: &[u8], load_address: usize, data: &[RangeInclusive<usize>]) -> String {
+    let is_data = |offset: usize| offset >= program.len() || data.iter().any(|range| range.contains(&(load_address + offset)));
+    let mut lines = Vec::new();
+
+    let mut offset = 0;
+    while offset < program.len() {
+        let address = load_address + offset;
+        if is_data(offset) || is_data(offset + 1) {
+            lines.push(format!("0x{:03X} | {:02X}          | DB 0x{:02X}", address, program[offset], program[offset]));
+            offset += 1;
+            continue;
+        }
+
+        let opcode = (program[offset] as u16) << 8 | program[offset + 1] as u16;
+        let long = decode(opcode) == Instruction::LdILong && !is_data(offset + 2) && !is_data(offset + 3);
+        if long {
+            let nnnn = (program[offset + 2] as u16) << 8 | program[offset + 3] as u16;
+            let bytes = format!("{:02X} {:02X} {:02X} {:02X}", program[offset], program[offset + 1], program[offset + 2], program[offset + 3]);
+            lines.push(format!("0x{:03X} | {} | LD I, 0x{:04X}", address, bytes, nnnn));
+            offset += 4;
+        } else {
+            lines.push(format!("0x{:03X} | {:02X} {:02X}       | {}", address, program[offset], program[offset + 1], decode(opcode)));
+            offset += 2;
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// A likely mistake in a program, found without running it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// A reachable instruction is not a CHIP-8 instruction.
+    UnknownOpcode { address: usize, opcode: u16 },
+    /// A jump or call goes to an address outside of the program.
+    OutsideProgram { address: usize, target: usize },
+    /// An instruction can use an address past the end of memory.
+    OutsideMemory { address: usize, target: usize },
+    /// A RET can be reached from the entry point without going through a CALL.
+    ReturnWithoutCall { address: usize },
+    /// A subroutine has no path that leads to a RET, so each call leaves an address on the stack.
+    NeverReturns { subroutine: usize },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::UnknownOpcode { address, opcode } => write!(f, "0x{:03X}: unknown opcode {:04X}", address, opcode),
+            Warning::OutsideProgram { address, target } => {
+                write!(f, "0x{:03X}: goes to 0x{:03X}, which is outside the program", address, target)
+            },
+            Warning::OutsideMemory { address, target } => {
+                write!(f, "0x{:03X}: can use 0x{:X}, which is past the end of memory", address, target)
+            },
+            Warning::ReturnWithoutCall { address } => write!(f, "0x{:03X}: RET can be reached without a CALL", address),
+            Warning::NeverReturns { subroutine } => write!(f, "0x{:03X}: the subroutine never returns", subroutine),
+        }
+    }
+}
+
+/// The addresses of the instructions reachable from start without going into subroutines.
+fn reachable(program: &[u8], load_address: usize, start: usize) -> Vec<usize> {
+    let mut seen = vec![false; program.len()];
+    let mut to_visit = vec![start];
+    let mut addresses = Vec::new();
+
+    while let Some(address) = to_visit.pop() {
+        let Some(offset) = address.checked_sub(load_address) else { continue };
+        if offset + 1 >= program.len() || seen[offset] {
+            continue;
+        }
+        seen[offset] = true;
+        addresses.push(address);
+        to_visit.extend(successors(program, load_address, address, false));
+    }
+
+    addresses
+}
+
+/// Walks the reachable instructions of a program loaded at load_address, without running it, and looks for unknown
+/// opcodes, addresses outside of the program or memory, and calls and returns that do not match
+/// up. The stack checks only follow the paths through the code, not the values of registers, so
+/// they can be wrong about programs that decide whether to return at run time.
+pub fn validate(program: &[u8], load_address: usize) -> Vec<Warning> {
+    let regions = classify(program, load_address);
+    let end = load_address + program.len();
+    let mut warnings = Vec::new();
+    let mut subroutines = Vec::new();
+
+    for (offset, _) in regions.iter().enumerate().filter(|(_, region)| **region == Region::Code) {
+        let address = load_address + offset;
+        let opcode = (program[offset] as u16) << 8 | program[offset + 1] as u16;
+        match decode(opcode) {
+            Instruction::Unknown(opcode) => warnings.push(Warning::UnknownOpcode { address, opcode }),
+            Instruction::Jp(nnn) | Instruction::Call(nnn) if !(load_address..end).contains(&(nnn as usize)) => {
+                warnings.push(Warning::OutsideProgram { address, target: nnn as usize });
+            },
+            // V0 can be up to 0xFF, which takes nnn past the end of memory.
+            Instruction::JpV0(nnn) if nnn as usize + 0xFF >= 0x1000 => {
+                warnings.push(Warning::OutsideMemory { address, target: nnn as usize + 0xFF });
+            },
+            Instruction::LdILong => {
+                if let Some(&[high, low]) = program.get(offset + 2..offset + 4) {
+                    let nnnn = (high as usize) << 8 | low as usize;
+                    if nnnn >= 0x1000 {
+                        warnings.push(Warning::OutsideMemory { address, target: nnnn });
+                    }
+                }
+            },
+            _ => (),
+        }
+        if let Instruction::Call(nnn) = decode(opcode) {
+            if !subroutines.contains(&(nnn as usize)) {
+                subroutines.push(nnn as usize);
+            }
+        }
+    }
+
+    let is_ret = |address: &usize| {
+        let offset = address - load_address;
+        decode((program[offset] as u16) << 8 | program[offset + 1] as u16) == Instruction::Ret
+    };
+    for address in reachable(program, load_address, load_address).into_iter().filter(is_ret) {
+        warnings.push(Warning::ReturnWithoutCall { address });
+    }
+    subroutines.sort();
+    for subroutine in subroutines.into_iter().filter(|&subroutine| (load_address..end).contains(&subroutine)) {
+        if !reachable(program, load_address, subroutine).iter().any(is_ret) {
+            warnings.push(Warning::NeverReturns { subroutine });
+        }
+    }
+
+    warnings
+}
+
+/// A CHIP-8 variant that a program may have been written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    /// The original COSMAC VIP interpreter.
+    Chip8,
+    /// SUPER-CHIP on the HP48 calculators.
+    SuperChip,
+    /// Octo's XO-CHIP extensions.
+    XoChip,
+}
+
+/// A guess at the platform a program was written for, and which quirks are likely to matter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuirkSuggestion {
+    /// The platform that the opcodes used by the program point to.
+    pub platform: Platform,
+    /// Notes on opcodes whose behaviour differs between interpreters.
+    pub notes: Vec<String>,
+}
+
+/// Scans the reachable instructions of a program loaded at load_address and suggests the platform and quirk settings it
+/// most likely expects. This is a heuristic, so it will not always be right:
+///
+/// - F000, F002, FX3A, 5XY2, 5XY3 and 00DN are only on XO-CHIP.
+/// - 00FF, 00FE, 00FB, 00FC, 00FD, 00CN, FX30, FX75 and FX85 were added by SUPER-CHIP.
+/// - 8XY6 and 8XYE with X != Y behave differently depending on whether VX or VY is shifted.
+/// - BNNN jumps using V0 or VX depending on the interpreter.
+/// - FX55 and FX65 leave I unchanged on some interpreters and increment it on others.
+pub fn suggest_quirks(program: &[u8], load_address: usize) -> QuirkSuggestion {
+    let regions = classify(program, load_address);
+    let opcodes = regions.iter().enumerate()
+        .filter(|(_, region)| **region == Region::Code)
+        .map(|(offset, _)| (program[offset] as u16) << 8 | program[offset + 1] as u16);
+
+    let mut xo_chip = false;
+    let mut super_chip = false;
+    let (mut shifts, mut jumps, mut loads) = (0, 0, 0);
+
+    for opcode in opcodes {
+        let (c, x, y, d) = (opcode >> 12, (opcode >> 8) & 0xF, (opcode >> 4) & 0xF, opcode & 0xF);
+        match (c, x, y, d) {
+            (0xF, 0, 0, 0) | (0xF, 0, 0, 0x2) | (0xF, _, 0x3, 0xA) | (0x5, _, _, 0x2) | (0x5, _, _, 0x3)
+            | (0, 0, 0xD, _) => xo_chip = true,
+            (0, 0, 0xF, 0xB..=0xF) | (0, 0, 0xC, _) | (0xF, _, 0x3, 0) | (0xF, _, 0x7, 0x5) | (0xF, _, 0x8, 0x5) => {
+                super_chip = true
+            },
+            (0x8, _, _, 0x6) | (0x8, _, _, 0xE) if x != y => shifts += 1,
+            (0xB, _, _, _) => jumps += 1,
+            (0xF, _, 0x5, 0x5) | (0xF, _, 0x6, 0x5) => loads += 1,
+            _ => (),
+        }
+    }
+
+    let platform = if xo_chip {
+        Platform::XoChip
+    } else if super_chip {
+        Platform::SuperChip
+    } else {
+        Platform::Chip8
+    };
+
+    let mut notes = Vec::new();
+    if shifts > 0 {
+        notes.push(format!("{} shifts use a different VX and VY, so the shift quirk matters.", shifts));
+    }
+    if jumps > 0 {
+        notes.push(format!("{} BNNN jumps, so the jump quirk matters.", jumps));
+    }
+    if loads > 0 {
+        notes.push(format!("{} FX55/FX65 loads and stores, so the memory quirk may matter.", loads));
+    }
+
+    QuirkSuggestion { platform, notes }
+}
+
+/// The address that ETI-660 programs are loaded at.
+pub const ETI_660_LOAD_ADDRESS: usize = 0x600;
+
+/// Guesses the address that a program expects to be loaded at, so that programs for other
+/// interpreters can be run without looking the address up. The heuristics are:
+///
+/// - Programs that start with `1260` are hi-res 64x64 CHIP-8 programs. The jump skips over the
+///   hi-res interpreter patch, and they still load at 0x200.
+/// - Programs too big to fit in memory above 0x600 can only load at 0x200.
+/// - Otherwise the absolute targets of the jumps, calls and `LD I, nnn` instructions are compared
+///   against the program loaded at 0x200 and at 0x600. ETI-660 programs load at 0x600, so their
+///   targets only land inside the program when it is loaded there.
+/// - If neither is a better fit, 0x200 is used.
+pub fn detect_load_address(program: &[u8]) -> usize {
+    explain_load_address(program).0
+}
+
+/// Guesses the load address like detect_load_address, and also returns which of the heuristics
+/// decided it, for telling the user.
+pub fn explain_load_address(program: &[u8]) -> (usize, &'static str) {
+    if program.starts_with(&[0x12, 0x60]) {
+        return (LOAD_ADDRESS, "it starts with the jump over the hi-res patch");
+    }
+    if ETI_660_LOAD_ADDRESS + program.len() > 0x1000 {
+        return (LOAD_ADDRESS, "it is too big to load at 0x600");
+    }
+
+    let targets = program.chunks_exact(2)
+        .filter_map(|bytes| match decode((bytes[0] as u16) << 8 | bytes[1] as u16) {
+            Instruction::Jp(nnn) | Instruction::Call(nnn) | Instruction::LdI(nnn) => Some(nnn as usize),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let fits = |start: usize| {
+        targets.iter().filter(|target| (start..start + program.len()).contains(*target)).count()
+    };
+
+    if fits(ETI_660_LOAD_ADDRESS) > fits(LOAD_ADDRESS) {
+        (ETI_660_LOAD_ADDRESS, "more of its addresses land inside it when it is loaded there")
+    } else {
+        (LOAD_ADDRESS, "its addresses do not fit better at 0x600")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles opcodes into a program.
+    fn program(opcodes: &[u16]) -> Vec<u8> {
+        opcodes.iter().flat_map(|opcode| opcode.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn chip8_opcodes_suggest_chip8() {
+        let suggestion = suggest_quirks(&program(&[0x00E0, 0x6001, 0xA300, 0xD015, 0x1208]), LOAD_ADDRESS);
+        assert_eq!(suggestion, QuirkSuggestion { platform: Platform::Chip8, notes: vec![] });
+    }
+
+    #[test]
+    fn superchip_opcodes_suggest_superchip() {
+        for opcode in [0x00FD, 0x00FF, 0x00C4, 0xF130, 0xF175, 0xF185] {
+            assert_eq!(suggest_quirks(&program(&[0x6001, opcode, 0x1204]), LOAD_ADDRESS).platform, Platform::SuperChip, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn xochip_opcodes_suggest_xochip_over_superchip() {
+        for opcode in [0xF002, 0xF13A, 0x5012, 0x5013, 0x00D4] {
+            assert_eq!(suggest_quirks(&program(&[0x00FF, opcode, 0x1204]), LOAD_ADDRESS).platform, Platform::XoChip, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn opcodes_that_depend_on_quirks_are_noted() {
+        let suggestion = suggest_quirks(&program(&[0x8016, 0x801E, 0x8006, 0xF155, 0xB300]), LOAD_ADDRESS);
+        assert_eq!(suggestion.platform, Platform::Chip8);
+        assert_eq!(suggestion.notes, vec![
+            "2 shifts use a different VX and VY, so the shift quirk matters.".to_string(),
+            "1 BNNN jumps, so the jump quirk matters.".to_string(),
+            "1 FX55/FX65 loads and stores, so the memory quirk may matter.".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn unreachable_opcodes_are_not_counted() {
+        // The jump goes over the SUPER-CHIP opcode, which is data.
+        assert_eq!(suggest_quirks(&program(&[0x1204, 0x00FF, 0x1204]), LOAD_ADDRESS).platform, Platform::Chip8);
+    }
+
+    #[test]
+    fn hi_res_programs_load_at_0x200() {
+        // Its addresses would otherwise fit better at 0x600.
+        let program = program(&[0x1260, 0xA604, 0x1602]);
+        assert_eq!(explain_load_address(&program), (LOAD_ADDRESS, "it starts with the jump over the hi-res patch"));
+    }
+
+    #[test]
+    fn programs_too_big_for_0x600_load_at_0x200() {
+        let mut program = program(&[0xA604, 0x1602]);
+        program.resize(0x1000 - ETI_660_LOAD_ADDRESS + 1, 0);
+        assert_eq!(explain_load_address(&program), (LOAD_ADDRESS, "it is too big to load at 0x600"));
+    }
+
+    #[test]
+    fn programs_whose_addresses_fit_at_0x600_load_there() {
+        let program = program(&[0xA608, 0x2606, 0x1604, 0x00EE, 0x0000]);
+        assert_eq!(
+            explain_load_address(&program),
+            (ETI_660_LOAD_ADDRESS, "more of its addresses land inside it when it is loaded there")
+        );
+    }
+
+    #[test]
+    fn other_programs_load_at_0x200() {
+        for program in [program(&[0xA208, 0x2206, 0x1204, 0x00EE]), program(&[0x6001, 0x00E0]), vec![]] {
+            assert_eq!(explain_load_address(&program), (LOAD_ADDRESS, "its addresses do not fit better at 0x600"));
+            assert_eq!(detect_load_address(&program), LOAD_ADDRESS);
+        }
+    }
+
+    #[test]
+    fn programs_without_mistakes_have_no_warnings() {
+        // Calls a subroutine that returns, then loops.
+        assert_eq!(validate(&program(&[0x2206, 0x1202, 0x0000, 0x00EE]), LOAD_ADDRESS), vec![]);
+    }
+
+    #[test]
+    fn reachable_unknown_opcodes_are_warned_about() {
+        // The second unknown opcode is jumped over, so it is data.
+        let warnings = validate(&program(&[0xE000, 0x1206, 0xFFFF, 0x1206]), LOAD_ADDRESS);
+        assert_eq!(warnings, vec![Warning::UnknownOpcode { address: 0x200, opcode: 0xE000 }]);
+    }
+
+    #[test]
+    fn jumps_and_calls_outside_the_program_are_warned_about() {
+        let warnings = validate(&program(&[0x2300, 0x11FE, 0x1204]), LOAD_ADDRESS);
+        assert_eq!(warnings, vec![
+            Warning::OutsideProgram { address: 0x200, target: 0x300 },
+            Warning::OutsideProgram { address: 0x202, target: 0x1FE },
+        ]);
+    }
+
+    #[test]
+    fn addresses_past_the_end_of_memory_are_warned_about() {
+        let warnings = validate(&program(&[0xF000, 0x1234, 0xBF80]), LOAD_ADDRESS);
+        assert_eq!(warnings, vec![
+            Warning::OutsideMemory { address: 0x200, target: 0x1234 },
+            Warning::OutsideMemory { address: 0x204, target: 0xF80 + 0xFF },
+        ]);
+    }
+
+    #[test]
+    fn returns_without_a_call_are_warned_about() {
+        let warnings = validate(&program(&[0x6001, 0x00EE]), LOAD_ADDRESS);
+        assert_eq!(warnings, vec![Warning::ReturnWithoutCall { address: 0x202 }]);
+    }
+
+    #[test]
+    fn subroutines_that_never_return_are_warned_about() {
+        // The subroutine at 0x204 loops forever instead of returning.
+        let warnings = validate(&program(&[0x2204, 0x1202, 0x1204]), LOAD_ADDRESS);
+        assert_eq!(warnings, vec![Warning::NeverReturns { subroutine: 0x204 }]);
+    }
+
+    #[test]
+    fn validation_uses_the_load_address() {
+        // Fine at 0x600, but every target is outside the program at 0x200.
+        let program = program(&[0x2606, 0x1602, 0x0000, 0x00EE]);
+        assert_eq!(validate(&program, ETI_660_LOAD_ADDRESS), vec![]);
+        assert_eq!(validate(&program, LOAD_ADDRESS), vec![
+            Warning::OutsideProgram { address: 0x200, target: 0x606 },
+            Warning::OutsideProgram { address: 0x202, target: 0x602 },
+        ]);
+        assert!(listing(&program, ETI_660_LOAD_ADDRESS).starts_with("0x600 code 2606 CALL 0x606\n0x602 code 1602"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::ops::Range;
+
+use crate::cpu::{WIDTH, HEIGHT};
+use crate::font::{FONT_BASE, LARGE_FONT_BASE};
+
+/// Renders each byte of a sprite as a row of '#' for set bits and '.' for clear bits, keeping the
+/// leftmost `width` bits of each row.
+pub fn sprite(bytes: &[u8], width: usize) -> Vec<String> {
+    bytes.iter()
+        .map(|byte| (0..width).map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' }).collect())
+        .collect()
+}
+
+/// Renders count sprites of height bytes each, one after another in memory from address, as they
+/// would be drawn by DXYN. Each sprite is labelled with its address, and sprites past the end of
+/// memory are left out.
+pub fn sprites(memory: &[u8], address: usize, count: usize, height: usize) -> String {
+    (0..count)
+        .map(|i| address + i * height)
+        .take_while(|start| start + height <= memory.len())
+        .map(|start| {
+            let mut lines = vec![format!("0x{:03X}", start)];
+            lines.extend(sprite(&memory[start..start + height], 8));
+            lines.join("\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Renders the framebuffer as 32 lines of 64 characters, with '#' for pixels that are on.
+pub fn framebuffer(framebuffer: &[bool; WIDTH * HEIGHT]) -> String {
+    framebuffer.chunks(WIDTH)
+        .map(|row| row.iter().map(|&on| if on { '#' } else { '.' }).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders the 16 hexadecimal digit sprites of both fonts in memory, the small font at FONT_BASE
+/// and then the large one at LARGE_FONT_BASE, with each digit labelled above its sprite. The large
+/// digits are split over two rows of 8 so that they fit in 80 columns.
+pub fn font(memory: &[u8]) -> String {
+    let small = glyphs(memory, FONT_BASE, 0..16, SMALL_GLYPH.0, SMALL_GLYPH.1);
+    let large_low = glyphs(memory, LARGE_FONT_BASE, 0..8, LARGE_GLYPH.0, LARGE_GLYPH.1);
+    let large_high = glyphs(memory, LARGE_FONT_BASE, 8..16, LARGE_GLYPH.0, LARGE_GLYPH.1);
+    [small, large_low, large_high].join("\n\n")
+}
+
+/// The width and height of a glyph in the small font.
+const SMALL_GLYPH: (usize, usize) = (4, 5);
+/// The width and height of a glyph in the large font.
+const LARGE_GLYPH: (usize, usize) = (8, 10);
+
+/// Renders the digits of the font at base side by side, each labelled above its sprite.
+fn glyphs(memory: &[u8], base: usize, digits: Range<usize>, width: usize, height: usize) -> String {
+    let sprites: Vec<Vec<String>> = digits.clone()
+        .map(|digit| sprite(&memory[base + digit * height..base + (digit + 1) * height], width))
+        .collect();
+
+    let mut lines = vec![digits.map(|digit| format!("{:<width$X}", digit)).collect::<Vec<_>>().join(" ")];
+    for row in 0..height {
+        lines.push(sprites.iter().map(|glyph| glyph[row].as_str()).collect::<Vec<_>>().join(" "));
+    }
+
+    lines.iter().map(|line| line.trim_end()).collect::<Vec<_>>().join("\n")
+}
+
+/// Asserts that the framebuffer matches expected, which is drawn like framebuffer draws it. Rows and
+/// columns that expected leaves out are taken to be off, and leading whitespace on each line is
+/// ignored so that expected can be indented. On a mismatch this panics with the actual and expected
+/// frames side by side, with a '>' next to each row that differs.
+#[cfg(test)]
+#[track_caller]
+pub(crate) fn assert_frame_eq(actual: &[bool; WIDTH * HEIGHT], expected: &str) {
+    let lines: Vec<&str> = expected.trim_matches('\n').lines().map(str::trim_start).collect();
+    let mut wanted = [false; WIDTH * HEIGHT];
+    for (y, line) in lines.iter().enumerate().take(HEIGHT) {
+        for (x, c) in line.chars().enumerate().take(WIDTH) {
+            wanted[y * WIDTH + x] = c == '#';
+        }
+    }
+
+    if *actual == wanted {
+        return;
+    }
+
+    let diff: Vec<String> = framebuffer(actual).lines().zip(framebuffer(&wanted).lines())
+        .map(|(got, want)| format!("{} {} | {}", if got == want { ' ' } else { '>' }, got, want))
+        .collect();
+    panic!("the frame does not match, actual on the left and expected on the right:\n{}", diff.join("\n"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_frames_pass() {
+        let mut frame = [false; WIDTH * HEIGHT];
+        frame[WIDTH + 2] = true;
+        assert_frame_eq(&frame, "
+            ........
+            ..#
+        ");
+    }
+
+    #[test]
+    #[should_panic(expected = ">")]
+    fn differing_frames_panic_with_a_diff() {
+        assert_frame_eq(&[false; WIDTH * HEIGHT], "#");
+    }
+
+    #[test]
+    fn the_framebuffer_is_32_lines_of_64_characters() {
+        let mut frame = [false; WIDTH * HEIGHT];
+        frame[0] = true;
+        frame[WIDTH * HEIGHT - 1] = true;
+        let text = framebuffer(&frame);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert!(lines.iter().all(|line| line.len() == 64 && line.chars().all(|c| c == '#' || c == '.')));
+        assert_eq!(lines[0], format!("#{}", ".".repeat(63)));
+        assert_eq!(lines[31], format!("{}#", ".".repeat(63)));
+        assert_eq!(text.matches('#').count(), 2);
+    }
+
+    #[test]
+    fn sprite_renders_the_leftmost_bits() {
+        assert_eq!(sprite(&[0xF0, 0x90], 4), vec!["####", "#..#"]);
+    }
+
+    #[test]
+    fn the_font_dump_shows_both_fonts() {
+        let dump = font(&crate::cpu::CPU::new(&[]).memory);
+        let blocks: Vec<Vec<&str>> = dump.split("\n\n").map(|block| block.lines().collect()).collect();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].len(), 6);
+        assert_eq!(blocks[0][1], "#### ..#. #### #### #..# #### #### #### #### #### #### ###. #### ###. #### ####");
+        assert_eq!(blocks[1][0], "0        1        2        3        4        5        6        7");
+        assert_eq!(blocks[2][0], "8        9        A        B        C        D        E        F");
+        assert_eq!(blocks[2].len(), 11);
+        assert!(blocks[2][1].starts_with("######## ######## .######."));
+    }
+
+    #[test]
+    fn a_digit_sprite_renders_as_its_pattern() {
+        let mut memory = [0; 0x1000];
+        memory[0x300..0x305].copy_from_slice(&crate::font::SMALL_FONT[5 * 2..5 * 3]);
+        assert_eq!(sprites(&memory, 0x300, 1, 5), "\
+            0x300\n\
+            ####....\n\
+            ...#....\n\
+            ####....\n\
+            #.......\n\
+            ####....");
+    }
+
+    #[test]
+    fn sprites_are_labelled_and_stop_at_the_end_of_memory() {
+        let mut memory = [0; 0x1000];
+        memory[0xFFC..].copy_from_slice(&[0x81, 0x42, 0x24, 0x18]);
+        assert_eq!(sprites(&memory, 0xFFC, 3, 2), "0xFFC\n#......#\n.#....#.\n\n0xFFE\n..#..#..\n...##...");
+    }
+}
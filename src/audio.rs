@@ -0,0 +1,324 @@
+use rand::Rng;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::cpu::CPU;
+
+/// The shape of the beep that is played while the sound timer is running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Waveform {
+    /// A harsh buzz, like the buzzer in the original machines.
+    #[default]
+    Square,
+    /// A pure tone.
+    Sine,
+    /// A softer tone than the square wave.
+    Triangle,
+    /// Random samples, ignoring the frequency.
+    Noise,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!("expected square, sine, triangle or noise, found {}", s)),
+        }
+    }
+}
+
+/// The frequency of the beep in Hz if one is not given.
+pub const DEFAULT_FREQUENCY: f64 = 440.0;
+
+/// A beep with a waveform and frequency, which an audio backend plays while the sound timer is
+/// running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tone {
+    pub waveform: Waveform,
+    /// The frequency in Hz.
+    pub frequency: f64,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Tone { waveform: Waveform::default(), frequency: DEFAULT_FREQUENCY }
+    }
+}
+
+impl Tone {
+    /// Fills the buffer with samples between -1 and 1 at the sample rate. start is the number of
+    /// samples that have already been played, so that consecutive buffers join up smoothly.
+    pub fn fill(&self, buffer: &mut [f32], sample_rate: u32, start: usize) {
+        let mut rng = rand::thread_rng();
+
+        for (i, sample) in buffer.iter_mut().enumerate() {
+            // How far through the current cycle of the wave the sample is, from 0 to 1.
+            let phase = ((start + i) as f64 * self.frequency / sample_rate as f64).fract();
+            *sample = match self.waveform {
+                Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+                Waveform::Sine => (phase * std::f64::consts::TAU).sin() as f32,
+                Waveform::Triangle => (1.0 - 4.0 * (phase - 0.5).abs()) as f32,
+                Waveform::Noise => rng.gen_range(-1.0..=1.0),
+            };
+        }
+    }
+}
+
+/// How loud the sound is, from 0 to 1. Square waves are harsh at full volume.
+pub const VOLUME: f32 = 0.25;
+
+/// How long the click played by Audio::click lasts, in seconds.
+pub const CLICK_LENGTH: f64 = 0.005;
+
+/// The pitch of the click in Hz. It is high so that it stands out from the beep.
+const CLICK_FREQUENCY: f64 = 2000.0;
+
+/// Somewhere that samples can be played, such as a sound card.
+pub trait Output {
+    /// The number of samples played each second.
+    fn sample_rate(&self) -> u32;
+
+    /// Queues samples between -1 and 1 to be played after the ones written before.
+    fn write(&mut self, samples: &[f32]);
+}
+
+/// Plays the sound of the cpu one frame at a time. While the sound timer is running this is the
+/// tone, or the XO-CHIP audio pattern at the pitch register's rate once a program has loaded one
+/// with F002. Silence is written the rest of the time, so that the output never runs dry.
+pub struct Audio {
+    pub output: Box<dyn Output>,
+    /// The beep played while the sound timer is running, unless there is an audio pattern.
+    pub tone: Tone,
+    /// The number of samples written so far.
+    samples: usize,
+    /// How far through the audio pattern playback has got, in bits.
+    position: f64,
+    /// The part of a sample owed from previous frames, as frames are not a whole number of samples.
+    debt: f64,
+    /// The number of samples of the click played so far, while one is playing.
+    click: Option<usize>,
+}
+
+impl Audio {
+    /// Plays the tone through the output.
+    pub fn new(output: Box<dyn Output>, tone: Tone) -> Audio {
+        Audio { output, tone, samples: 0, position: 0.0, debt: 0.0, click: None }
+    }
+
+    /// Starts a short tick, which is mixed in with the beep from the next frame on rather than
+    /// replacing it. A click while one is playing starts it again.
+    pub fn click(&mut self) {
+        self.click = Some(0);
+    }
+
+    /// Writes the sound for a frame of the given length, with the cpu as it is once the frame's
+    /// instructions have run.
+    pub fn play_frame(&mut self, cpu: &CPU, length: Duration) {
+        let sample_rate = self.output.sample_rate();
+        self.debt += length.as_secs_f64() * sample_rate as f64;
+        let mut buffer = vec![0.0; self.debt as usize];
+        self.debt %= 1.0;
+
+        if !cpu.sound_on() {
+            self.position = 0.0;
+        } else if cpu.audio_pattern == [0; 16] {
+            self.tone.fill(&mut buffer, sample_rate, self.samples);
+        } else {
+            let step = cpu.playback_rate() / sample_rate as f64;
+            for sample in &mut buffer {
+                *sample = if cpu.audio_sample(self.position as usize) { 1.0 } else { -1.0 };
+                self.position = (self.position + step) % 128.0;
+            }
+        }
+
+        for sample in &mut buffer {
+            *sample *= VOLUME;
+        }
+        if let Some(played) = self.click {
+            self.click = self.mix_click(&mut buffer, played);
+        }
+        self.samples += buffer.len();
+        self.output.write(&buffer);
+    }
+
+    /// Adds the click to the start of the buffer, carrying on from the sample it had got to, and
+    /// fading it out over its length. Returns how far it has got if it has not finished.
+    fn mix_click(&self, buffer: &mut [f32], played: usize) -> Option<usize> {
+        let sample_rate = self.output.sample_rate();
+        let length = (CLICK_LENGTH * sample_rate as f64) as usize;
+        let mut click = vec![0.0; length.saturating_sub(played).min(buffer.len())];
+        Tone { waveform: Waveform::Square, frequency: CLICK_FREQUENCY }.fill(&mut click, sample_rate, played);
+
+        for (i, (sample, tick)) in buffer.iter_mut().zip(&click).enumerate() {
+            let fade = 1.0 - (played + i) as f32 / length as f32;
+            *sample = (*sample + tick * VOLUME * fade).clamp(-1.0, 1.0);
+        }
+        Some(played + click.len()).filter(|&played| played < length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// An output that keeps the samples written to it.
+    struct Recorder {
+        sample_rate: u32,
+        samples: Rc<RefCell<Vec<f32>>>,
+    }
+
+    impl Output for Recorder {
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+
+        fn write(&mut self, samples: &[f32]) {
+            self.samples.borrow_mut().extend_from_slice(samples);
+        }
+    }
+
+    /// Plays frames of the cpu at 60 frames a second and returns the samples written.
+    fn play(cpu: &CPU, sample_rate: u32, frames: usize) -> Vec<f32> {
+        let samples = Rc::new(RefCell::new(Vec::new()));
+        let mut audio = Audio::new(Box::new(Recorder { sample_rate, samples: samples.clone() }), Tone::default());
+        for _ in 0..frames {
+            audio.play_frame(cpu, Duration::from_secs_f64(1.0 / 60.0));
+        }
+        samples.take()
+    }
+
+    #[test]
+    fn silence_is_played_without_the_sound_timer() {
+        assert_eq!(play(&CPU::new(&[]), 6000, 1), vec![0.0; 100]);
+    }
+
+    #[test]
+    fn a_part_of_a_sample_is_carried_over_to_the_next_frame() {
+        assert_eq!(play(&CPU::new(&[]), 100, 3).len(), 5);
+    }
+
+    #[test]
+    fn the_tone_is_played_while_the_sound_timer_runs() {
+        let mut cpu = CPU::new(&[]);
+        cpu.sound_timer = 1;
+        let samples = play(&cpu, 44100, 1);
+        assert_eq!(samples.len(), 735);
+        // A 440Hz square wave at 44100Hz is high for the first 50 samples of each cycle.
+        assert!(samples[..50].iter().all(|&sample| sample == VOLUME));
+        assert!(samples[51..100].iter().all(|&sample| sample == -VOLUME));
+    }
+
+    #[test]
+    fn the_audio_pattern_is_played_at_the_playback_rate() {
+        let mut cpu = CPU::new(&[]);
+        cpu.sound_timer = 1;
+        cpu.audio_pattern[0] = 0xF0;
+        // A pitch of 64 plays 4000 bits a second, so each sample is one bit at 4000Hz, and the
+        // 128-bit pattern loops.
+        let samples = play(&cpu, 4000, 3);
+        let bit = |i: usize| if i % 128 < 4 { VOLUME } else { -VOLUME };
+        assert_eq!(samples, (0..200).map(bit).collect::<Vec<_>>());
+
+        cpu.pitch = 112;
+        assert_eq!(cpu.playback_rate(), 8000.0);
+        let samples = play(&cpu, 4000, 1);
+        assert_eq!(&samples[..4], &[VOLUME, VOLUME, -VOLUME, -VOLUME]);
+    }
+
+    /// Two cycles of the waveform at 1000Hz, sampled 8 times a cycle.
+    fn two_cycles(waveform: Waveform) -> Vec<f32> {
+        let mut buffer = vec![0.0; 16];
+        Tone { waveform, frequency: 1000.0 }.fill(&mut buffer, 8000, 0);
+        buffer
+    }
+
+    /// Checks that the samples are the expected ones, give or take rounding.
+    #[track_caller]
+    fn assert_samples(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+            assert!((actual - expected).abs() < 1e-6, "sample {} is {}, expected {}", i, actual, expected);
+        }
+    }
+
+    #[test]
+    fn square_waves_alternate_between_two_levels() {
+        let cycle = [1.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0, -1.0];
+        assert_samples(&two_cycles(Waveform::Square), &[cycle, cycle].concat());
+    }
+
+    #[test]
+    fn sine_waves_rise_to_1_and_fall_to_minus_1() {
+        let h = std::f32::consts::FRAC_1_SQRT_2;
+        let cycle = [0.0, h, 1.0, h, 0.0, -h, -1.0, -h];
+        assert_samples(&two_cycles(Waveform::Sine), &[cycle, cycle].concat());
+    }
+
+    #[test]
+    fn triangle_waves_ramp_evenly_between_minus_1_and_1() {
+        let cycle = [-1.0, -0.5, 0.0, 0.5, 1.0, 0.5, 0.0, -0.5];
+        assert_samples(&two_cycles(Waveform::Triangle), &[cycle, cycle].concat());
+    }
+
+    #[test]
+    fn noise_is_random_within_range() {
+        let mut buffer = vec![0.0; 4000];
+        Tone { waveform: Waveform::Noise, frequency: 1000.0 }.fill(&mut buffer, 8000, 0);
+        assert!(buffer.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+        // The samples do not repeat with the frequency, and average out near 0.
+        assert_ne!(buffer[..8], buffer[8..16]);
+        let mean = buffer.iter().sum::<f32>() / buffer.len() as f32;
+        assert!(mean.abs() < 0.1, "the mean is {}", mean);
+    }
+
+    #[test]
+    fn consecutive_buffers_join_up() {
+        for waveform in [Waveform::Square, Waveform::Sine, Waveform::Triangle] {
+            let mut second = vec![0.0; 5];
+            Tone { waveform, frequency: 1000.0 }.fill(&mut second, 8000, 11);
+            assert_samples(&second, &two_cycles(waveform)[11..]);
+        }
+    }
+
+    #[test]
+    fn a_click_is_mixed_in_with_the_beep_and_fades_out() {
+        let samples = Rc::new(RefCell::new(Vec::new()));
+        let mut audio = Audio::new(Box::new(Recorder { sample_rate: 8000, samples: samples.clone() }), Tone::default());
+        let mut cpu = CPU::new(&[]);
+        cpu.sound_timer = 1;
+        audio.click();
+        // 5ms at 8000Hz is 40 samples, split over two frames of 20.
+        audio.play_frame(&cpu, Duration::from_secs_f64(0.0025));
+        audio.play_frame(&cpu, Duration::from_secs_f64(0.0025));
+        audio.play_frame(&cpu, Duration::from_secs_f64(0.0025));
+        let samples = samples.take();
+
+        // What is left once the beep is taken away is the click, which starts at full volume on
+        // top of the beep, carries on into the second frame, and is quieter each cycle.
+        let click: Vec<f32> = samples.iter().zip(beep(0..60)).map(|(sample, beep)| sample - beep).collect();
+        assert_eq!(click[0], VOLUME);
+        assert!(click[..40].iter().all(|&sample| sample != 0.0));
+        assert!((4..40).all(|i| click[i].abs() < click[i - 4].abs()));
+        assert!(click[40..].iter().all(|&sample| sample == 0.0));
+    }
+
+    /// The samples of the default beep at 8000Hz in the given range.
+    fn beep(range: std::ops::Range<usize>) -> Vec<f32> {
+        let mut buffer = vec![0.0; range.len()];
+        Tone::default().fill(&mut buffer, 8000, range.start);
+        buffer.iter().map(|sample| sample * VOLUME).collect()
+    }
+
+    #[test]
+    fn waveforms_are_parsed_from_their_names() {
+        assert_eq!("triangle".parse(), Ok(Waveform::Triangle));
+        assert_eq!("saw".parse::<Waveform>(), Err("expected square, sine, triangle or noise, found saw".to_string()));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::cpu::{WIDTH, HEIGHT};
+
+/// Something that can display the framebuffer of the cpu and read input from the user.
+pub trait Backend {
+    /// Displays the framebuffer. This is called at most once per frame, after all of the frame's
+    /// instructions have run, so a backend should show the whole framebuffer in one operation
+    /// rather than drawing individual sprites as they happen.
+    fn present(&mut self, framebuffer: &[bool; WIDTH * HEIGHT]);
+
+    /// Polls for input and returns the keys on the 16-key keypad that are held, with bit n set if
+    /// key n is held. This is called once per frame. Backends report the held state rather than
+    /// key presses, so that the cpu works out presses and releases the same way for every backend.
+    /// A backend that only sees key presses should report a key as held for the frame after it is
+    /// pressed.
+    fn keys(&mut self) -> u16;
+
+    /// Returns true if the user has asked to quit.
+    fn should_quit(&self) -> bool;
+
+    /// Returns the position of the pointer on the display, in display pixels, or None if it is not
+    /// over the display. Only asked when the mouse is turned on.
+    fn mouse(&mut self) -> Option<(u8, u8)> {
+        None
+    }
+
+    /// Returns true while the user is holding the rewind control. Only asked when rewinding is
+    /// turned on.
+    fn should_rewind(&self) -> bool {
+        false
+    }
+
+    /// Returns true while the user has paused the program. Nothing runs while it is paused, other
+    /// than single steps.
+    fn is_paused(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the user has asked to execute one instruction while paused. Asked once per
+    /// frame while paused, after keys.
+    fn should_step(&mut self) -> bool {
+        false
+    }
+
+    /// Returns true if the backend has the user's input focus. Backends with no idea of focus are
+    /// always focused.
+    fn is_focused(&mut self) -> bool {
+        true
+    }
+
+    /// Plays a short tick, separate from the sound timer's beep. Asked for after each DXYN that
+    /// collides when clicking on draws is turned on and there is no audio output to play it
+    /// through. Backends with no sound ignore it.
+    fn click(&mut self) {}
+
+    /// Shows a short description of what the cpu is doing, e.g. in the title bar. This is called
+    /// when it changes. Backends with nowhere to show it ignore it.
+    fn set_status(&mut self, _status: &str) {}
+}
+
+/// Allows the backend to be chosen at runtime.
+impl<B: Backend + ?Sized> Backend for Box<B> {
+    fn present(&mut self, framebuffer: &[bool; WIDTH * HEIGHT]) {
+        (**self).present(framebuffer)
+    }
+
+    fn keys(&mut self) -> u16 {
+        (**self).keys()
+    }
+
+    fn should_quit(&self) -> bool {
+        (**self).should_quit()
+    }
+
+    fn mouse(&mut self) -> Option<(u8, u8)> {
+        (**self).mouse()
+    }
+
+    fn should_rewind(&self) -> bool {
+        (**self).should_rewind()
+    }
+
+    fn is_paused(&self) -> bool {
+        (**self).is_paused()
+    }
+
+    fn should_step(&mut self) -> bool {
+        (**self).should_step()
+    }
+
+    fn is_focused(&mut self) -> bool {
+        (**self).is_focused()
+    }
+
+    fn click(&mut self) {
+        (**self).click()
+    }
+
+    fn set_status(&mut self, status: &str) {
+        (**self).set_status(status)
+    }
+}
+
+/// A backend with no window and no input, used for running programs headlessly.
+pub struct Headless;
+
+impl Backend for Headless {
+    fn present(&mut self, _framebuffer: &[bool; WIDTH * HEIGHT]) {}
+
+    fn keys(&mut self) -> u16 {
+        0
+    }
+
+    fn should_quit(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,1263 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::audio::Audio;
+use crate::backend::Backend;
+use crate::clock::{Clock, TokioClock};
+use crate::cpu::{Activity, CPU, RunOutcome, WIDTH, HEIGHT};
+use crate::error::Error;
+use crate::instruction::{decode_variant, Instruction};
+use crate::trace::{self, Record, TraceWriter};
+
+/// The number of instructions executed each second if a speed is not given.
+pub const DEFAULT_HZ: u32 = 600;
+/// The number of times the display is refreshed each second if a frame rate is not given.
+pub const DEFAULT_FPS: u32 = 60;
+/// The COSMAC VIP's 1802 ran at 1.7609MHz, and took 8 clock pulses for each machine cycle.
+pub const VIP_CYCLES_PER_SECOND: u32 = 1_760_900 / 8;
+/// The machine cycles of each frame that the VIP's 1861 display took for itself, reading 8 bytes
+/// of the framebuffer by DMA for each of its 128 lines.
+pub const VIP_DISPLAY_CYCLES: u32 = 8 * 128;
+/// The machine cycles that the VIP's interpreter took to fetch and decode an instruction, before
+/// running it.
+pub const VIP_FETCH_CYCLES: u32 = 40;
+/// The rate that the timers count down at if a rate is not given. It does not depend on the cpu
+/// speed or frame rate.
+pub const TIMER_HZ: u32 = 60;
+/// The most frames of time that one slow frame makes up for. Beyond this, e.g. after the window
+/// was dragged, the lost time is dropped rather than run all at once.
+pub const MAX_CATCH_UP: f64 = 4.0;
+
+/// Roughly how many machine cycles the COSMAC VIP's interpreter took over an instruction, including
+/// the fetch. DXYN's cost grows with the height of the sprite, and instructions that took different
+/// paths, e.g. skipping or not, are given an average. Instructions that the VIP did not have only
+/// cost the fetch. The figures are only meant to get a program's speed into the right range, not
+/// to be cycle exact.
+pub fn vip_cycles(instruction: Instruction) -> u32 {
+    use Instruction::*;
+    VIP_FETCH_CYCLES
+        + match instruction {
+            Cls => 1550,
+            Ret => 10,
+            Jp(_) | LdI(_) => 12,
+            Call(_) => 26,
+            SeVxByte(..) | SneVxByte(..) => 12,
+            SeVxVy(..) | SneVxVy(..) | Skp(_) | Sknp(_) => 14,
+            LdVxByte(..) => 6,
+            AddVxByte(..) | LdVxDt(_) | LdDtVx(_) | LdStVx(_) => 10,
+            LdVxVy(..) | Or(..) | And(..) | Xor(..) | AddVxVy(..) | Sub(..) | Shr(..) | Subn(..) | Shl(..) => 44,
+            JpV0(_) => 22,
+            Rnd(..) => 36,
+            Drw(_, _, n) => 26 + 70 * n as u32,
+            LdVxK(_) => 18,
+            AddIVx(_) | LdFVx(_) => 16,
+            LdBVx(_) => 80,
+            LdIVx(x) | LdVxI(x) => 14 + 14 * (x as u32 + 1),
+            _ => 0,
+        }
+}
+
+/// A CHIP-8 machine, made up of the cpu and the backend that it displays to and reads input from.
+pub struct Chip8<B: Backend> {
+    /// The state of the machine.
+    pub cpu: CPU,
+    /// Displays the framebuffer and provides the keypad input.
+    pub backend: B,
+    /// The number of instructions executed each second.
+    pub hz: u32,
+    /// Spends VIP_CYCLES_PER_SECOND, less the display's share, on instructions that each cost
+    /// vip_cycles, in place of hz instructions a second.
+    pub vip_timing: bool,
+    /// The number of times the display is refreshed each second.
+    pub fps: u32,
+    /// The number of times the delay and sound timers count down each second.
+    pub timer_hz: u32,
+    /// The number of frames skipped between each one that is displayed, for slow displays. The cpu
+    /// and timers still run every frame.
+    pub frameskip: u32,
+    /// If true, the program does not start until the backend has focus and a key is pressed.
+    pub wait_for_focus: bool,
+    /// Lets the program read the position of the mouse with Fx4D. This is not part of CHIP-8, so
+    /// it is off unless asked for.
+    pub mouse: bool,
+    /// Records every instruction that step() executes, if set.
+    pub trace: Option<TraceWriter>,
+    /// Plays the sound timer's beep and the XO-CHIP audio pattern, if set.
+    pub audio: Option<Audio>,
+    /// The number of frames that can be rewound, or 0 to turn rewinding off. Each frame keeps a
+    /// copy of the cpu, so nothing is copied unless this is set.
+    pub rewind_frames: usize,
+    /// Ends a frame early when the program is polling the keypad in a loop of EX9E or EXA1 and a
+    /// jump back to it. The keys only change between frames, so the rest of the frame would go
+    /// round the same loop, and leaving it out saves power without changing what the program does.
+    pub idle_polling: bool,
+    /// How long to stop for after each DXYN, which is shown straight away rather than at the end
+    /// of the frame, so that sprites can be watched being drawn one at a time. Zero turns it off.
+    /// The frame stops at the draw, and run_with_clock sleeps on its clock before running the rest
+    /// of it as part of the same frame.
+    pub draw_delay: Duration,
+    /// Plays a click after every DXYN that sets VF, as a cue for hits that can be heard rather than
+    /// seen. It is mixed into the audio output if there is one, and asked of the backend if not.
+    pub click_on_draw: bool,
+    /// How long after a key goes up or down that further changes to it are ignored, for keyboards
+    /// whose keys chatter. Zero turns it off.
+    pub input_delay: Duration,
+    /// The keys held after input_delay was applied.
+    debounced_keys: u16,
+    /// The time that each key last went up or down at, after input_delay was applied.
+    key_changes: [Option<Duration>; 16],
+    /// The time that input_delay is measured against. run_with_clock sets it from its clock before
+    /// each frame, and a frame run on its own moves it on by the frame's length.
+    time: Duration,
+    /// The cpu at the start of each of the last rewind_frames frames, oldest first.
+    snapshots: VecDeque<CPU>,
+    /// Set from outside the cpu loop, e.g. by a signal handler, to make run() return.
+    stop: Arc<AtomicBool>,
+    /// Instructions owed to the cpu from previous frames, when hz is not a multiple of fps.
+    cycle_debt: f64,
+    /// Timer ticks owed from previous frames, when fps is not a multiple of the timer rate.
+    timer_debt: f64,
+    /// The number of instructions executed since the machine was created.
+    cycles: u64,
+    /// The number of frames run since the machine was created.
+    frames: u64,
+    /// What the cpu was doing at the end of the last frame, as last shown by the backend.
+    activity: Option<Activity>,
+    /// Whether the last frame was run while the backend was paused.
+    paused: bool,
+    /// The instructions per second actually achieved by run(), averaged over recent frames.
+    effective_hz: f64,
+    /// The draw delay owed by a frame that stopped at a draw, which run_with_clock sleeps for
+    /// before carrying on with the frame.
+    pending_draw_delay: Option<Duration>,
+    /// The number of frames of time that the current frame stands for, as given to
+    /// run_frame_scaled.
+    frame_scale: f64,
+}
+
+impl<B: Backend> Chip8<B> {
+    /// Creates a machine with the program loaded into memory.
+    pub fn new(program: &[u8], backend: B) -> Chip8<B> {
+        Chip8::from_cpu(CPU::new(program), backend)
+    }
+
+    /// Approximates the timing of the COSMAC VIP: 60 frames a second, the VIP's instruction costs,
+    /// and the display wait quirk.
+    pub fn authentic(&mut self) {
+        self.vip_timing = true;
+        self.fps = TIMER_HZ;
+        self.cpu.quirks.display_wait = true;
+    }
+
+    /// Creates a machine from a cpu that has already been set up.
+    pub fn from_cpu(cpu: CPU, backend: B) -> Chip8<B> {
+        Chip8 {
+            cpu,
+            backend,
+            hz: DEFAULT_HZ,
+            vip_timing: false,
+            fps: DEFAULT_FPS,
+            timer_hz: TIMER_HZ,
+            frameskip: 0,
+            wait_for_focus: false,
+            mouse: false,
+            trace: None,
+            audio: None,
+            rewind_frames: 0,
+            idle_polling: false,
+            draw_delay: Duration::ZERO,
+            click_on_draw: false,
+            input_delay: Duration::ZERO,
+            debounced_keys: 0,
+            key_changes: [None; 16],
+            time: Duration::ZERO,
+            snapshots: VecDeque::new(),
+            stop: Arc::new(AtomicBool::new(false)),
+            cycle_debt: 0.0,
+            timer_debt: 0.0,
+            cycles: 0,
+            frames: 0,
+            activity: None,
+            paused: false,
+            effective_hz: 0.0,
+            pending_draw_delay: None,
+            frame_scale: 1.0,
+        }
+    }
+
+    /// Executes a single instruction. Returns the outcome if the program has stopped.
+    pub fn step(&mut self) -> Result<Option<RunOutcome>, Error> {
+        self.cycles += 1;
+        let Some(trace) = &mut self.trace else {
+            return self.cpu.step();
+        };
+
+        let pc = self.cpu.pc();
+        let memory = &self.cpu.memory;
+        let opcode = (memory[pc % memory.len()] as u16) << 8 | memory[(pc + 1) % memory.len()] as u16;
+        let draws = matches!(self.cpu.next_instruction(), Instruction::Drw(..));
+        let outcome = self.cpu.step();
+
+        let mut flags = 0;
+        if self.cpu.registers()[0xF] != 0 {
+            flags |= trace::FLAG_VF;
+        }
+        if draws {
+            flags |= trace::FLAG_DRAW;
+        }
+        if self.cpu.waiting_for_key.is_some() {
+            flags |= trace::FLAG_WAITING;
+        }
+        // A trace that cannot be written is dropped, rather than stopping the program.
+        if let Err(error) = trace.write(Record { pc: pc as u16, opcode, flags }) {
+            eprintln!("Stopped tracing: {}.", error);
+            self.trace = None;
+        }
+        outcome
+    }
+
+    /// The number of instructions executed since the machine was created.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The number of instructions per second that run() is actually achieving, averaged over the
+    /// last few frames. This is 0 until run() has finished a frame.
+    pub fn effective_hz(&self) -> f64 {
+        self.effective_hz
+    }
+
+    /// Executes one frame worth of instructions, counts the timers down for the time that the
+    /// frame takes, and displays the framebuffer if it changed. Returns the outcome if the program
+    /// has stopped.
+    ///
+    /// The keypad is read from the backend once at the start of the frame, so that FX0A, EX9E and
+    /// EXA1 all see the same key state for the whole frame.
+    ///
+    /// With rewinding turned on, a frame where the backend asks to rewind goes back one frame
+    /// instead of running.
+    pub fn run_frame(&mut self) -> Result<Option<RunOutcome>, Error> {
+        self.run_frame_scaled(1.0)
+    }
+
+    /// Runs a frame that stands for scale frames of time, executing and counting the timers down
+    /// scale times as much as run_frame. run() uses this to keep to hz when frames take longer
+    /// than they should.
+    ///
+    /// A frame that stops at a draw for the draw delay is left open, and the next call carries on
+    /// with the rest of it, without waiting for the delay, rather than starting a new frame.
+    pub fn run_frame_scaled(&mut self, scale: f64) -> Result<Option<RunOutcome>, Error> {
+        if self.pending_draw_delay.take().is_some() {
+            return self.continue_frame();
+        }
+
+        let keys = self.read_keys();
+        let keys = self.debounce(keys);
+        self.time += Duration::from_secs_f64(scale / self.fps as f64);
+        if self.backend.is_paused() {
+            return self.run_paused(keys);
+        }
+        self.paused = false;
+
+        if self.rewind_frames > 0 {
+            if self.backend.should_rewind() {
+                self.rewind();
+                return Ok(None);
+            }
+            if self.snapshots.len() >= self.rewind_frames {
+                self.snapshots.pop_front();
+            }
+            self.snapshots.push_back(self.cpu.clone());
+        }
+
+        self.cpu.set_keys(keys);
+        // The last position is kept while the pointer is off the display.
+        if self.mouse {
+            self.cpu.mouse = self.backend.mouse().or(self.cpu.mouse).or(Some((0, 0)));
+        }
+
+        // With VIP timing the debt is in machine cycles rather than instructions.
+        self.cycle_debt += if self.vip_timing {
+            (VIP_CYCLES_PER_SECOND as f64 / self.fps as f64 - VIP_DISPLAY_CYCLES as f64).max(0.0) * scale
+        } else {
+            self.hz as f64 / self.fps as f64 * scale
+        };
+        self.frame_scale = scale;
+        self.continue_frame()
+    }
+
+    /// Spends the cycle debt left in the current frame, then ends the frame, unless it stops at a
+    /// draw for the draw delay again. The keys, rewinding and the rest of the once a frame work are
+    /// left to run_frame_scaled, so the pieces of a frame split by draw delays still make up one
+    /// frame.
+    fn continue_frame(&mut self) -> Result<Option<RunOutcome>, Error> {
+        let mut outcome = None;
+        while outcome.is_none() && self.cycle_debt >= 1.0 {
+            // Nothing runs while FX0A waits for a key, and the time spent waiting is not made up
+            // for once a key arrives.
+            if self.cpu.waiting_for_key.is_some() {
+                self.cycle_debt %= 1.0;
+                break;
+            }
+            let instruction = self.cpu.next_instruction();
+            let (pc, draws) = (self.cpu.pc(), matches!(instruction, Instruction::Drw(..)));
+            outcome = self.step()?;
+            // An instruction can cost more than is left, and the next frame pays the rest.
+            self.cycle_debt -= if self.vip_timing { vip_cycles(instruction) as f64 } else { 1.0 };
+
+            // DXY0 draws nothing, so last_draw is only this instruction's if it has the same pc.
+            if draws && self.click_on_draw && self.cpu.last_draw.is_some_and(|draw| draw.pc == pc && draw.collided) {
+                match &mut self.audio {
+                    Some(audio) => audio.click(),
+                    None => self.backend.click(),
+                }
+            }
+            // The rest of the frame's instructions are run by the next call after the delay, with
+            // the cycle debt that is left. A draw that stops the program ends the frame instead.
+            if draws && !self.draw_delay.is_zero() && outcome.is_none() {
+                self.backend.present(&self.cpu.framebuffer);
+                self.cpu.frame_dirty = false;
+                self.pending_draw_delay = Some(self.draw_delay);
+                return Ok(None);
+            }
+
+            // With the display wait quirk, the rest of the frame is spent waiting after a draw.
+            if draws && self.cpu.quirks.display_wait {
+                self.cycle_debt %= 1.0;
+                break;
+            }
+            // The key test is run once each frame, so a key pressed during the frame is seen at
+            // the start of the next one.
+            if self.idle_polling && self.is_polling() {
+                self.cycle_debt %= 1.0;
+                break;
+            }
+        }
+
+        self.end_frame();
+        Ok(outcome)
+    }
+
+    /// Plays the frame's sound, counts the timers down, and shows the framebuffer and the status
+    /// if they changed.
+    fn end_frame(&mut self) {
+        let scale = self.frame_scale;
+        // Played before the timers count down, so that a sound timer of 1 is heard for a frame.
+        if let Some(audio) = &mut self.audio {
+            audio.play_frame(&self.cpu, Duration::from_secs_f64(scale / self.fps as f64));
+        }
+
+        self.timer_debt += self.timer_hz as f64 / self.fps as f64 * scale;
+        while self.timer_debt >= 1.0 {
+            self.cpu.tick_timers();
+            self.timer_debt -= 1.0;
+        }
+
+        // Skipped frames leave the framebuffer dirty, so changes are shown on the next frame that
+        // is displayed.
+        if self.cpu.frame_dirty && self.frames.is_multiple_of(self.frameskip as u64 + 1) {
+            self.backend.present(&self.cpu.framebuffer);
+            self.cpu.frame_dirty = false;
+        }
+        self.frames += 1;
+        if let Some(stats) = &mut self.cpu.draw_stats {
+            stats.end_frame();
+        }
+
+        let activity = self.cpu.activity();
+        if self.activity != Some(activity) {
+            self.backend.set_status(&activity.to_string());
+            self.activity = Some(activity);
+        }
+    }
+
+    /// Returns true if the next instruction jumps back to an EX9E or EXA1 just before it, so the
+    /// key test has just failed and will keep failing until the keys change.
+    fn is_polling(&self) -> bool {
+        let pc = self.cpu.program_counter;
+        if pc < 2 || self.cpu.next_instruction() != Instruction::Jp(pc as u16 - 2) {
+            return false;
+        }
+        // A skip at the end of memory leaves the pc past it, so the key test is read wrapping round
+        // like step reads instructions.
+        let memory = &self.cpu.memory;
+        let opcode = u16::from_be_bytes([memory[(pc - 2) % memory.len()], memory[(pc - 1) % memory.len()]]);
+        matches!(decode_variant(opcode, self.cpu.variant), Instruction::Skp(_) | Instruction::Sknp(_))
+    }
+
+    /// Runs a frame while the backend is paused. Nothing is executed unless the backend asks for a
+    /// single step, which shows the instruction it executed in the status, and the timers do not
+    /// count down.
+    fn run_paused(&mut self, keys: u16) -> Result<Option<RunOutcome>, Error> {
+        // Clearing the activity makes the status be shown again once the program carries on.
+        if !self.paused {
+            self.backend.set_status("paused");
+            self.paused = true;
+            self.activity = None;
+        }
+
+        let mut outcome = None;
+        if self.backend.should_step() {
+            self.cpu.set_keys(keys);
+            let (pc, instruction) = (self.cpu.pc(), self.cpu.next_instruction());
+            outcome = self.step()?;
+            self.backend.set_status(&format!("paused - 0x{:03X} {}", pc, instruction));
+        }
+
+        if self.cpu.frame_dirty {
+            self.backend.present(&self.cpu.framebuffer);
+            self.cpu.frame_dirty = false;
+        }
+        Ok(outcome)
+    }
+
+    /// Reads the keypad from the backend. No keys are held while the backend does not have focus,
+    /// so that keys typed into other windows are not seen by the program.
+    pub fn read_keys(&mut self) -> u16 {
+        // The keys are read even when they are not used, as that is what polls some backends.
+        let keys = self.backend.keys();
+        if self.backend.is_focused() {
+            keys
+        } else {
+            0
+        }
+    }
+
+    /// Ignores each change to a key that comes less than input_delay after the key last changed,
+    /// so that a key that chatters is seen as a single press. Presses and releases further apart
+    /// than that all get through.
+    fn debounce(&mut self, keys: u16) -> u16 {
+        if self.input_delay.is_zero() {
+            return keys;
+        }
+
+        // A clock handed over after frames were run on their own can start behind the times kept.
+        for key in 0..16 {
+            let changed = (keys ^ self.debounced_keys) & 1 << key != 0;
+            let settled = self.key_changes[key].is_none_or(|time| self.time.saturating_sub(time) >= self.input_delay);
+            if changed && settled {
+                self.debounced_keys ^= 1 << key;
+                self.key_changes[key] = Some(self.time);
+            }
+        }
+        self.debounced_keys
+    }
+
+    /// Puts the cpu back to how it was at the start of the last frame, and displays it. Does
+    /// nothing once there are no frames left to go back to.
+    pub fn rewind(&mut self) {
+        if let Some(cpu) = self.snapshots.pop_back() {
+            self.cpu = cpu;
+        }
+        // Presented even if nothing changed, so that the frame gone back to is always shown.
+        self.backend.present(&self.cpu.framebuffer);
+        self.cpu.frame_dirty = false;
+    }
+
+    /// The number of frames that can currently be rewound.
+    pub fn snapshots(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// The current contents of the display.
+    pub fn framebuffer(&self) -> &[bool; WIDTH * HEIGHT] {
+        &self.cpu.framebuffer
+    }
+
+    /// Returns a flag that makes run() return QuitByUser at the end of the current frame when it is
+    /// set. This lets a Ctrl-C handler shut the machine down cleanly.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Returns true if the user has asked to quit, either through the backend or the stop flag.
+    fn should_quit(&self) -> bool {
+        self.stop.load(Ordering::Relaxed) || self.backend.should_quit()
+    }
+
+    /// Contains the main cpu loop. Runs until the program stops or the user quits.
+    pub async fn run(&mut self) -> Result<RunOutcome, Error> {
+        self.run_with_clock(&mut TokioClock::new()).await
+    }
+
+    /// Runs the cpu loop, paced by the clock rather than real time. Runs until the program stops
+    /// or the user quits.
+    pub async fn run_with_clock(&mut self, clock: &mut impl Clock) -> Result<RunOutcome, Error> {
+        let frame = Duration::from_secs_f64(1.0 / self.fps as f64);
+        let mut next_frame = clock.now();
+
+        // Holds the program until the user is ready, so that input at the start of a game is not
+        // lost while the window is being focused.
+        if self.wait_for_focus {
+            self.backend.present(&self.cpu.framebuffer);
+            while !(self.backend.is_focused() && self.backend.keys() != 0) {
+                if self.should_quit() {
+                    return Ok(RunOutcome::QuitByUser);
+                }
+                wait_for_frame(clock, &mut next_frame, frame).await;
+            }
+        }
+
+        // Each frame is scaled by how long the last one actually took, so that a machine too slow
+        // to keep up with fps still runs hz instructions a second.
+        let mut frame_start = clock.now();
+        let mut scale = 1.0;
+        loop {
+            if self.should_quit() {
+                return Ok(RunOutcome::QuitByUser);
+            }
+            let cycles = self.cycles;
+            self.time = clock.now();
+            let mut outcome = self.run_frame_scaled(scale)?;
+            // A frame that stopped for the draw delay carries on afterwards, without any more time
+            // passing for the cpu or the timers. The delays are left out of the frame's time, so
+            // that the next frame does not try to catch up on them.
+            let mut delayed = Duration::ZERO;
+            while let (None, Some(delay)) = (outcome, self.pending_draw_delay.take()) {
+                clock.sleep(delay).await;
+                delayed += delay;
+                outcome = self.continue_frame()?;
+            }
+            if let Some(outcome) = outcome {
+                return Ok(outcome);
+            }
+            next_frame += delayed;
+            wait_for_frame(clock, &mut next_frame, frame).await;
+
+            let now = clock.now();
+            let elapsed = (now - frame_start - delayed).as_secs_f64();
+            frame_start = now;
+            scale = (elapsed / frame.as_secs_f64()).min(MAX_CATCH_UP);
+            if elapsed > 0.0 {
+                let hz = (self.cycles - cycles) as f64 / elapsed;
+                self.effective_hz = if self.effective_hz == 0.0 { hz } else { self.effective_hz * 0.9 + hz * 0.1 };
+            }
+        }
+    }
+}
+
+/// Sleeps until the start of the next frame. If the loop has fallen behind, it carries on from
+/// now rather than running several frames at once to catch up.
+async fn wait_for_frame(clock: &mut impl Clock, next_frame: &mut Duration, frame: Duration) {
+    *next_frame += frame;
+    let now = clock.now();
+    if *next_frame > now {
+        clock.sleep(*next_frame - now).await;
+    } else {
+        *next_frame = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Headless;
+    use crate::ascii::assert_frame_eq;
+    use crate::clock::ManualClock;
+    use crate::instruction::Variant;
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::rc::Rc;
+
+    /// A backend that holds the keys given for each frame in turn, and no keys once they run out.
+    #[derive(Default)]
+    struct Scripted {
+        keys: VecDeque<u16>,
+        /// The number of times the keys were read.
+        reads: usize,
+        /// Asks to quit once the keys have been read this many times.
+        quit_after: Option<usize>,
+        /// Every framebuffer presented, in order.
+        presented: Vec<[bool; WIDTH * HEIGHT]>,
+        /// The number of times that the backend says it does not have focus before it gets it.
+        unfocused_for: usize,
+        /// Loses focus once the keys have been read this many times.
+        focus_lost_after: Option<usize>,
+        /// Sets the flag once the keys have been read this many times.
+        stop_after: Option<(usize, Arc<AtomicBool>)>,
+        /// Every status shown, in order.
+        statuses: Vec<String>,
+        /// Where the mouse is over the display.
+        pointer: Option<(u8, u8)>,
+        paused: bool,
+        /// Whether to ask for a single step, for each frame in turn while paused.
+        steps: VecDeque<bool>,
+    }
+
+    impl Scripted {
+        fn new(keys: &[u16]) -> Scripted {
+            Scripted { keys: keys.iter().copied().collect(), ..Scripted::default() }
+        }
+    }
+
+    impl Backend for Scripted {
+        fn present(&mut self, framebuffer: &[bool; WIDTH * HEIGHT]) {
+            self.presented.push(*framebuffer);
+        }
+
+        fn keys(&mut self) -> u16 {
+            self.reads += 1;
+            if let Some((reads, stop)) = &self.stop_after {
+                stop.store(self.reads >= *reads, Ordering::Relaxed);
+            }
+            self.keys.pop_front().unwrap_or(0)
+        }
+
+        fn should_quit(&self) -> bool {
+            self.quit_after.is_some_and(|reads| self.reads >= reads)
+        }
+
+        fn is_focused(&mut self) -> bool {
+            if self.focus_lost_after.is_some_and(|reads| self.reads >= reads) {
+                return false;
+            }
+            if self.unfocused_for > 0 {
+                self.unfocused_for -= 1;
+                return false;
+            }
+            true
+        }
+
+        fn set_status(&mut self, status: &str) {
+            self.statuses.push(status.to_string());
+        }
+
+        fn mouse(&mut self) -> Option<(u8, u8)> {
+            self.pointer
+        }
+
+        fn is_paused(&self) -> bool {
+            self.paused
+        }
+
+        fn should_step(&mut self) -> bool {
+            self.steps.pop_front().unwrap_or(false)
+        }
+    }
+
+    /// A clock that a backend can move too, to stand in for the time that a frame takes to run.
+    #[derive(Clone, Default)]
+    struct SharedClock(Rc<RefCell<ManualClock>>);
+
+    impl Clock for SharedClock {
+        fn now(&self) -> Duration {
+            self.0.borrow().now()
+        }
+
+        fn sleep(&mut self, duration: Duration) -> impl Future<Output = ()> {
+            self.0.borrow_mut().advance(duration);
+            std::future::ready(())
+        }
+    }
+
+    /// A backend on a machine too slow to keep up, which moves the clock on every time the keys
+    /// are read, and asks to quit after a number of frames.
+    struct Slow {
+        clock: SharedClock,
+        frame_time: Duration,
+        frames: usize,
+        quit_after: usize,
+    }
+
+    impl Backend for Slow {
+        fn present(&mut self, _framebuffer: &[bool; WIDTH * HEIGHT]) {}
+
+        fn keys(&mut self) -> u16 {
+            self.clock.0.borrow_mut().advance(self.frame_time);
+            self.frames += 1;
+            0
+        }
+
+        fn should_quit(&self) -> bool {
+            self.frames >= self.quit_after
+        }
+    }
+
+    /// A backend that asks to rewind whenever `rewinding` is set.
+    struct Rewinder {
+        rewinding: bool,
+    }
+
+    impl Backend for Rewinder {
+        fn present(&mut self, _framebuffer: &[bool; WIDTH * HEIGHT]) {}
+
+        fn keys(&mut self) -> u16 {
+            0
+        }
+
+        fn should_quit(&self) -> bool {
+            false
+        }
+
+        fn should_rewind(&self) -> bool {
+            self.rewinding
+        }
+    }
+
+    #[tokio::test]
+    async fn exit_halts_the_program() {
+        let mut chip8 = Chip8::new(&[0x60, 0x01, 0x00, 0xFD], Headless);
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::Halted));
+        assert_eq!(chip8.cpu.registers()[0], 1);
+    }
+
+    #[tokio::test]
+    async fn nothing_after_exit_is_run() {
+        let program = [0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0x00, 0xFD, 0x65, 0x05, 0x12, 0x08];
+        let mut chip8 = Chip8::new(&program, Headless);
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::Halted));
+        assert_eq!(chip8.cycles(), 4);
+        assert_eq!(chip8.cpu.registers()[..6], [1, 2, 3, 0, 0, 0]);
+        assert_eq!(chip8.cpu.pc(), 0x208);
+    }
+
+    #[tokio::test]
+    async fn running_off_the_end_of_the_program_reaches_empty_memory() {
+        let mut chip8 = Chip8::new(&[0x60, 0x01], Headless);
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::ReachedEmptyMemory));
+        assert_eq!(chip8.cpu.pc(), 0x204);
+    }
+
+    #[tokio::test]
+    async fn the_backend_asking_to_quit_stops_the_loop() {
+        let backend = Scripted { quit_after: Some(3), ..Scripted::default() };
+        let mut chip8 = Chip8::new(&[0x12, 0x00], backend);
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::QuitByUser));
+        assert_eq!(chip8.backend.reads, 3);
+    }
+
+    #[tokio::test]
+    async fn setting_the_stop_flag_stops_the_loop() {
+        let mut chip8 = Chip8::new(&[0x12, 0x00], Headless);
+        let stop = chip8.stop_handle();
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::QuitByUser));
+        assert_eq!(chip8.cycles(), 0);
+
+        // A flag set part way through a frame, as a signal handler would, is seen at the end of it.
+        let mut chip8 = Chip8::new(&[0x12, 0x00], Scripted::default());
+        chip8.backend.stop_after = Some((3, chip8.stop_handle()));
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::QuitByUser));
+        assert_eq!(chip8.backend.reads, 3);
+        assert_eq!(chip8.cycles(), 3 * (DEFAULT_HZ / DEFAULT_FPS) as u64);
+    }
+
+    #[tokio::test]
+    async fn errors_stop_the_loop() {
+        let mut chip8 = Chip8::new(&[0x00, 0xEE], Headless);
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Err(Error::StackUnderflow { pc: 0x200 }));
+    }
+
+    #[tokio::test]
+    async fn waiting_for_focus_holds_the_program_until_a_key_is_pressed_with_focus() {
+        // The window gets focus after 3 frames, and a key is pressed 2 frames after that.
+        let backend = Scripted { unfocused_for: 3, ..Scripted::new(&[0, 1 << 5]) };
+        let mut chip8 = Chip8::new(&[0x00, 0xFD], backend);
+        chip8.wait_for_focus = true;
+        let mut clock = ManualClock::new();
+
+        assert_eq!(chip8.run_with_clock(&mut clock).await, Ok(RunOutcome::Halted));
+        let frame = Duration::from_secs_f64(1.0 / DEFAULT_FPS as f64);
+        assert_eq!(clock.now(), frame * 4);
+        // The keys are only read once there is focus, by the two waiting frames and the first
+        // frame of the program.
+        assert_eq!(chip8.backend.reads, 3);
+    }
+
+    #[test]
+    fn the_cpu_speed_and_frame_rate_are_set_separately() {
+        for (hz, fps) in [(600, 60), (600, 30), (1200, 60), (90, 30)] {
+            let mut chip8 = Chip8::new(&[0x12, 0x00], Headless);
+            chip8.hz = hz;
+            chip8.fps = fps;
+            chip8.cpu.delay_timer = 200;
+            for _ in 0..fps {
+                chip8.run_frame().unwrap();
+            }
+            // A second of frames runs hz instructions, and the timer counts down 60 times.
+            assert_eq!(chip8.cycles(), hz as u64, "{}Hz at {} frames per second", hz, fps);
+            assert_eq!(chip8.cpu.delay_timer, 140, "{}Hz at {} frames per second", hz, fps);
+        }
+    }
+
+    #[test]
+    fn one_press_a_frame_is_seen_once_a_frame() {
+        // Waits for a key with FX0A, adds it to V1 and counts it in V2, and goes round again.
+        let program = [0xF0, 0x0A, 0x81, 0x04, 0x72, 0x01, 0x12, 0x00];
+        // Keys 1 to 4 are each pressed for a frame and let go on the next.
+        let mut chip8 = Chip8::new(&program, Scripted::new(&[1 << 1, 0, 1 << 2, 0, 1 << 3, 0, 1 << 4, 0]));
+        for _ in 0..10 {
+            chip8.run_frame().unwrap();
+        }
+        assert_eq!(chip8.backend.reads, 10);
+        assert_eq!(chip8.cpu.registers()[1], 1 + 2 + 3 + 4);
+        assert_eq!(chip8.cpu.registers()[2], 4);
+    }
+
+    #[test]
+    fn a_frame_is_presented_once_with_all_of_its_sprites() {
+        // Draws the 0 glyph at x = 0, 8 and 16, then stops.
+        let program = [0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x61, 0x08, 0xD1, 0x05, 0x61, 0x10, 0xD1, 0x05, 0x12, 0x0E];
+        let mut chip8 = Chip8::new(&program, Scripted::default());
+        chip8.hz = chip8.fps * 10;
+        for _ in 0..3 {
+            chip8.run_frame().unwrap();
+        }
+
+        // Nothing changes after the first frame, so it is the only one presented.
+        assert_eq!(chip8.backend.presented.len(), 1);
+        assert_frame_eq(&chip8.backend.presented[0], "
+            ####....####....####
+            #..#....#..#....#..#
+            #..#....#..#....#..#
+            #..#....#..#....#..#
+            ####....####....####
+        ");
+    }
+
+    #[test]
+    fn rewinding_restores_an_earlier_framebuffer() {
+        // Draws the first bytes of the program as a sprite, 8 pixels further right each time.
+        let program = [0xA2, 0x00, 0xD0, 0x14, 0x70, 0x08, 0x12, 0x02];
+        let mut chip8 = Chip8::new(&program, Rewinder { rewinding: false });
+        chip8.hz = chip8.fps;
+        chip8.rewind_frames = 600;
+
+        for _ in 0..2 {
+            chip8.run_frame().unwrap();
+        }
+        let first_draw = *chip8.framebuffer();
+        for _ in 0..3 {
+            chip8.run_frame().unwrap();
+        }
+        assert_ne!(*chip8.framebuffer(), first_draw);
+
+        chip8.backend.rewinding = true;
+        for _ in 0..3 {
+            chip8.run_frame().unwrap();
+        }
+        assert_eq!(*chip8.framebuffer(), first_draw);
+        assert_eq!(chip8.snapshots(), 2);
+    }
+
+    #[test]
+    fn no_snapshots_are_taken_with_rewinding_off() {
+        let mut chip8 = Chip8::new(&[0x12, 0x00], Rewinder { rewinding: false });
+        for _ in 0..10 {
+            chip8.run_frame().unwrap();
+        }
+        assert_eq!(chip8.snapshots(), 0);
+    }
+
+    #[test]
+    fn snapshots_are_capped_at_rewind_frames() {
+        let mut chip8 = Chip8::new(&[0x12, 0x00], Rewinder { rewinding: false });
+        chip8.rewind_frames = 5;
+        for _ in 0..10 {
+            chip8.run_frame().unwrap();
+        }
+        assert_eq!(chip8.snapshots(), 5);
+    }
+
+    #[tokio::test]
+    async fn the_delay_timer_counts_down_once_a_frame_and_stops_at_zero() {
+        // Sets the delay timer to 30 and waits for it to reach 0.
+        let program = [0x60, 0x1E, 0xF0, 0x15, 0xF1, 0x07, 0x31, 0x00, 0x12, 0x04, 0x00, 0xFD];
+        let mut chip8 = Chip8::new(&program, Headless);
+        let mut clock = ManualClock::new();
+
+        assert_eq!(chip8.run_with_clock(&mut clock).await, Ok(RunOutcome::Halted));
+        // The timer reaches 0 at the end of the 30th frame, and the program stops in the 31st.
+        let frame = Duration::from_secs_f64(1.0 / DEFAULT_FPS as f64);
+        assert_eq!(clock.now(), frame * 30);
+        assert_eq!(chip8.cpu.delay_timer, 0);
+
+        // With nothing left to wait for, a finished timer stays at 0.
+        chip8.run_frame().unwrap();
+        assert_eq!(chip8.cpu.delay_timer, 0);
+    }
+
+    #[test]
+    fn the_status_is_shown_when_the_activity_changes() {
+        // Waits for a key with FX0A, then stops on a jump to itself.
+        let mut chip8 = Chip8::new(&[0xF0, 0x0A, 0x12, 0x02], Scripted::new(&[0, 1 << 1, 1 << 1, 0]));
+        for _ in 0..6 {
+            chip8.run_frame().unwrap();
+        }
+        assert_eq!(chip8.backend.statuses, ["waiting for a key", "halted"]);
+    }
+
+    #[tokio::test]
+    async fn broken_programs_only_error_in_strict_mode() {
+        for (program, error) in [
+            // 5XY1 is only an instruction on CHIP-8E.
+            (vec![0x60, 0x01, 0x51, 0x21, 0x00, 0xFD], Error::UnknownOpcode { pc: 0x202, opcode: 0x5121 }),
+            (vec![0x03, 0x00, 0x00, 0xFD], Error::MachineCode { pc: 0x200, address: 0x300 }),
+            (vec![0xA1, 0x00, 0xF0, 0x55, 0x00, 0xFD], Error::LowMemoryWrite { pc: 0x202, address: 0x100 }),
+            (vec![0xAF, 0xFF, 0xF1, 0x55, 0x00, 0xFD], Error::MemoryOverrun { pc: 0x202, address: 0x1000 }),
+            (vec![0x60, 0x00, 0xF0, 0x4D, 0x00, 0xFD], Error::UnknownOpcode { pc: 0x202, opcode: 0xF04D }),
+        ] {
+            let mut chip8 = Chip8::new(&program, Headless);
+            assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::Halted), "{}", error);
+
+            let mut chip8 = Chip8::new(&program, Headless);
+            chip8.cpu.strict = true;
+            assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Err(error));
+        }
+    }
+
+    #[test]
+    fn nothing_is_executed_while_waiting_for_a_key() {
+        // FX0A, then counts up in V1 forever.
+        let mut chip8 = Chip8::new(&[0xF0, 0x0A, 0x71, 0x01, 0x12, 0x02], Scripted::new(&[0; 20]));
+        chip8.cpu.delay_timer = 30;
+        for _ in 0..20 {
+            chip8.run_frame().unwrap();
+        }
+        assert_eq!(chip8.cycles(), 1);
+        assert_eq!((chip8.cpu.pc(), chip8.cpu.registers()[1]), (0x202, 0));
+        // The timers keep counting down.
+        assert_eq!(chip8.cpu.delay_timer, 10);
+
+        // Pressing and letting go of a key starts it running again.
+        chip8.backend.keys.extend([1 << 4, 0]);
+        chip8.run_frame().unwrap();
+        assert_eq!(chip8.cycles(), 1);
+        chip8.run_frame().unwrap();
+        assert_eq!(chip8.cpu.registers()[0], 4);
+        assert!(chip8.cycles() > 1);
+    }
+
+    #[tokio::test]
+    async fn two_seconds_on_the_clock_count_the_timers_down_120_times() {
+        let mut chip8 = Chip8::new(&[0x12, 0x00], Scripted::default());
+        chip8.backend.stop_after = Some((2 * DEFAULT_FPS as usize, chip8.stop_handle()));
+        chip8.cpu.delay_timer = 200;
+        chip8.cpu.sound_timer = 100;
+        let mut clock = ManualClock::new();
+
+        assert_eq!(chip8.run_with_clock(&mut clock).await, Ok(RunOutcome::QuitByUser));
+        assert!((clock.now().as_secs_f64() - 2.0).abs() < 1e-6, "took {:?}", clock.now());
+        assert_eq!((chip8.cpu.delay_timer, chip8.cpu.sound_timer), (80, 0));
+        assert_eq!(chip8.cycles(), 2 * DEFAULT_HZ as u64);
+        assert!((chip8.effective_hz() - DEFAULT_HZ as f64).abs() < 1.0, "ran at {}Hz", chip8.effective_hz());
+    }
+
+    #[test]
+    fn frameskip_presents_one_frame_in_every_n_plus_1() {
+        for frameskip in [0, 1, 2, 3, 5, 11] {
+            // Draws the 0 glyph over and over, so every frame changes the display.
+            let mut chip8 = Chip8::new(&[0xD0, 0x05, 0x12, 0x00], Scripted::default());
+            chip8.frameskip = frameskip;
+            for _ in 0..12 {
+                chip8.run_frame().unwrap();
+            }
+            assert_eq!(chip8.backend.presented.len(), 12 / (frameskip as usize + 1), "frameskip {}", frameskip);
+            // The cpu runs at full speed whatever is shown.
+            assert_eq!(chip8.cycles(), 12 * (DEFAULT_HZ / DEFAULT_FPS) as u64, "frameskip {}", frameskip);
+        }
+    }
+
+    #[test]
+    fn authentic_timing_spends_the_vip_cycle_rate_whatever_the_instructions() {
+        // The VIP's 1.7609MHz clock gives 1,760,900 / 8 machine cycles a second, and the display
+        // takes 8 of them for each of its 128 lines a frame.
+        let per_frame = 1_760_900.0 / 8.0 / 60.0 - 8.0 * 128.0;
+        let mixes: [&[u8]; 3] = [
+            &[0x70, 0x01, 0x12, 0x00],
+            &[0x60, 0x05, 0x70, 0x01, 0x80, 0x14, 0xA3, 0x00, 0x30, 0x00, 0x12, 0x00],
+            &[0xC0, 0xFF, 0xF0, 0x15, 0xF1, 0x1E, 0x81, 0x06, 0xF2, 0x65, 0x12, 0x00],
+        ];
+        let mut rates = vec![];
+        for program in mixes {
+            let mut chip8 = Chip8::new(program, Headless);
+            chip8.authentic();
+            let loop_cycles: u32 = program
+                .chunks(2)
+                .map(|opcode| vip_cycles(decode_variant(u16::from_be_bytes([opcode[0], opcode[1]]), Variant::Chip8)))
+                .sum();
+            for _ in 0..60 {
+                chip8.run_frame().unwrap();
+            }
+            // The loop never skips, so every instruction in it runs once each time round.
+            let spent = chip8.cycles() as f64 / (program.len() / 2) as f64 * loop_cycles as f64;
+            assert!((spent - 60.0 * per_frame).abs() <= loop_cycles as f64, "{:02X?} spent {} machine cycles", program, spent);
+            rates.push(chip8.cycles());
+        }
+        // Slower instructions mean fewer of them each frame.
+        assert!(rates[0] > rates[1] && rates[1] > rates[2], "{:?}", rates);
+    }
+
+    #[test]
+    fn authentic_timing_ends_the_frame_after_a_draw() {
+        // Draws on every fourth instruction.
+        let mut chip8 = Chip8::new(&[0x70, 0x01, 0x70, 0x01, 0x70, 0x01, 0xD0, 0x05, 0x12, 0x00], Headless);
+        chip8.authentic();
+        chip8.run_frame().unwrap();
+        assert_eq!(chip8.cycles(), 4);
+        chip8.run_frame().unwrap();
+        assert_eq!(chip8.cycles(), 9);
+    }
+
+    #[tokio::test]
+    async fn the_program_reads_the_mouse_from_the_backend() {
+        let backend = Scripted { pointer: Some((40, 20)), ..Scripted::default() };
+        let mut chip8 = Chip8::new(&[0xF3, 0x4D, 0x00, 0xFD], backend);
+        chip8.mouse = true;
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::Halted));
+        assert_eq!(chip8.cpu.registers[3..5], [40, 20]);
+    }
+
+    #[tokio::test]
+    async fn the_governor_converges_on_the_target_rate_when_frames_are_slow() {
+        // Each frame takes 25ms rather than 16.7ms, so without scaling only 400 instructions would
+        // run a second.
+        let mut errors = vec![];
+        for frames in [10, 30, 90] {
+            let clock = SharedClock::default();
+            let backend = Slow { clock: clock.clone(), frame_time: Duration::from_millis(25), frames: 0, quit_after: frames };
+            let mut chip8 = Chip8::new(&[0x12, 0x00], backend);
+            assert_eq!(chip8.run_with_clock(&mut clock.clone()).await, Ok(RunOutcome::QuitByUser));
+
+            let rate = chip8.cycles() as f64 / clock.now().as_secs_f64();
+            assert!(rate > DEFAULT_HZ as f64 * 0.95, "{} frames ran at {}Hz", frames, rate);
+            errors.push((chip8.effective_hz() - DEFAULT_HZ as f64).abs());
+        }
+        assert!(errors[0] > errors[1] && errors[1] > errors[2], "errors {:?}", errors);
+        assert!(errors[2] < 1.0, "errors {:?}", errors);
+    }
+
+    #[test]
+    fn losing_focus_lets_go_of_every_held_key() {
+        let backend = Scripted { focus_lost_after: Some(3), ..Scripted::new(&[0b101, 0b101, 0b101, 0b101]) };
+        let mut chip8 = Chip8::new(&[0x12, 0x00], backend);
+        for _ in 0..2 {
+            chip8.run_frame().unwrap();
+            assert_eq!(chip8.cpu.keys, 0b101);
+        }
+        for _ in 0..2 {
+            chip8.run_frame().unwrap();
+            assert_eq!(chip8.cpu.keys, 0);
+        }
+    }
+
+    #[test]
+    fn a_step_while_paused_runs_exactly_one_instruction() {
+        // F000 NNNN is four bytes long, and the others are two.
+        let steps = [false, true, true, false, true].into();
+        let backend = Scripted { paused: true, steps, ..Scripted::default() };
+        let mut chip8 = Chip8::new(&[0x60, 0x01, 0xF0, 0x00, 0x03, 0x00, 0x70, 0x01], backend);
+        chip8.cpu.delay_timer = 10;
+
+        let mut pcs = vec![];
+        for _ in 0..5 {
+            chip8.run_frame().unwrap();
+            pcs.push(chip8.cpu.pc());
+        }
+        assert_eq!(pcs, [0x200, 0x202, 0x206, 0x206, 0x208]);
+        assert_eq!(chip8.cycles(), 3);
+        assert_eq!((chip8.cpu.registers[0], chip8.cpu.index()), (2, 0x300));
+        assert_eq!(chip8.cpu.delay_timer, 10);
+        assert_eq!(chip8.backend.statuses, [
+            "paused",
+            "paused - 0x200 LD V0, 0x01",
+            "paused - 0x202 LD I, LONG",
+            "paused - 0x206 ADD V0, 0x01",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn timers_at_30hz_count_down_once_every_other_frame() {
+        for (timer_hz, delay_timer) in [(60, 80), (30, 140)] {
+            let mut chip8 = Chip8::new(&[0x12, 0x00], Scripted::default());
+            chip8.backend.stop_after = Some((2 * DEFAULT_FPS as usize, chip8.stop_handle()));
+            chip8.timer_hz = timer_hz;
+            chip8.cpu.delay_timer = 200;
+            let mut clock = ManualClock::new();
+
+            assert_eq!(chip8.run_with_clock(&mut clock).await, Ok(RunOutcome::QuitByUser));
+            assert!((clock.now().as_secs_f64() - 2.0).abs() < 1e-6, "took {:?}", clock.now());
+            // The display and the cpu run at the same rate either way.
+            assert_eq!(chip8.backend.reads, 2 * DEFAULT_FPS as usize);
+            assert_eq!(chip8.cycles(), 2 * DEFAULT_HZ as u64);
+            assert_eq!(chip8.cpu.delay_timer, delay_timer, "at {}Hz", timer_hz);
+        }
+    }
+
+    #[test]
+    fn an_idle_polling_loop_still_sees_a_key_on_the_next_frame() {
+        // Goes round until key 3 is held, then adds 1 to V5 and exits.
+        let program = [0x61, 0x03, 0xE1, 0x9E, 0x12, 0x02, 0x75, 0x01, 0x00, 0xFD];
+        for idle_polling in [false, true] {
+            let mut chip8 = Chip8::new(&program, Scripted::new(&[0, 0, 1 << 3]));
+            chip8.idle_polling = idle_polling;
+            assert_eq!(chip8.run_frame(), Ok(None));
+            assert_eq!(chip8.run_frame(), Ok(None));
+            // Each throttled frame stops at the first failed key test: the LD and SKP, then the JP
+            // and SKP.
+            let cycles = if idle_polling { 2 + 2 } else { 2 * DEFAULT_HZ as u64 / DEFAULT_FPS as u64 };
+            assert_eq!(chip8.cycles(), cycles, "idle polling {}", idle_polling);
+
+            assert_eq!(chip8.run_frame(), Ok(Some(RunOutcome::Halted)), "idle polling {}", idle_polling);
+            assert_eq!(chip8.cpu.registers[5], 1);
+        }
+    }
+
+    #[test]
+    fn an_idle_polling_loop_can_wrap_round_the_end_of_memory() {
+        let mut chip8 = Chip8::new(&[], Scripted::default());
+        chip8.idle_polling = true;
+        // A SKNP at 0xFFD skips to 0x1001, where the JP read from 0x001 goes back to a second SKNP
+        // that starts at 0xFFF and ends at 0x000.
+        let memory = &mut chip8.cpu.memory;
+        let end = memory.len();
+        memory[end - 3..].copy_from_slice(&[0xE1, 0xA1, 0xE1]);
+        memory[..3].copy_from_slice(&[0xA1, 0x1F, 0xFF]);
+        chip8.cpu.program_counter = end - 3;
+
+        assert_eq!(chip8.run_frame(), Ok(None));
+        assert_eq!(chip8.cpu.pc(), end + 1);
+        assert_eq!(chip8.cycles(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_frame_with_two_draws_takes_two_draw_delays_longer() {
+        // Draws the 0 glyph twice, then counts in V1 for ever.
+        let program = [0xD0, 0x05, 0xD0, 0x05, 0x71, 0x01, 0x12, 0x04];
+        let mut chip8 = Chip8::new(&program, Scripted::default());
+        chip8.draw_delay = Duration::from_millis(100);
+        chip8.cpu.delay_timer = 10;
+        chip8.rewind_frames = 10;
+        chip8.cpu.draw_stats = Some(Default::default());
+        // Each frame reads the keys once, however many delays it is split by, so this runs three.
+        chip8.backend.stop_after = Some((3, chip8.stop_handle()));
+        let mut clock = ManualClock::new();
+
+        assert_eq!(chip8.run_with_clock(&mut clock).await, Ok(RunOutcome::QuitByUser));
+        let frame = Duration::from_secs_f64(1.0 / DEFAULT_FPS as f64);
+        let expected = frame * 3 + chip8.draw_delay * 2;
+        assert!(clock.now().abs_diff(expected) < Duration::from_micros(10), "took {:?}", clock.now());
+        // Each draw is shown before its delay, and the delays are not made up for afterwards.
+        assert_eq!(chip8.backend.presented.len(), 2);
+        assert_eq!(chip8.cycles(), 3 * (DEFAULT_HZ / DEFAULT_FPS) as u64);
+        assert_eq!(chip8.cpu.delay_timer, 7);
+        // The pieces of the first frame still count as one frame.
+        assert_eq!(chip8.snapshots(), 3);
+        assert_eq!(chip8.cpu.draw_stats.as_ref().map(|stats| stats.frames), Some(3));
+    }
+
+    #[test]
+    fn run_frame_carries_on_with_a_frame_split_by_the_draw_delay() {
+        // Draws the 0 glyph, then counts in V1 for ever.
+        let mut chip8 = Chip8::new(&[0xD0, 0x05, 0x71, 0x01, 0x12, 0x02], Scripted::default());
+        chip8.draw_delay = Duration::from_millis(100);
+
+        // The frame stops at the draw, and the next call runs the rest of it without reading the
+        // keys again.
+        assert_eq!(chip8.run_frame(), Ok(None));
+        assert_eq!(chip8.cycles(), 1);
+        assert_eq!(chip8.run_frame(), Ok(None));
+        assert_eq!(chip8.cycles(), (DEFAULT_HZ / DEFAULT_FPS) as u64);
+        assert_eq!(chip8.backend.reads, 1);
+
+        assert_eq!(chip8.run_frame(), Ok(None));
+        assert_eq!(chip8.cycles(), 2 * (DEFAULT_HZ / DEFAULT_FPS) as u64);
+        assert_eq!(chip8.backend.reads, 2);
+    }
+
+    #[tokio::test]
+    async fn a_key_and_a_quit_from_the_same_poll_are_both_seen() {
+        // Goes round until key 0 is held, then adds 1 to V1 for ever.
+        let program = [0xE0, 0x9E, 0x12, 0x00, 0x71, 0x01, 0x12, 0x04];
+        let mut chip8 = Chip8::new(&program, Scripted::new(&[1 << 0]));
+        // The backend asks to quit from the same poll that holds the key.
+        chip8.backend.quit_after = Some(1);
+
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::QuitByUser));
+        assert_eq!(chip8.backend.reads, 1);
+        assert!(chip8.cpu.registers[1] > 0);
+    }
+
+    #[tokio::test]
+    async fn two_presses_inside_the_input_delay_register_as_one() {
+        // Counts the keys let go in V1.
+        let program = [0xF0, 0x0A, 0x71, 0x01, 0x12, 0x00];
+        // Key 1 goes down, up and down again a frame apart, then up for good, all inside 60ms.
+        for (input_delay, presses) in [(0, 2), (60, 1)] {
+            let mut chip8 = Chip8::new(&program, Scripted::new(&[1 << 1, 0, 1 << 1, 0]));
+            chip8.input_delay = Duration::from_millis(input_delay);
+            chip8.backend.quit_after = Some(8);
+            let mut clock = ManualClock::new();
+
+            assert_eq!(chip8.run_with_clock(&mut clock).await, Ok(RunOutcome::QuitByUser));
+            assert_eq!(chip8.cpu.registers[1], presses, "input delay {}ms", input_delay);
+        }
+    }
+
+    #[test]
+    fn redrawing_many_times_a_frame_is_seen_as_flicker_without_the_display_wait() {
+        // Erases a one-row sprite and draws it again a pixel to the right, for ever.
+        let program = [0xA2, 0x0A, 0xD0, 0x11, 0x70, 0x01, 0xD0, 0x11, 0x12, 0x02, 0xFF];
+        for display_wait in [false, true] {
+            let mut chip8 = Chip8::new(&program, Scripted::default());
+            chip8.cpu.quirks.display_wait = display_wait;
+            chip8.cpu.draw_stats = Some(Default::default());
+            for _ in 0..120 {
+                assert_eq!(chip8.run_frame(), Ok(None));
+            }
+
+            let stats = chip8.cpu.draw_stats.as_ref().unwrap();
+            assert_eq!(stats.frames, 120);
+            // With the display wait each frame draws once, so nothing is drawn over in a frame.
+            let overdrawn = if display_wait { 0 } else { 120 };
+            assert_eq!(stats.overdrawn_frames, overdrawn, "display wait {}", display_wait);
+            assert_eq!(stats.flickers(), !display_wait);
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_run_before_a_new_clock_do_not_break_the_input_delay() {
+        // Runs frames on their own until key 1 goes down, then hands over to a clock that starts
+        // at zero again, before the time that the key went down.
+        let mut chip8 = Chip8::new(&[0x12, 0x00], Scripted::new(&[0, 0, 1 << 1, 1 << 1, 0]));
+        chip8.input_delay = Duration::from_millis(20);
+        for _ in 0..3 {
+            assert_eq!(chip8.run_frame(), Ok(None));
+        }
+        chip8.backend.quit_after = Some(6);
+
+        assert_eq!(chip8.run_with_clock(&mut ManualClock::new()).await, Ok(RunOutcome::QuitByUser));
+    }
+
+    /// An audio output that keeps the samples written to it.
+    struct Recorder(Rc<RefCell<Vec<f32>>>);
+
+    impl crate::audio::Output for Recorder {
+        fn sample_rate(&self) -> u32 {
+            8000
+        }
+
+        fn write(&mut self, samples: &[f32]) {
+            self.0.borrow_mut().extend_from_slice(samples);
+        }
+    }
+
+    #[test]
+    fn only_a_colliding_draw_queues_a_click() {
+        // Draws the program's own first bytes once, or twice so that the second draw collides,
+        // then exits.
+        for (program, collides) in [(&[0xA2, 0x00, 0xD0, 0x05, 0x00, 0xFD][..], false), (&[0xA2, 0x00, 0xD0, 0x05, 0xD0, 0x05, 0x00, 0xFD], true)] {
+            let samples = Rc::new(RefCell::new(Vec::new()));
+            let mut chip8 = Chip8::new(program, Scripted::default());
+            chip8.click_on_draw = true;
+            chip8.audio = Some(Audio::new(Box::new(Recorder(samples.clone())), Default::default()));
+
+            assert_eq!(chip8.run_frame(), Ok(Some(RunOutcome::Halted)));
+            // The sound timer is not running, so anything other than silence is the click.
+            let clicked = samples.borrow().iter().any(|&sample| sample != 0.0);
+            assert_eq!(clicked, collides, "collides {}", collides);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A source of time for pacing the cpu loop, so that it can be run against a fake clock.
+pub trait Clock {
+    /// The time that has passed since the clock was created.
+    fn now(&self) -> Duration;
+
+    /// Waits for the duration to pass.
+    fn sleep(&mut self, duration: Duration) -> impl Future<Output = ()>;
+}
+
+/// A clock that follows real time, using tokio's timers.
+pub struct TokioClock {
+    start: Instant,
+}
+
+impl TokioClock {
+    /// Starts a clock at the current time.
+    pub fn new() -> TokioClock {
+        TokioClock { start: Instant::now() }
+    }
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) -> impl Future<Output = ()> {
+        tokio::time::sleep(duration)
+    }
+}
+
+/// A clock that only moves when it is told to, so that timing can be tested without waiting.
+/// Sleeping moves the clock forward by the duration straight away.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Duration,
+}
+
+impl ManualClock {
+    /// Starts a clock at zero.
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&mut self, duration: Duration) {
+        self.now += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now
+    }
+
+    fn sleep(&mut self, duration: Duration) -> impl Future<Output = ()> {
+        self.advance(duration);
+        std::future::ready(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clocks_only_move_when_told_to() {
+        let mut clock = ManualClock::new();
+        assert_eq!(clock.now(), Duration::ZERO);
+        assert_eq!(clock.now(), Duration::ZERO);
+
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(clock.now(), Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn sleeping_on_a_manual_clock_moves_it_straight_away() {
+        let mut clock = ManualClock::new();
+        clock.sleep(Duration::from_secs(3600)).await;
+        assert_eq!(clock.now(), Duration::from_secs(3600));
+        clock.sleep(Duration::ZERO).await;
+        assert_eq!(clock.now(), Duration::from_secs(3600));
+    }
+}
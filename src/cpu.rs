@@ -1,14 +1,190 @@
 // rand library used to generate a random number for 0xCxkk.
-use rand::Rng;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::time::{sleep, interval};
-use minifb::{Window, WindowOptions, Scale, Key};
 
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+use crate::error::Error;
+use crate::font::{self, FONT_BASE};
+use crate::instruction::{decode_variant, Instruction, Variant};
+use crate::quirks::{Edge, Quirks, ShiftSource};
+
+/// Width of the display in pixels.
+pub const WIDTH: usize = 64;
+/// Height of the display in pixels.
+pub const HEIGHT: usize = 32;
+
+/// The address that programs are loaded at.
+pub const LOAD_ADDRESS: usize = 0x200;
+
+/// The number of nested subroutines that the stack has room for unless it is made deeper.
+pub const STACK_DEPTH: usize = 16;
+
+/// The reason that a program stopped running without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The program asked to stop.
+    Halted,
+    /// The user closed the window.
+    QuitByUser,
+    /// The cpu read an opcode of 0000, usually because execution ran past the end of the program.
+    ReachedEmptyMemory,
+}
+
+/// What the cpu is doing, so that a program that is waiting does not look frozen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    /// Executing instructions.
+    Running,
+    /// Waiting for a key in FX0A.
+    WaitingForKey,
+    /// Stuck on a jump to itself, which programs use to stop.
+    Halted,
+}
+
+impl fmt::Display for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Activity::Running => write!(f, "running"),
+            Activity::WaitingForKey => write!(f, "waiting for a key"),
+            Activity::Halted => write!(f, "halted"),
+        }
+    }
+}
+
+/// The most recent DXYN, for diagnostics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Draw {
+    /// The address of the instruction.
+    pub pc: usize,
+    /// The screen position that the sprite was drawn at.
+    pub x: u8,
+    pub y: u8,
+    /// The number of rows in the sprite.
+    pub height: u8,
+    /// Whether any pixels were turned off, setting VF.
+    pub collided: bool,
+}
+
+/// Totals over every DXYN executed, for seeing how much drawing a program does.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DrawStats {
+    /// The number of DXYN executed.
+    pub draws: u64,
+    /// The number of pixels turned on or off.
+    pub pixels_flipped: u64,
+    /// The number of draws that turned pixels off, setting VF.
+    pub collisions: u64,
+    /// The number of sprite rows drawn, counting rows that were clipped.
+    pub rows: u64,
+    /// The number of frames that have ended while drawing was being counted.
+    pub frames: u64,
+    /// The number of frames where at least two sprites were drawn over sprites from earlier in the
+    /// same frame. A program that erases and redraws the same things several times a frame shows
+    /// them half drawn, so it flickers.
+    pub overdrawn_frames: u64,
+    /// The area of each sprite drawn so far this frame, as (x, y, width, height).
+    regions: Vec<(usize, usize, usize, usize)>,
+    /// The number of sprites drawn over earlier ones so far this frame.
+    overdraws: u32,
+}
+
+impl DrawStats {
+    /// The average number of rows in a sprite, or 0 if nothing has been drawn.
+    pub fn average_height(&self) -> f64 {
+        if self.draws == 0 {
+            0.0
+        } else {
+            self.rows as f64 / self.draws as f64
+        }
+    }
+
+    /// Counts a sprite drawn at (x, y), and whether it covers one drawn earlier in the frame.
+    fn add_region(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let overlaps = |&(x2, y2, width2, height2): &(usize, usize, usize, usize)| {
+            x < x2 + width2 && x2 < x + width && y < y2 + height2 && y2 < y + height
+        };
+        if self.regions.iter().any(overlaps) {
+            self.overdraws += 1;
+        }
+        self.regions.push((x, y, width, height));
+    }
+
+    /// Ends a frame, so that the sprites drawn after it are not compared with the ones before.
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        if self.overdraws >= 2 {
+            self.overdrawn_frames += 1;
+        }
+        self.regions.clear();
+        self.overdraws = 0;
+    }
+
+    /// Returns true if enough frames draw over their own sprites that the program probably
+    /// flickers without the display wait quirk, which stops it from drawing again until the next
+    /// frame.
+    pub fn flickers(&self) -> bool {
+        self.overdrawn_frames >= 60 && self.overdrawn_frames * 4 >= self.frames
+    }
+}
+
+impl fmt::Display for DrawStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} draws, {} pixels flipped, {} collisions, {:.1} rows per sprite on average",
+            self.draws, self.pixels_flipped, self.collisions, self.average_height(),
+        )
+    }
+}
+
+/// What happens when a program writes to memory below the load address, where the font and the
+/// interpreter live. Well-behaved programs never write there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LowMemoryProtection {
+    /// The write goes ahead silently.
+    Off,
+    /// The write goes ahead, and a warning is printed.
+    Warn,
+    /// The write is refused and the cpu stops with an error.
+    Error,
+}
+
+impl FromStr for LowMemoryProtection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(LowMemoryProtection::Off),
+            "warn" => Ok(LowMemoryProtection::Warn),
+            "error" => Ok(LowMemoryProtection::Error),
+            _ => Err(format!("expected off, warn or error, found {}", s)),
+        }
+    }
+}
+
+/// Where the cpu writes one kind of message, such as its warnings, a line each. Clones of the cpu,
+/// e.g. the rewind snapshots, write to the same place.
+#[derive(Clone)]
+pub struct Sink(Arc<Mutex<dyn Write + Send>>);
+
+impl Sink {
+    /// Writes the messages to out.
+    pub fn new(out: impl Write + Send + 'static) -> Sink {
+        Sink(Arc::new(Mutex::new(out)))
+    }
+
+    /// Writes a line. A line that cannot be written is dropped rather than stopping the program.
+    fn line(&self, line: &str) {
+        let mut out = self.0.lock().unwrap();
+        let _ = writeln!(out, "{}", line).and_then(|()| out.flush());
+    }
+}
 
 /// Data structure that holds the current state of the cpu.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub struct CPU {
     /// 16 one-byte registers that are available for use by the program.
     pub registers: [u8; 16],
@@ -16,134 +192,511 @@ pub struct CPU {
     pub program_counter: usize,
     /// 4kiB of memory that holds the proram and the font.
     pub memory: [u8; 0x1000],
-    /// 16-address stack, allows for 16 nested subroutines.
-    pub stack: [u16; 16],
+    /// The return addresses of the nested subroutines, with room for as many as it is long. This
+    /// is STACK_DEPTH, as on most interpreters, unless it is changed with set_stack_depth.
+    pub stack: Vec<u16>,
     /// Holds the location of the most recent address added to the stack.
     pub stack_pointer: usize,
     /// A register that holds an address that often points to a sprite.
     pub index_register: u16,
-    pub delay_timer: Arc<Mutex<u8>>,
+    /// Counts down by one on each tick_timers until it reaches zero. The cpu loop ticks the timers
+    /// Chip8::timer_hz times a second, which is 60 unless --timer-hz changes it.
+    pub delay_timer: u8,
+    /// Counts down alongside the delay timer, and the machine beeps while it is above zero.
+    pub sound_timer: u8,
+    /// The 64x32 monochrome display, stored row by row. A pixel is on if it is true.
+    pub framebuffer: [bool; WIDTH * HEIGHT],
+    /// Set when the framebuffer changes so that the backend knows to redraw it.
+    pub frame_dirty: bool,
+    /// The keys on the 16-key keypad that are currently held, with bit n set if key n is held.
+    /// Keys that are not mapped by the backend are never set, so with nothing held this is 0,
+    /// EXA1 always skips and FX0A keeps waiting.
+    pub keys: u16,
+    /// The keys that were let go at the start of this frame, which FX0A waits for.
+    pub released_keys: u16,
+    /// Set to x while FX0A is waiting for a key to store in Vx. Nothing is executed until then.
+    pub waiting_for_key: Option<u8>,
+    /// XO-CHIP 16-byte audio pattern, played as a looping 128-bit 1-bit sample.
+    pub audio_pattern: [u8; 16],
+    /// XO-CHIP pitch register that sets the playback rate of the audio pattern.
+    pub pitch: u8,
+    /// SUPER-CHIP RPL user flags. On the HP48 these survived the interpreter being restarted.
+    pub rpl_flags: [u8; 8],
+    /// The address that the program was loaded at, and where execution starts.
+    pub load_address: usize,
+    /// The instruction set that opcodes are decoded with.
+    pub variant: Variant,
+    /// The interpreter behaviours that programs disagree on.
+    pub quirks: Quirks,
+    /// How writes below the load address by FX33 and FX55 are treated. Strict mode always treats
+    /// them as errors.
+    pub low_memory: LowMemoryProtection,
+    /// Marks the bytes of memory that have been executed as an instruction, so that a warning can
+    /// be printed when one is overwritten. None unless self-modifying code is being looked for.
+    pub executed: Option<Box<[bool; 0x1000]>>,
+    /// The most recent sprite that was drawn.
+    pub last_draw: Option<Draw>,
+    /// Prints every draw that sets VF because it turned pixels off.
+    pub log_collisions: bool,
+    /// Counts what DXYN draws. None unless drawing is being profiled.
+    pub draw_stats: Option<DrawStats>,
+    /// The position of the mouse on the display, read by Fx4D. None if the mouse is turned off,
+    /// in which case Fx4D is an unknown opcode.
+    pub mouse: Option<(u8, u8)>,
+    /// Turns unknown opcodes, writes below the load address and writes past the end of memory into
+    /// errors, for catching bugs while writing a program.
+    pub strict: bool,
+    /// Warns when VF holds data that an instruction overwrites with a flag before it is read.
+    pub warn_vf_clobber: bool,
+    /// Where warnings about things the program did that are allowed but are probably mistakes are
+    /// written. Standard error unless set.
+    pub warnings: Sink,
+    /// Where the logs that were asked for, such as the collisions, are written. Standard output
+    /// unless set.
+    pub log: Sink,
+    /// The instruction that last stored data in VF, if VF has not been used as a flag since.
+    vf_data: Option<usize>,
+    /// The instructions that stored data in VF and then overwrote it with a flag, if VF has not
+    /// been read since.
+    vf_clobbered: Option<(usize, usize)>,
+    /// Generates the numbers for CXKK. Each cpu has its own, so that a clone makes the same
+    /// numbers as the original.
+    rng: StdRng,
 }
 
 impl CPU {
-    /// Initialises the window and containes the main cpu loop.
-    pub async fn run(&mut self) {
-        let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+    /// Creates an empty cpu with the font loaded at FONT_BASE and the program loaded at 0x200.
+    pub fn new(program: &[u8]) -> CPU {
+        CPU::with_load_address(program, LOAD_ADDRESS)
+    }
+
+    /// Creates an empty cpu with the font loaded at FONT_BASE and the program loaded at the address,
+    /// for interpreters such as the ETI-660 that do not use 0x200.
+    pub fn with_load_address(program: &[u8], load_address: usize) -> CPU {
+        // Initialises and empty memory that is 4kiB in length.
+        let mut memory = [0u8; 4096];
+
+        // Insert the fonts into memory.
+        font::load(&mut memory);
 
-        let mut options = WindowOptions::default();
-        options.scale = Scale::X16;
+        // Insert the program into memory at the load address.
+        for (i, byte) in program.iter().enumerate() {
+            memory[i + load_address] = *byte;
+        }
+
+        CPU {
+            registers: [0; 16],
+            program_counter: load_address,
+            memory,
+            stack: vec![0; STACK_DEPTH],
+            stack_pointer: 0,
+            index_register: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+            framebuffer: [false; WIDTH * HEIGHT],
+            frame_dirty: false,
+            keys: 0,
+            released_keys: 0,
+            waiting_for_key: None,
+            audio_pattern: [0; 16],
+            pitch: 64,
+            rpl_flags: [0; 8],
+            load_address,
+            variant: Variant::default(),
+            quirks: Quirks::default(),
+            low_memory: LowMemoryProtection::Off,
+            executed: None,
+            last_draw: None,
+            log_collisions: false,
+            draw_stats: None,
+            mouse: None,
+            strict: false,
+            warn_vf_clobber: false,
+            warnings: Sink::new(io::stderr()),
+            log: Sink::new(io::stdout()),
+            vf_data: None,
+            vf_clobbered: None,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// The 16 general purpose registers, V0 to VF.
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.registers
+    }
+
+    /// The address of the next instruction.
+    pub fn pc(&self) -> usize {
+        self.program_counter
+    }
 
-        let mut window = Window::new(
-            "CHIP-8 Emulator", 
-            WIDTH,
-            HEIGHT,
-            options,
-        ).unwrap();
+    /// The value of the index register.
+    pub fn index(&self) -> u16 {
+        self.index_register
+    }
 
-        window.limit_update_rate(Some(Duration::from_micros(16600)));
+    /// Makes room on the stack for depth nested subroutines, for programs that recurse deeper than
+    /// STACK_DEPTH. Addresses that no longer fit are dropped.
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack.resize(depth, 0);
+        self.stack_pointer = self.stack_pointer.min(depth);
+    }
 
-        let mut decrement_future;
+    /// The return addresses currently on the stack, oldest first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.stack_pointer]
+    }
 
-        // Main cpu loop.
-        'running: loop {
-            if window.is_key_down(Key::Escape) {
-                break 'running;
+    /// Updates the keys that are held. EX9E and EXA1 look at whether a key is held, while FX0A
+    /// waits for a key to be pressed and let go, so the keys that were held before this call and
+    /// are not now are recorded as released.
+    ///
+    /// If the cpu is waiting in FX0A and a key was let go, the key is stored and the cpu carries
+    /// on.
+    pub fn set_keys(&mut self, keys: u16) {
+        self.released_keys = self.keys & !keys;
+        self.keys = keys;
+
+        if let Some(x) = self.waiting_for_key {
+            if self.take_released_key(x) {
+                self.waiting_for_key = None;
             }
+        }
+    }
+
+    /// Presses a key on the keypad, leaving the other keys as they are, for driving the cpu without
+    /// a backend. Keys above 0xF are ignored. Chip8::run_frame replaces all the keys with the
+    /// backend's each frame.
+    pub fn key_down(&mut self, key: u8) {
+        if key < 16 {
+            self.set_keys(self.keys | 1 << key);
+        }
+    }
+
+    /// Lets go of a key on the keypad, which FX0A sees as a key press. Keys above 0xF are ignored.
+    pub fn key_up(&mut self, key: u8) {
+        if key < 16 {
+            self.set_keys(self.keys & !(1 << key));
+        }
+    }
+
+    /// The instruction at the PC, which will be executed next.
+    pub fn next_instruction(&self) -> Instruction {
+        decode_variant(self.read_opcode(), self.variant)
+    }
+
+    /// Returns true if the next instruction jumps to itself, so the program can never continue.
+    pub fn is_halted(&self) -> bool {
+        self.next_instruction() == Instruction::Jp(self.program_counter as u16)
+    }
+
+    /// Works out what the cpu is doing from the next instruction and whether it is waiting for a
+    /// key.
+    pub fn activity(&self) -> Activity {
+        if self.waiting_for_key.is_some() {
+            Activity::WaitingForKey
+        } else if self.is_halted() {
+            Activity::Halted
+        } else {
+            Activity::Running
+        }
+    }
+
+    /// Fills the memory above the program, and the registers, with pseudo-random bytes generated
+    /// from the seed. Real hardware powers up with indeterminate RAM, so this helps find programs
+    /// that rely on memory being zeroed. The font and the program are left intact.
+    pub fn fuzz_memory(&mut self, seed: u64, program_len: usize) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        rng.fill(&mut self.memory[self.load_address + program_len..]);
+        rng.fill(&mut self.registers);
+    }
+
+    /// Turns each pixel of the display on or off at random, using pixels generated from the
+    /// seed. Real displays showed garbage at power on, so this helps find programs that draw
+    /// without clearing the screen first.
+    pub fn dirty_screen(&mut self, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        rng.fill(&mut self.framebuffer[..]);
+        self.frame_dirty = true;
+    }
+
+    /// Fetches, decodes and executes a single instruction, or does nothing while waiting for a key.
+    /// Returns the outcome if the program has stopped. Errors if the PC has run off the end of memory.
+    pub fn step(&mut self) -> Result<Option<RunOutcome>, Error> {
+        if self.waiting_for_key.is_some() {
+            return Ok(None);
+        }
+        if self.program_counter >= self.memory.len() - 1 {
+            return Err(Error::PcOutOfRange { pc: self.program_counter });
+        }
+
+        // Get the current opcode.
+        let opcode = self.read_opcode();
+        if let Some(executed) = &mut self.executed {
+            executed[self.program_counter] = true;
+            executed[self.program_counter + 1] = true;
+        }
+        // Increment the PC to the next instruction.
+        self.program_counter += 2;
+
+        let instruction = decode_variant(opcode, self.variant);
+        if self.warn_vf_clobber {
+            self.check_vf(instruction);
+        }
+        self.execute(instruction)
+    }
+
+    /// Writes a warning about something the program did that is allowed but is probably a mistake.
+    fn warn(&self, message: String) {
+        self.warnings.line(&format!("Warning: {}", message));
+    }
+
+    /// Writes a line of a diagnostic log that was asked for, such as the collisions.
+    fn log(&self, line: String) {
+        self.log.line(&line);
+    }
 
-            // Get the current opcode.
-            let opcode = self.read_opcode();
-            // Increment the PC to the next instruction.
-            self.program_counter += 2;
-
-            // Splits the opcode into 6 different parts. 0xcxyd, 0x_nnn, and 0x__kk.
-            let c = ((opcode & 0xF000) >> 12) as u8;
-            let x = ((opcode & 0x0F00) >> 8) as u8;
-            let y = ((opcode & 0x00F0) >> 4) as u8;
-            let d = ((opcode & 0x000F) >> 0) as u8;
-
-            let nnn = opcode & 0x0FFF;
-            let kk = (opcode & 0x00FF) as u8;
-
-            // Decide what to do based on the opcode.
-            match (c, x, y, d) {
-                (0, 0, 0, 0) => { return; },
-                (0, 0, 0xE, 0) => self.clear(&mut window),
-                (0, 0, 0xE, 0xE) => self.ret(),
-                (0x1, _, _, _) => self.jump(nnn),
-                (0x2, _, _, _) => self.call(nnn),
-                (0x3, _, _, _) => self.skip_x_equal(x, kk),
-                (0x4, _, _, _) => self.skip_x_nequal(x, kk),
-                (0x5, _, _, 0) => self.skip_equal(x, y),
-                (0x6, _, _, _) => self.set(x, kk),
-                (0x7, _, _, _) => self.add(x, kk),
-                (0x8, _, _, 0) => self.set_xy(x, y),
-                (0x8, _, _, 0x1) => self.bitwise_or(x, y),
-                (0x8, _, _, 0x2) => self.bitwise_and(x, y),
-                (0x8, _, _, 0x3) => self.bitwise_xor(x, y),
-                (0x8, _, _, 0x4) => self.add_xy(x, y),
-                (0x8, _, _, 0x5) => self.sub_xy(x, y),
-                (0x8, _, _, 0x6) => self.shift_right(x),
-                (0x8, _, _, 0x7) => self.sub_yx(x, y),
-                (0x8, _, _, 0xE) => self.shift_left(x),
-                (0x9, _, _, 0) => self.skip_nequal(x, y),
-                (0xA, _, _, _) => self.set_index(nnn),
-                (0xB, _, _, _) => self.jump_offset(nnn),
-                (0xC, _, _, _) => self.random(x, kk),
-                (0xD, _, _, _) => self.display(x, y, d, &mut window, &mut buffer),
-                (0xE, _, 0x9, 0xE) => self.skip_key_pressed(x, &mut window),
-                (0xE, _, 0xA, 0x1) => self.skip_key_npressed(x, &mut window),
-                (0xF, _, 0, 0x7) => decrement_future = &self.set_timer(x),
-                (0xF, _, 0x1, 0x5) => self.read_timer(x),
-                (0xF, _, 0x1, 0x8) => (),
-                (0xF, _, 0x1, 0xE) => self.add_to_index(x),
-                (0xF, _, 0, 0xA) => self.get_key(x, &mut window),
-                (0xF, _, 0x2, 0x9) => self.font(x),
-                (0xF, _, 0x3, 0x3) => self.decimal(x),
-                (0xF, _, 0x5, 0x5) => self.store_memory(x),
-                (0xF, _, 0x6, 0x5) => self.load_memory(x),
-                _ => (), //todo!("opcode {:04x}", opcode)
+    /// Warns when VF is read as data after an instruction overwrote it with a flag, straight after
+    /// the program stored data in it. Reading a flag just after it is set is how carries and
+    /// collisions are checked, so that is not warned about.
+    fn check_vf(&mut self, instruction: Instruction) {
+        let pc = self.instruction_address();
+        let (reads, data, flag) = vf_use(instruction);
+
+        if reads {
+            if let Some((stored, flagged)) = self.vf_clobbered.take() {
+                self.warn(format!(
+                    "VF was read as data at 0x{:03X}, but it was overwritten by a flag at 0x{:03X} after being set at 0x{:03X}.",
+                    pc, flagged, stored,
+                ));
             }
-            sleep(Duration::from_micros(100)).await;
+        }
+        if data {
+            self.vf_data = Some(pc);
+            self.vf_clobbered = None;
+        }
+        if flag {
+            if let Some(stored) = self.vf_data.take() {
+                self.vf_clobbered = Some((stored, pc));
+            }
+        }
+    }
+
+    /// The address of the instruction being executed, which is two behind the PC. execute can be
+    /// called with the PC anywhere, so below 2 this wraps around to the end of memory, as the PC
+    /// does when it reads opcodes.
+    fn instruction_address(&self) -> usize {
+        self.program_counter.wrapping_sub(2) % self.memory.len()
+    }
+
+    /// Executes a decoded instruction. Returns the outcome if the program has stopped. The
+    /// instruction is taken to be at the address two before the PC, as if it had just been read.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<Option<RunOutcome>, Error> {
+        match instruction {
+            Instruction::Empty => return Ok(Some(RunOutcome::ReachedEmptyMemory)),
+            Instruction::Cls => self.clear(),
+            Instruction::Ret => self.ret()?,
+            Instruction::Exit => return Ok(Some(RunOutcome::Halted)),
+            Instruction::ScrollUp(n) => self.scroll_up(n),
+            // There is no COSMAC VIP machine code to run, so modern interpreters skip it.
+            Instruction::Sys(nnn) if self.strict => {
+                return Err(Error::MachineCode { pc: self.instruction_address(), address: nnn as usize });
+            },
+            Instruction::Sys(_) => (),
+            Instruction::Jp(nnn) => self.jump(nnn),
+            Instruction::Call(nnn) => self.call(nnn)?,
+            Instruction::SeVxByte(x, kk) => self.skip_x_equal(x, kk),
+            Instruction::SneVxByte(x, kk) => self.skip_x_nequal(x, kk),
+            Instruction::SeVxVy(x, y) => self.skip_equal(x, y),
+            Instruction::LdVxByte(x, kk) => self.set(x, kk),
+            Instruction::AddVxByte(x, kk) => self.add(x, kk),
+            Instruction::LdVxVy(x, y) => self.set_xy(x, y),
+            Instruction::Or(x, y) => self.bitwise_or(x, y),
+            Instruction::And(x, y) => self.bitwise_and(x, y),
+            Instruction::Xor(x, y) => self.bitwise_xor(x, y),
+            Instruction::AddVxVy(x, y) => self.add_xy(x, y),
+            Instruction::Sub(x, y) => self.sub_xy(x, y),
+            Instruction::Shr(x, y) => self.shift_right(x, y),
+            Instruction::Subn(x, y) => self.sub_yx(x, y),
+            Instruction::Shl(x, y) => self.shift_left(x, y),
+            Instruction::SneVxVy(x, y) => self.skip_nequal(x, y),
+            Instruction::LdI(nnn) => self.set_index(nnn),
+            Instruction::JpV0(nnn) => self.jump_offset(nnn),
+            Instruction::Rnd(x, kk) => self.random(x, kk),
+            // DXY0 draws a 16x16 sprite on SUPER-CHIP, and draws nothing on CHIP-8, where nothing
+            // can collide.
+            Instruction::Drw(x, y, 0) if self.variant == Variant::SuperChip => self.display(x, y, 16, 16),
+            Instruction::Drw(_, _, 0) => self.registers[0xF] = 0,
+            Instruction::Drw(x, y, n) => self.display(x, y, n, 8),
+            Instruction::Skp(x) => self.skip_key_pressed(x),
+            Instruction::Sknp(x) => self.skip_key_npressed(x),
+            Instruction::LdILong => self.set_index_long(),
+            Instruction::Audio => self.load_audio_pattern(),
+            Instruction::LdVxDt(x) => self.read_timer(x),
+            Instruction::LdVxK(x) => self.get_key(x),
+            Instruction::LdDtVx(x) => self.set_timer(x),
+            Instruction::LdStVx(x) => self.sound_timer = self.registers[x as usize],
+            Instruction::AddIVx(x) => self.add_to_index(x),
+            Instruction::LdFVx(x) => self.font(x),
+            Instruction::LdBVx(x) => self.decimal(x)?,
+            Instruction::Pitch(x) => self.set_pitch(x),
+            Instruction::LdIVx(x) => self.store_memory(x)?,
+            Instruction::LdVxI(x) => self.load_memory(x),
+            Instruction::LdRVx(x) => self.store_flags(x),
+            Instruction::LdVxR(x) => self.load_flags(x),
+            Instruction::LdMouse(x) => match self.mouse {
+                Some(position) => self.read_mouse(x, position),
+                None if self.strict => {
+                    return Err(Error::UnknownOpcode { pc: self.instruction_address(), opcode: 0xF04D | (x as u16) << 8 });
+                },
+                None => (),
+            },
+            Instruction::Skip => self.skip_next(),
+            Instruction::SgtVxVy(x, y) => self.skip_greater(x, y),
+            // Both branches are relative to the address of the branch, which is two behind the PC.
+            Instruction::JpBack(nn) => self.program_counter = self.instruction_address().saturating_sub(nn as usize),
+            Instruction::JpForward(nn) => self.program_counter = self.instruction_address() + nn as usize,
+            Instruction::Unknown(opcode) if self.strict => {
+                return Err(Error::UnknownOpcode { pc: self.instruction_address(), opcode });
+            },
+            Instruction::Unknown(_) => (),
+        }
+        Ok(None)
+    }
+
+    /// Returns true if a tone should be playing, because the sound timer is running.
+    pub fn sound_on(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    /// The rate in Hz that the bits of the audio pattern are played at, set by the pitch register.
+    /// A pitch of 64 plays at 4000Hz.
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+
+    /// Returns whether the sample at the given position in the looping audio pattern is high.
+    pub fn audio_sample(&self, position: usize) -> bool {
+        let bit = position % 128;
+        self.audio_pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+    }
+
+    /// Copies the 16 bytes of memory at the index register into the audio pattern buffer.
+    fn load_audio_pattern(&mut self) {
+        for i in 0..16 {
+            self.audio_pattern[i] = self.read_memory(self.index_register as usize + i);
+        }
+    }
+
+    /// Sets the pitch register to Vx.
+    fn set_pitch(&mut self, x: u8) {
+        self.pitch = self.registers[x as usize];
+    }
+
+    /// Stores V0 to Vx in the RPL user flags. There are only 8 flags, so x is clamped to 7.
+    fn store_flags(&mut self, x: u8) {
+        for i in 0..=x.min(7) as usize {
+            self.rpl_flags[i] = self.registers[i];
+        }
+    }
+
+    /// Loads V0 to Vx from the RPL user flags. There are only 8 flags, so x is clamped to 7.
+    fn load_flags(&mut self, x: u8) {
+        for i in 0..=x.min(7) as usize {
+            self.registers[i] = self.rpl_flags[i];
         }
     }
 
     fn load_memory(&mut self, x: u8) {
         for i in 0..=x {
-            self.registers[i as usize] = self.memory[(self.index_register + i as u16) as usize];
+            self.registers[i as usize] = self.read_memory(self.index_register as usize + i as usize);
         }
     }
 
-    fn store_memory(&mut self, x: u8) {
+    fn store_memory(&mut self, x: u8) -> Result<(), Error> {
         for i in 0..=x {
-            self.memory[(self.index_register + i as u16) as usize] = self.registers[i as usize];
+            self.write_memory(self.index_register as usize + i as usize, self.registers[i as usize])?;
         }
+        Ok(())
     }
 
-    fn decimal(&mut self, x: u8) {
-        let digits = self.registers[x as usize]
-            .to_string()
-            .chars()
-            .map(|d| d.to_digit(10).unwrap())
-            .collect::<Vec<_>>();
+    /// Reads a byte on behalf of the current instruction. Addresses past the end of memory wrap
+    /// around to the start.
+    fn read_memory(&self, address: usize) -> u8 {
+        self.memory[address % self.memory.len()]
+    }
 
-        for (i, digit) in digits.iter().enumerate() {
-            self.memory[(self.index_register + i as u16) as usize] = *digit as u8;
+    /// Writes a byte on behalf of the current instruction, checking it against the low memory
+    /// protection first, and warning if it overwrites code that has already run. Addresses past
+    /// the end of memory wrap around to the start, or are an error in strict mode.
+    fn write_memory(&mut self, address: usize, value: u8) -> Result<(), Error> {
+        if address >= self.memory.len() {
+            if self.strict {
+                return Err(Error::MemoryOverrun { pc: self.instruction_address(), address });
+            }
+            return self.write_memory(address % self.memory.len(), value);
+        }
+        if address < self.load_address {
+            let pc = self.instruction_address();
+            match self.low_memory {
+                _ if self.strict => return Err(Error::LowMemoryWrite { pc, address }),
+                LowMemoryProtection::Off => (),
+                LowMemoryProtection::Warn => {
+                    self.warn(format!("write to 0x{:03X} below the load address at 0x{:03X}.", address, pc));
+                },
+                LowMemoryProtection::Error => return Err(Error::LowMemoryWrite { pc, address }),
+            }
         }
+        if self.executed.as_ref().is_some_and(|executed| executed[address]) {
+            self.warn(format!(
+                "0x{:03X}, which has been executed, was overwritten by the instruction at 0x{:03X}.",
+                address,
+                self.instruction_address(),
+            ));
+        }
+        self.memory[address] = value;
+        Ok(())
     }
 
+    /// Stores the hundreds, tens and units of Vx at I, I+1 and I+2. All three are always written,
+    /// so 5 is stored as 0, 0, 5.
+    fn decimal(&mut self, x: u8) -> Result<(), Error> {
+        let value = self.registers[x as usize];
+        let digits = [value / 100, value / 10 % 10, value % 10];
+
+        for (i, digit) in digits.into_iter().enumerate() {
+            self.write_memory(self.index_register as usize + i, digit)?;
+        }
+        Ok(())
+    }
+
+    /// Points the index register at the 5-byte font sprite for the lowest nibble of Vx.
     fn font(&mut self, x: u8) {
         let font_char = self.registers[x as usize] & 0xF;
-        self.index_register = (font_char * 5) as u16;
+        self.index_register = (FONT_BASE + font_char as usize * 5) as u16;
     }
 
-    fn get_key(&mut self, x: u8, window: &mut Window) {
-        if let Some(key) = self.get_depressed_key(window) {
-            self.registers[x as usize] = key;
-        } else {
-            self.program_counter -= 2;
+    /// Waits for a key to be pressed and let go, as on the COSMAC VIP, and stores it in Vx. If a
+    /// key has not been let go yet, the cpu stops executing until set_keys sees one.
+    fn get_key(&mut self, x: u8) {
+        if !self.take_released_key(x) {
+            self.waiting_for_key = Some(x);
         }
     }
 
+    /// Stores a key that was let go in Vx, and returns false if there was not one. If several keys
+    /// were let go at once, the lowest one is used. Each release is only seen once.
+    fn take_released_key(&mut self, x: u8) -> bool {
+        if self.released_keys == 0 {
+            return false;
+        }
+
+        let key = self.released_keys.trailing_zeros();
+        self.registers[x as usize] = key as u8;
+        self.released_keys &= !(1 << key);
+        true
+    }
+
     fn add_to_index(&mut self, x: u8) {
         let arg1 = self.registers[x as usize];
 
@@ -158,119 +711,101 @@ impl CPU {
     }
 
     fn read_timer(&mut self, x: u8) {
-        self.registers[x as usize] = *self.delay_timer.lock().unwrap();
+        self.registers[x as usize] = self.delay_timer;
     }
 
-    async fn set_timer(&mut self, x: u8) {
-        let mut interval = interval(Duration::from_secs_f64(1.0 / 60.0));
-        *self.delay_timer.lock().unwrap() = self.registers[x as usize];
-        loop {
-            interval.tick().await;
-            let mut timer = self.delay_timer.lock().unwrap();
-            if *timer > 0 {
-                *timer -= 1;
-            }
-        }
+    fn set_timer(&mut self, x: u8) {
+        self.delay_timer = self.registers[x as usize];
+    }
+
+    /// Counts the delay and sound timers down by one. This should be called at the timer rate,
+    /// Chip8::timer_hz, which is 60 times a second by default.
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    /// Skips over the next instruction, which is four bytes long if it is F000 NNNN.
+    fn skip_next(&mut self) {
+        self.program_counter += match decode_variant(self.read_opcode(), self.variant) {
+            Instruction::LdILong => 4,
+            _ => 2,
+        };
+    }
+
+    /// Sets the index register to the big-endian word after F000, then moves the PC past it.
+    /// Memory is only 4kiB, so the address wraps around to stay inside it.
+    fn set_index_long(&mut self) {
+        self.index_register = self.read_opcode() % self.memory.len() as u16;
+        self.program_counter += 2;
     }
 
-    /// Reads the current two-byte opcode using the PC and memory.
+    /// Reads the current two-byte opcode using the PC and memory. Addresses past the end of memory
+    /// wrap around, so that the next instruction can always be looked at.
     fn read_opcode(&self) -> u16 {
         let p = self.program_counter;
-        let op_byte1 = self.memory[p] as u16;
-        let op_byte2 = self.memory[p + 1] as u16;
+        let op_byte1 = self.memory[p % self.memory.len()] as u16;
+        let op_byte2 = self.memory[(p % self.memory.len() + 1) % self.memory.len()] as u16;
 
         // Small hack to merge the two bytes in memory.
         op_byte1 << 8 | op_byte2
     }
 
     /// Skips to the next instruction if the key in Vx is not pressed.
-    fn skip_key_npressed(&mut self, x: u8, window: &mut Window) {
-        let key = self.get_depressed_key(window);
-
-        match key {
-            Some(value) => {
-                if self.registers[x as usize] != value {
-                    self.program_counter += 2;
-                }
-            }
-            None => (),
+    fn skip_key_npressed(&mut self, x: u8) {
+        if !self.is_key_held(x) {
+            self.skip_next();
         }
     }
 
     /// Skips to the next instruction if the key in Vx is pressed.
-    fn skip_key_pressed(&mut self, x: u8, window: &mut Window) {
-        let key = self.get_depressed_key(window);
+    fn skip_key_pressed(&mut self, x: u8) {
+        if self.is_key_held(x) {
+            self.skip_next();
+        }
+    }
 
-        match key {
-            Some(value) => {
-                if self.registers[x as usize] == value {
-                    self.program_counter += 2;
-                }
-            },
-            None => (),
-        }
-    }
-
-    /// Function to get any keys that are currently being pressed. Mimics the old 16-key keyboard
-    /// that CHIP-8 programs use.
-    fn get_depressed_key(&mut self, window: &mut Window) -> Option<u8> {
-        let mut keycode: Option<u8> = None;
-        window.get_keys().iter().for_each(|key|
-            match key {
-                Key::Key1 => keycode = Some(0x1),
-                Key::Key2 => keycode = Some(0x2),
-                Key::Key3 => keycode = Some(0x3),
-                Key::Key4 => keycode = Some(0xC),
-                Key::Q => keycode = Some(0x4),
-                Key::W => keycode = Some(0x5),
-                Key::E => keycode = Some(0x6),
-                Key::R => keycode = Some(0xD),
-                Key::A => keycode = Some(0x7),
-                Key::S => keycode = Some(0x8),
-                Key::D => keycode = Some(0x9),
-                Key::F => keycode = Some(0xD),
-                Key::Z => keycode = Some(0xA),
-                Key::X => keycode = Some(0x0),
-                Key::C => keycode = Some(0xB),
-                Key::V => keycode = Some(0xF),
-                _ => (),
-            },
-        );
-        return keycode;
+    /// Returns true if the key in Vx is held. Only the lowest nibble of Vx is used.
+    fn is_key_held(&self, x: u8) -> bool {
+        let key = self.registers[x as usize] & 0xF;
+        self.keys & (1 << key) != 0
     }
 
     /// Generates a random u8, bitwise ands it with kk and then stores it in Vx.
     fn random(&mut self, x: u8, kk: u8) {
-        let random = rand::thread_rng().gen_range(0..u8::MAX);
+        let random = self.rng.gen_range(0..u8::MAX);
         self.registers[x as usize] = random & kk;
     }
 
-    /// Jumps a to an instruction offset by the value of Vx. This allows for decision tables.
+    /// Jumps a to an instruction offset by the value of V0. This allows for decision tables.
+    /// The address wraps around to stay within memory.
     fn jump_offset(&mut self, nnn: u16) {
         let offset = self.registers[0];
-        self.program_counter = (nnn + offset as u16) as usize;
+        self.program_counter = (nnn + offset as u16) as usize % self.memory.len();
     }
 
-    /// Shifts Vx left once. Sets VF to 1 if there is an overflow.
-    fn shift_left(&mut self, x: u8) {
-        if self.registers[x as usize] & 0x80 == 0x80 {
-            self.registers[0xF] = 1;
-        } else {
-            self.registers[0xF] = 0;
+    /// The register that 8XY6 and 8XYE shift, depending on the shift quirk.
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        match self.quirks.shift {
+            ShiftSource::Vx => self.registers[x as usize],
+            ShiftSource::Vy => self.registers[y as usize],
         }
-
-        self.registers[x as usize] <<= 1;
     }
 
-    /// Shifts Vx right once. Sets VF to 1 if there is an overflow.
-    fn shift_right(&mut self, x: u8) {
-        if self.registers[x as usize] & 0x1 == 0x1 {
-            self.registers[0xF] = 1;
-        } else {
-            self.registers[0xF] = 0;
-        }
+    /// Shifts the source register left once and puts the result in Vx. Sets VF to the bit that was
+    /// shifted out of the source. VF is set last, so it holds the flag even if x is F.
+    fn shift_left(&mut self, x: u8, y: u8) {
+        let source = self.shift_source(x, y);
+        self.registers[x as usize] = source << 1;
+        self.registers[0xF] = source >> 7;
+    }
 
-        self.registers[x as usize] >>= 1;
+    /// Shifts the source register right once and puts the result in Vx. Sets VF to the bit that
+    /// was shifted out of the source. VF is set last, so it holds the flag even if x is F.
+    fn shift_right(&mut self, x: u8, y: u8) {
+        let source = self.shift_source(x, y);
+        self.registers[x as usize] = source >> 1;
+        self.registers[0xF] = source & 0x1;
     }
 
     /// Subtracts Vx from Vy and puts the result in Vx. 
@@ -328,82 +863,134 @@ impl CPU {
     /// Skips to the next instruction if Vx and Vy are not equal.
     fn skip_nequal(&mut self, x: u8, y: u8) {
         if self.registers[x as usize] != self.registers[y as usize] {
-            self.program_counter += 2;
+            self.skip_next();
+        }
+    }
+
+    /// Sets Vx to the column and the register after it, wrapping from VF to V0, to the row of the
+    /// mouse.
+    fn read_mouse(&mut self, x: u8, (column, row): (u8, u8)) {
+        self.registers[x as usize] = column;
+        self.registers[(x as usize + 1) % 16] = row;
+    }
+
+    /// Skips to the next instruction if Vx is greater than Vy.
+    fn skip_greater(&mut self, x: u8, y: u8) {
+        if self.registers[x as usize] > self.registers[y as usize] {
+            self.skip_next();
         }
     }
 
     /// Skips to the next instruction if Vx and Vy are equal.
     fn skip_equal(&mut self, x: u8, y: u8) {
         if self.registers[x as usize] == self.registers[y as usize] {
-            self.program_counter += 2;
+            self.skip_next();
         }
     }
 
     /// Skips to the next instruction if Vx is not equal to kk.
     fn skip_x_nequal(&mut self, x: u8, kk: u8) {
         if self.registers[x as usize] != kk {
-            self.program_counter += 2;
+            self.skip_next();
         }
     }
 
     /// Skips to the next instruction if Vx is equal to kk.
     fn skip_x_equal(&mut self, x: u8, kk: u8) {
         if self.registers[x as usize] == kk {
-            self.program_counter += 2;
+            self.skip_next();
         }
     }
 
+    /// The size of the screen in the current display mode. There is only the 64x32 mode for now,
+    /// but drawing uses this rather than the constants so that a SUPER-CHIP 128x64 mode only has
+    /// to change it.
+    pub fn screen_size(&self) -> (usize, usize) {
+        (WIDTH, HEIGHT)
+    }
+
     /// Displays a sprite found in memory at the index register.
-    /// The sprite is n rows tall and is displayed at (Vx, Vy).
-    fn display(&mut self, x: u8, y: u8, n: u8, window: &mut Window, buffer: &mut Vec<u32>) {
-        // Gets the coordinates to display the sprite.
-        let mut xp = self.registers[x as usize];
-        let mut yp = self.registers[y as usize];
+    /// The sprite is n rows tall and is displayed at (Vx, Vy). Each row is a byte of 8 pixels, or
+    /// two bytes if the sprite is 16 pixels wide.
+    fn display(&mut self, x: u8, y: u8, n: u8, width_bits: usize) {
+        // Gets the coordinates to display the sprite. The starting position wraps around the
+        // screen, while the parts of the sprite that go off the edge are clipped or wrapped
+        // depending on the edge quirk. Vx and Vy are read before VF is reset, in case either is
+        // VF. VF is only set again by a collision, so it is never left over from before.
+        let (width, height) = self.screen_size();
+        let (vx, vy) = (self.registers[x as usize], self.registers[y as usize]);
+        let xp = vx as usize % width;
+        let yp = vy as usize % height;
         self.registers[0xF] = 0;
+        let mut flipped = 0;
 
-        // Progressivley display each row, starting at the top.
-        'rows: for row in 0..n {
-            // If the bottom of the screen is reached then stop.
-            if yp >= 32 {
+        // Progressivley display each row, starting at the top. The positions are worked out in
+        // usize, as a row times WIDTH does not fit in a byte.
+        for row in 0..n as usize {
+            // If the bottom of the screen is reached then stop, or carry on from the top.
+            if yp + row >= height && self.quirks.edge == Edge::Clip {
                 break;
             }
 
             // Get the sprite row to display. Each bit in the byte means to flip the current value
             // of the pixel in its place. For example, if the bit is a 1 and the pixel is currently
             // on, then it gets turned off. If the bit is 0, the pixel is not changed.
-            let sprite_row = self.memory[(self.index_register + row as u16) as usize];
-
-            // Iterate over each bit in the byte.
-            for j in 0..8 {
-                // Stops if the end of the screen is reached.
-                if xp >= 64 {
-                    continue 'rows;
+            let bytes = width_bits / 8;
+            let address = self.index_register as usize + row * bytes;
+            let sprite_row = (0..bytes).fold(0u16, |bits, i| bits << 8 | self.read_memory(address + i) as u16) << (16 - width_bits);
+
+            // Iterate over each bit in the row.
+            for j in 0..width_bits {
+                // Stops if the end of the screen is reached, and carries on with the next row, or
+                // carries on from the left.
+                if xp + j >= width && self.quirks.edge == Edge::Clip {
+                    break;
                 }
+                let pixel = (yp + row) % height * width + (xp + j) % width;
                 // Use a bit mask to grab the bit we want.
-                let mask = 0x80 >> j;
+                let mask = 0x8000 >> j;
                 match sprite_row & mask {
-                    // Matches if the bit we want is 1.
-                    1|2|4|8|16|32|64|128 =>
-                    // If it the pixel is on, turn it off.
-                    if buffer[(yp * WIDTH as u8 + xp) as usize] == 1 {
-                        buffer[yp as usize * WIDTH + xp as usize] = 0;
-                        self.registers[0xF] = 1;
-                    // Else if it is off then turn it on.
-                    } else if buffer[(yp * WIDTH as u8 + xp) as usize] == 0 {
-                        buffer[yp as usize * WIDTH + xp as usize] = u32::MAX;
+                    // Matches if the bit we want is 0.
+                    0 => (),
+                    // Otherwise the bit is 1.
+                    _ => {
+                        flipped += 1;
+                        // If it the pixel is on, turn it off.
+                        if self.framebuffer[pixel] {
+                            self.framebuffer[pixel] = false;
+                            self.registers[0xF] = 1;
+                        // Else if it is off then turn it on.
+                        } else {
+                            self.framebuffer[pixel] = true;
+                        }
                     },
-                    // Do nothing if the bit is 0.
-                    _ => (),
                 }
-                // Move over one.
-                xp += 1;
             }
-            // Go back to the start of the row and go down one row.
-            xp -= 8;
-            yp += 1;
         }
-        // Displays the canvas.
-        window.update_with_buffer(buffer, WIDTH, HEIGHT).unwrap();
+        let draw = Draw {
+            pc: self.instruction_address(),
+            x: vx,
+            y: vy,
+            height: n,
+            collided: self.registers[0xF] == 1,
+        };
+        if self.log_collisions && draw.collided {
+            self.log(format!(
+                "Collision at 0x{:03X}: {}-row sprite at ({}, {}).",
+                draw.pc, draw.height, draw.x, draw.y,
+            ));
+        }
+        if let Some(stats) = &mut self.draw_stats {
+            stats.draws += 1;
+            stats.pixels_flipped += flipped;
+            stats.collisions += draw.collided as u64;
+            stats.rows += n as u64;
+            stats.add_region(xp, yp, width_bits, n as usize);
+        }
+        self.last_draw = Some(draw);
+
+        // Tells the backend to display the canvas.
+        self.frame_dirty = true;
     }
 
     /// Set the index register to nnn.
@@ -411,15 +998,10 @@ impl CPU {
         self.index_register = nnn;
     }
 
-    /// Adds kk to Vx. Does not affect VF if thers is an overflow.
+    /// Adds kk to Vx, wrapping around on an overflow. Never affects VF, even if there is an
+    /// overflow, as on the original hardware.
     fn add(&mut self, x: u8, kk: u8) {
-        let val = self.registers[x as usize];
-
-        match val.checked_add(kk) {
-            Some(value) => self.registers[x as usize] = value,
-            // If an overflow occurs, then set it to it's previous value minus one.
-            None => self.registers[x as usize] -= 1,
-        }
+        self.registers[x as usize] = self.registers[x as usize].wrapping_add(kk);
     }
 
     /// Sets Vx to kk.
@@ -428,35 +1010,48 @@ impl CPU {
     }
 
     /// Changes the PC to nnn and stores the prevoius value on the stack to return to it later.
-    /// Panics if the stack is full.
-    fn call(&mut self, nnn: u16) {
+    /// Errors if the stack is full.
+    fn call(&mut self, nnn: u16) -> Result<(), Error> {
         let sp = self.stack_pointer;
         let stack = &mut self.stack;
 
         if sp >= stack.len() {
-            panic!("Stack overflow!")
+            return Err(Error::StackOverflow { pc: self.instruction_address() });
         }
 
         stack[sp] = self.program_counter as u16;
         self.stack_pointer += 1;
         self.program_counter = nnn as usize;
+        Ok(())
     }
 
     /// Pops an instruction from stack and set the PC to it.
-    /// Panics if the stack is empty.
-    fn ret(&mut self) {
+    /// Errors if the stack is empty.
+    fn ret(&mut self) -> Result<(), Error> {
         if self.stack_pointer == 0 {
-          panic!("Stack underflow");
+            return Err(Error::StackUnderflow { pc: self.instruction_address() });
         }
 
         self.stack_pointer -= 1;
         let addr = self.stack[self.stack_pointer];
         self.program_counter = addr as usize;
+        Ok(())
     }
 
     /// Clears the screen.
-    fn clear(&mut self, window: &mut Window) {
-        window.update_with_buffer(&[0u32; WIDTH * HEIGHT], WIDTH, HEIGHT).unwrap();
+    fn clear(&mut self) {
+        self.framebuffer = [false; WIDTH * HEIGHT];
+        self.frame_dirty = true;
+    }
+
+    /// Moves the display up n rows, losing the top rows and leaving the rows at the bottom off.
+    fn scroll_up(&mut self, n: u8) {
+        let (width, height) = self.screen_size();
+        let n = (n as usize).min(height);
+        let screen = &mut self.framebuffer[..width * height];
+        screen.copy_within(n * width.., 0);
+        screen[(height - n) * width..].fill(false);
+        self.frame_dirty = true;
     }
 
     /// Sets the PC to nnn.
@@ -479,3 +1074,1364 @@ impl CPU {
         }
     }
 }
+
+/// How an instruction uses VF: whether it reads VF, stores data in it, or sets it as a flag.
+fn vf_use(instruction: Instruction) -> (bool, bool, bool) {
+    let f = |x: u8| x == 0xF;
+    match instruction {
+        Instruction::SeVxByte(x, _)
+        | Instruction::SneVxByte(x, _)
+        | Instruction::Skp(x)
+        | Instruction::Sknp(x)
+        | Instruction::LdDtVx(x)
+        | Instruction::LdStVx(x)
+        | Instruction::LdFVx(x)
+        | Instruction::LdBVx(x)
+        | Instruction::Pitch(x)
+        | Instruction::LdIVx(x)
+        | Instruction::LdRVx(x) => (f(x), false, false),
+        Instruction::SeVxVy(x, y) | Instruction::SneVxVy(x, y) | Instruction::SgtVxVy(x, y) => (f(x) || f(y), false, false),
+        Instruction::LdVxByte(x, _)
+        | Instruction::Rnd(x, _)
+        | Instruction::LdVxDt(x)
+        | Instruction::LdVxK(x)
+        | Instruction::LdVxI(x)
+        | Instruction::LdVxR(x) => (false, f(x), false),
+        Instruction::LdMouse(x) => (false, f(x) || x == 0xE, false),
+        Instruction::AddVxByte(x, _) => (f(x), f(x), false),
+        Instruction::LdVxVy(x, y) => (f(y), f(x), false),
+        Instruction::Or(x, y) | Instruction::And(x, y) | Instruction::Xor(x, y) => (f(x) || f(y), f(x), false),
+        Instruction::AddVxVy(x, y)
+        | Instruction::Sub(x, y)
+        | Instruction::Subn(x, y)
+        | Instruction::Shr(x, y)
+        | Instruction::Shl(x, y)
+        | Instruction::Drw(x, y, _) => (f(x) || f(y), false, true),
+        Instruction::AddIVx(x) => (f(x), false, true),
+        _ => (false, false, false),
+    }
+}
+
+/// Formats a one-line summary of the cpu state, e.g.
+/// `PC:0200 I:0000 SP:0 DT:00 V:00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00`.
+impl fmt::Display for CPU {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PC:{:04X} I:{:04X} SP:{:X} DT:{:02X} V:",
+            self.program_counter,
+            self.index_register,
+            self.stack_pointer,
+            self.delay_timer,
+        )?;
+
+        for (i, register) in self.registers.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02X}", register)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ascii::assert_frame_eq;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::fs::{self, File};
+    use std::process::Command;
+    use std::sync::OnceLock;
+
+    /// A sink for tests to read back what the cpu wrote to it.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        /// Returns the lines written since the last call, and forgets them.
+        fn take(&self) -> Vec<String> {
+            let written = std::mem::take(&mut *self.0.lock().unwrap());
+            String::from_utf8(written).unwrap().lines().map(str::to_string).collect()
+        }
+    }
+
+    /// Sends the cpu's warnings to a sink that the test can read.
+    fn capture_warnings(cpu: &mut CPU) -> Captured {
+        let captured = Captured::default();
+        cpu.warnings = Sink::new(captured.clone());
+        captured
+    }
+
+    /// Sends the cpu's logs to a sink that the test can read.
+    fn capture_log(cpu: &mut CPU) -> Captured {
+        let captured = Captured::default();
+        cpu.log = Sink::new(captured.clone());
+        captured
+    }
+
+    /// The environment variable that every_arm_of_execute_is_covered sets on the run of the tests
+    /// that it starts, naming the file that the kinds of instruction executed are written to.
+    const COVERAGE_FILE: &str = "CHIP8_EXECUTE_COVERAGE";
+
+    /// The name of the instruction's kind, e.g. Drw, which is what the coverage check counts.
+    fn kind(instruction: Instruction) -> String {
+        format!("{:?}", instruction).split('(').next().unwrap().to_string()
+    }
+
+    /// The kinds of instruction written to the coverage file so far, and the file.
+    type Seen = Mutex<(BTreeSet<String>, File)>;
+
+    /// Writes the kind of instruction to the coverage file, the first time that each kind is seen,
+    /// if the tests were started by every_arm_of_execute_is_covered.
+    fn count_kind(instruction: Instruction) {
+        static SEEN: OnceLock<Option<Seen>> = OnceLock::new();
+        let seen = SEEN.get_or_init(|| {
+            let path = std::env::var_os(COVERAGE_FILE)?;
+            Some(Mutex::new((BTreeSet::new(), File::options().create(true).append(true).open(path).unwrap())))
+        });
+        if let Some(seen) = seen {
+            let (kinds, file) = &mut *seen.lock().unwrap();
+            let kind = kind(instruction);
+            if !kinds.contains(&kind) {
+                writeln!(file, "{}", kind).unwrap();
+                kinds.insert(kind);
+            }
+        }
+    }
+
+    /// A cpu in a known state: the opcodes loaded at 0x200, V0 to VF holding 0x00, 0x11, ... 0xFF
+    /// so that every register can be told apart, I pointing at 0x300, and the stack, timers and
+    /// display empty.
+    fn known_cpu(opcodes: &[u16]) -> CPU {
+        let program: Vec<u8> = opcodes.iter().flat_map(|opcode| opcode.to_be_bytes()).collect();
+        let mut cpu = CPU::new(&program);
+        for (i, register) in cpu.registers.iter_mut().enumerate() {
+            *register = i as u8 * 0x11;
+        }
+        cpu.index_register = 0x300;
+        cpu
+    }
+
+    /// Executes the next instruction, which must not stop the program.
+    fn step(cpu: &mut CPU) {
+        assert_eq!(checked_step(cpu), Ok(None));
+    }
+
+    /// Executes the next instruction and returns the result for the test to check. Only the
+    /// instructions run through here count towards every_arm_of_execute_is_covered, so that tests
+    /// which run random memory and check nothing but that it does not panic are left out.
+    fn checked_step(cpu: &mut CPU) -> Result<Option<RunOutcome>, Error> {
+        count_kind(cpu.next_instruction());
+        cpu.step()
+    }
+
+    #[test]
+    fn empty_memory_stops_the_program() {
+        let mut cpu = known_cpu(&[0x0000]);
+        assert_eq!(checked_step(&mut cpu), Ok(Some(RunOutcome::ReachedEmptyMemory)));
+    }
+
+    #[test]
+    fn cls_clears_the_display() {
+        let mut cpu = known_cpu(&[0x00E0]);
+        cpu.framebuffer = [true; WIDTH * HEIGHT];
+        step(&mut cpu);
+        assert_eq!(cpu.framebuffer, [false; WIDTH * HEIGHT]);
+        assert!(cpu.frame_dirty);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn ret_pops_the_return_address() {
+        let mut cpu = known_cpu(&[0x00EE]);
+        cpu.stack[0] = 0x234;
+        cpu.stack_pointer = 1;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x234);
+        assert_eq!(cpu.stack_pointer, 0);
+    }
+
+    #[test]
+    fn ret_with_an_empty_stack_is_an_error() {
+        let mut cpu = known_cpu(&[0x00EE]);
+        assert_eq!(checked_step(&mut cpu), Err(Error::StackUnderflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn exit_halts() {
+        let mut cpu = known_cpu(&[0x00FD]);
+        assert_eq!(checked_step(&mut cpu), Ok(Some(RunOutcome::Halted)));
+    }
+
+    #[test]
+    fn scroll_up_moves_the_display_up() {
+        let mut cpu = known_cpu(&[0x00D2]);
+        cpu.framebuffer[3 * WIDTH + 5] = true;
+        cpu.framebuffer[(HEIGHT - 1) * WIDTH] = true;
+        step(&mut cpu);
+        assert!(cpu.framebuffer[WIDTH + 5]);
+        assert!(cpu.framebuffer[(HEIGHT - 3) * WIDTH]);
+        assert_eq!(cpu.framebuffer.iter().filter(|&&on| on).count(), 2);
+    }
+
+    #[test]
+    fn sys_is_ignored() {
+        let mut cpu = known_cpu(&[0x0123]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn sys_is_an_error_in_strict_mode() {
+        let mut cpu = known_cpu(&[0x0123]);
+        cpu.strict = true;
+        assert_eq!(checked_step(&mut cpu), Err(Error::MachineCode { pc: 0x200, address: 0x123 }));
+    }
+
+    #[test]
+    fn jp_jumps() {
+        let mut cpu = known_cpu(&[0x1234]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x234);
+    }
+
+    #[test]
+    fn call_pushes_the_next_address() {
+        let mut cpu = known_cpu(&[0x2345]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x345);
+        assert_eq!(cpu.stack(), &[0x202]);
+    }
+
+    #[test]
+    fn call_with_a_full_stack_is_an_error() {
+        let mut cpu = known_cpu(&[0x2345]);
+        cpu.stack_pointer = STACK_DEPTH;
+        assert_eq!(checked_step(&mut cpu), Err(Error::StackOverflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn a_deeper_stack_fits_more_nested_calls() {
+        // Calls itself until the stack overflows.
+        for depth in [STACK_DEPTH, 32] {
+            let mut cpu = known_cpu(&[0x2200]);
+            cpu.set_stack_depth(depth);
+            for _ in 0..depth {
+                step(&mut cpu);
+            }
+            assert_eq!(cpu.stack(), vec![0x202; depth]);
+            assert_eq!(checked_step(&mut cpu), Err(Error::StackOverflow { pc: 0x200 }), "depth {}", depth);
+        }
+    }
+
+    #[test]
+    fn se_vx_byte_skips_if_equal() {
+        let mut cpu = known_cpu(&[0x3111]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+
+        let mut cpu = known_cpu(&[0x3112]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn sne_vx_byte_skips_if_not_equal() {
+        let mut cpu = known_cpu(&[0x4112]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+
+        let mut cpu = known_cpu(&[0x4111]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn se_vx_vy_skips_if_equal() {
+        let mut cpu = known_cpu(&[0x5110]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+
+        let mut cpu = known_cpu(&[0x5120]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn ld_vx_byte_sets_vx() {
+        let mut cpu = known_cpu(&[0x6A42]);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[0xA], 0x42);
+    }
+
+    #[test]
+    fn add_vx_byte_wraps_without_touching_vf() {
+        let mut cpu = known_cpu(&[0x7101, 0x7E20]);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[1], 0x12);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[0xE], 0x0E);
+        assert_eq!(cpu.registers[0xF], 0xFF);
+    }
+
+    #[test]
+    fn ld_vx_vy_copies_vy() {
+        let mut cpu = known_cpu(&[0x8120]);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[1], 0x22);
+        assert_eq!(cpu.registers[2], 0x22);
+    }
+
+    #[test]
+    fn or_ors_vy_into_vx() {
+        let mut cpu = known_cpu(&[0x8121]);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[1], 0x33);
+    }
+
+    #[test]
+    fn and_ands_vy_into_vx() {
+        let mut cpu = known_cpu(&[0x8132]);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[1], 0x11);
+    }
+
+    #[test]
+    fn xor_xors_vy_into_vx() {
+        let mut cpu = known_cpu(&[0x8133]);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[1], 0x22);
+    }
+
+    #[test]
+    fn add_vx_vy_sets_vf_on_a_carry() {
+        let mut cpu = known_cpu(&[0x8124, 0x8EE4]);
+        step(&mut cpu);
+        assert_eq!((cpu.registers[1], cpu.registers[0xF]), (0x33, 0));
+        step(&mut cpu);
+        assert_eq!((cpu.registers[0xE], cpu.registers[0xF]), (0xDC, 1));
+    }
+
+    #[test]
+    fn sub_sets_vf_if_there_is_no_borrow() {
+        let mut cpu = known_cpu(&[0x8215, 0x8125]);
+        step(&mut cpu);
+        assert_eq!((cpu.registers[2], cpu.registers[0xF]), (0x11, 1));
+        step(&mut cpu);
+        assert_eq!((cpu.registers[1], cpu.registers[0xF]), (0x00, 1));
+    }
+
+    #[test]
+    fn sub_clears_vf_on_a_borrow() {
+        let mut cpu = known_cpu(&[0x8125]);
+        step(&mut cpu);
+        assert_eq!((cpu.registers[1], cpu.registers[0xF]), (0xEF, 0));
+    }
+
+    #[test]
+    fn shr_shifts_right_into_vf() {
+        let mut cpu = known_cpu(&[0x8346]);
+        step(&mut cpu);
+        assert_eq!((cpu.registers[3], cpu.registers[0xF]), (0x19, 1));
+    }
+
+    #[test]
+    fn subn_subtracts_vx_from_vy() {
+        let mut cpu = known_cpu(&[0x8127, 0x8217]);
+        step(&mut cpu);
+        assert_eq!((cpu.registers[1], cpu.registers[0xF]), (0x11, 1));
+        step(&mut cpu);
+        assert_eq!((cpu.registers[2], cpu.registers[0xF]), (0xEF, 0));
+    }
+
+    #[test]
+    fn shl_shifts_left_into_vf() {
+        let mut cpu = known_cpu(&[0x8E0E]);
+        step(&mut cpu);
+        assert_eq!((cpu.registers[0xE], cpu.registers[0xF]), (0xDC, 1));
+    }
+
+    #[test]
+    fn sne_vx_vy_skips_if_not_equal() {
+        let mut cpu = known_cpu(&[0x9120]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+
+        let mut cpu = known_cpu(&[0x9110]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn ld_i_sets_the_index() {
+        let mut cpu = known_cpu(&[0xA123]);
+        step(&mut cpu);
+        assert_eq!(cpu.index(), 0x123);
+    }
+
+    #[test]
+    fn jp_v0_adds_v0_to_the_address() {
+        let mut cpu = known_cpu(&[0xB300]);
+        cpu.registers[0] = 0x12;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x312);
+    }
+
+    #[test]
+    fn rnd_masks_the_random_byte() {
+        for _ in 0..32 {
+            let mut cpu = known_cpu(&[0xC10F]);
+            step(&mut cpu);
+            assert_eq!(cpu.registers[1] & 0xF0, 0);
+        }
+    }
+
+    #[test]
+    fn drw_draws_the_sprite_at_i() {
+        let mut cpu = known_cpu(&[0xD125]);
+        cpu.index_register = FONT_BASE as u16;
+        cpu.registers[1] = 2;
+        cpu.registers[2] = 1;
+        step(&mut cpu);
+        assert_frame_eq(&cpu.framebuffer, "
+            ......
+            ..####
+            ..#..#
+            ..#..#
+            ..#..#
+            ..####
+        ");
+        assert_eq!(cpu.registers[0xF], 0);
+        assert!(cpu.frame_dirty);
+    }
+
+    #[test]
+    fn drw_sets_vf_when_a_pixel_is_turned_off() {
+        let mut cpu = known_cpu(&[0xD001, 0xD001]);
+        cpu.memory[0x300] = 0xC0;
+        step(&mut cpu);
+        assert_frame_eq(&cpu.framebuffer, "##");
+        assert_eq!(cpu.registers[0xF], 0);
+        step(&mut cpu);
+        assert_frame_eq(&cpu.framebuffer, "");
+        assert_eq!(cpu.registers[0xF], 1);
+    }
+
+    #[test]
+    fn drw_is_counted_in_the_draw_stats() {
+        // Draws two pixels, erases them, then draws them again in a 3-row sprite further down.
+        let mut cpu = known_cpu(&[0xD001, 0xD001, 0xD013]);
+        cpu.memory[0x300] = 0xC0;
+        cpu.draw_stats = Some(DrawStats::default());
+        for _ in 0..3 {
+            step(&mut cpu);
+        }
+
+        let stats = cpu.draw_stats.unwrap();
+        assert_eq!(stats.draws, 3);
+        assert_eq!(stats.pixels_flipped, 6);
+        assert_eq!(stats.collisions, 1);
+        assert_eq!(stats.average_height(), 5.0 / 3.0);
+    }
+
+    #[test]
+    fn drw_with_no_rows_only_clears_vf() {
+        let mut cpu = known_cpu(&[0xD120]);
+        step(&mut cpu);
+        assert_frame_eq(&cpu.framebuffer, "");
+        assert_eq!(cpu.registers[0xF], 0);
+    }
+
+    #[test]
+    fn drw_with_no_rows_draws_a_16x16_sprite_on_superchip() {
+        let mut cpu = known_cpu(&[0xD000]);
+        cpu.variant = Variant::SuperChip;
+        cpu.memory[0x300..0x320].copy_from_slice(&[0x80, 0x01].repeat(16));
+        cpu.memory[0x31E..0x320].copy_from_slice(&[0xFF, 0xFF]);
+        cpu.registers[0xF] = 1;
+        step(&mut cpu);
+
+        let edges = format!("#{}#", ".".repeat(14));
+        assert_frame_eq(&cpu.framebuffer, &(vec![edges.as_str(); 15].join("\n") + "\n" + &"#".repeat(16)));
+        assert_eq!(cpu.registers[0xF], 0);
+        assert_eq!(cpu.last_draw.map(|draw| draw.height), Some(16));
+    }
+
+    #[test]
+    fn skp_skips_if_the_key_is_held() {
+        let mut cpu = known_cpu(&[0xE19E]);
+        cpu.registers[1] = 5;
+        cpu.set_keys(1 << 5);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+    }
+
+    #[test]
+    fn sknp_skips_if_the_key_is_not_held() {
+        let mut cpu = known_cpu(&[0xE1A1]);
+        cpu.registers[1] = 5;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+    }
+
+    #[test]
+    fn a_key_pressed_from_code_is_seen_by_skp() {
+        let mut cpu = known_cpu(&[0xE09E, 0x0000, 0xE09E]);
+        cpu.registers[0] = 5;
+        cpu.key_down(5);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+
+        cpu.key_up(5);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x206);
+    }
+
+    #[test]
+    fn ld_i_long_reads_the_next_word() {
+        let mut cpu = known_cpu(&[0xF000, 0x0ABC]);
+        step(&mut cpu);
+        assert_eq!(cpu.index(), 0xABC);
+        assert_eq!(cpu.pc(), 0x204);
+    }
+
+    #[test]
+    fn audio_copies_16_bytes_from_i_into_the_pattern() {
+        let mut cpu = known_cpu(&[0xF002]);
+        for i in 0..17 {
+            cpu.memory[0x300 + i] = 0xA0 + i as u8;
+        }
+        step(&mut cpu);
+        assert_eq!(cpu.audio_pattern, core::array::from_fn(|i| 0xA0 + i as u8));
+    }
+
+    #[test]
+    fn ld_vx_dt_reads_the_delay_timer() {
+        let mut cpu = known_cpu(&[0xF107]);
+        cpu.delay_timer = 0x42;
+        step(&mut cpu);
+        assert_eq!(cpu.registers[1], 0x42);
+    }
+
+    #[test]
+    fn ld_vx_k_waits_for_a_key_to_be_let_go() {
+        let mut cpu = known_cpu(&[0xF10A]);
+        step(&mut cpu);
+        assert_eq!(cpu.waiting_for_key, Some(1));
+
+        cpu.set_keys(1 << 7);
+        assert_eq!(cpu.waiting_for_key, Some(1));
+        cpu.set_keys(0);
+        assert_eq!(cpu.waiting_for_key, None);
+        assert_eq!(cpu.registers[1], 7);
+    }
+
+    #[test]
+    fn ld_dt_vx_sets_the_delay_timer() {
+        let mut cpu = known_cpu(&[0xF115]);
+        step(&mut cpu);
+        assert_eq!(cpu.delay_timer, 0x11);
+    }
+
+    #[test]
+    fn ld_st_vx_sets_the_sound_timer() {
+        let mut cpu = known_cpu(&[0xF118]);
+        step(&mut cpu);
+        assert_eq!(cpu.sound_timer, 0x11);
+    }
+
+    #[test]
+    fn add_i_vx_adds_vx_to_the_index() {
+        let mut cpu = known_cpu(&[0xF11E]);
+        step(&mut cpu);
+        assert_eq!(cpu.index(), 0x311);
+    }
+
+    #[test]
+    fn ld_f_vx_points_at_the_glyph() {
+        let mut cpu = known_cpu(&[0xF129]);
+        step(&mut cpu);
+        assert_eq!(cpu.index() as usize, FONT_BASE + 5);
+    }
+
+    #[test]
+    fn ld_b_vx_stores_the_decimal_digits() {
+        let mut cpu = known_cpu(&[0xFF33]);
+        step(&mut cpu);
+        assert_eq!(cpu.memory[0x300..0x303], [2, 5, 5]);
+    }
+
+    #[test]
+    fn pitch_sets_the_pitch() {
+        let mut cpu = known_cpu(&[0xF13A]);
+        step(&mut cpu);
+        assert_eq!(cpu.pitch, 0x11);
+    }
+
+    #[test]
+    fn ld_i_vx_stores_v0_to_vx() {
+        let mut cpu = known_cpu(&[0xF255]);
+        step(&mut cpu);
+        assert_eq!(cpu.memory[0x300..0x304], [0x00, 0x11, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn ld_vx_i_loads_v0_to_vx() {
+        let mut cpu = known_cpu(&[0xF265]);
+        cpu.memory[0x300..0x304].copy_from_slice(&[0xA0, 0xA1, 0xA2, 0xA3]);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[..4], [0xA0, 0xA1, 0xA2, 0x33]);
+    }
+
+    #[test]
+    fn ld_r_vx_stores_the_rpl_flags() {
+        let mut cpu = known_cpu(&[0xF275]);
+        step(&mut cpu);
+        assert_eq!(cpu.rpl_flags, [0x00, 0x11, 0x22, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn ld_vx_r_loads_the_rpl_flags() {
+        let mut cpu = known_cpu(&[0xF285]);
+        cpu.rpl_flags = [0xA0, 0xA1, 0xA2, 0xA3, 0, 0, 0, 0];
+        step(&mut cpu);
+        assert_eq!(cpu.registers[..4], [0xA0, 0xA1, 0xA2, 0x33]);
+    }
+
+    #[test]
+    fn ld_mouse_reads_the_mouse_position() {
+        let mut cpu = known_cpu(&[0xF14D]);
+        cpu.mouse = Some((12, 34));
+        step(&mut cpu);
+        assert_eq!(cpu.registers[1..3], [12, 34]);
+    }
+
+    #[test]
+    fn ld_mouse_without_a_mouse_is_ignored() {
+        let mut cpu = known_cpu(&[0xF14D]);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[1..3], [0x11, 0x22]);
+
+        let mut cpu = known_cpu(&[0xF14D]);
+        cpu.strict = true;
+        assert_eq!(checked_step(&mut cpu), Err(Error::UnknownOpcode { pc: 0x200, opcode: 0xF14D }));
+    }
+
+    #[test]
+    fn skip_skips_on_chip8e() {
+        let mut cpu = known_cpu(&[0x0188]);
+        cpu.variant = Variant::Chip8E;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+    }
+
+    #[test]
+    fn sgt_skips_if_greater_on_chip8e() {
+        let mut cpu = known_cpu(&[0x5211]);
+        cpu.variant = Variant::Chip8E;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+
+        let mut cpu = known_cpu(&[0x5121]);
+        cpu.variant = Variant::Chip8E;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn jp_back_and_forward_are_relative_on_chip8e() {
+        let mut cpu = known_cpu(&[0xBB04]);
+        cpu.variant = Variant::Chip8E;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x1FC);
+
+        let mut cpu = known_cpu(&[0xBF06]);
+        cpu.variant = Variant::Chip8E;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x206);
+    }
+
+    #[test]
+    fn unknown_opcodes_are_ignored() {
+        let mut cpu = known_cpu(&[0xFFFF]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn unknown_opcodes_are_an_error_in_strict_mode() {
+        let mut cpu = known_cpu(&[0xFFFF]);
+        cpu.strict = true;
+        assert_eq!(checked_step(&mut cpu), Err(Error::UnknownOpcode { pc: 0x200, opcode: 0xFFFF }));
+    }
+
+    #[test]
+    fn every_arm_of_execute_is_covered() {
+        // Runs the rest of the cpu tests again, writing down each kind of instruction that they
+        // step through and check. The tests only see which kinds were run once they have all
+        // finished, so they are run in a process of their own.
+        let path = std::env::temp_dir().join(format!("chip8-coverage-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["cpu::tests::", "--skip", "every_arm_of_execute_is_covered"])
+            .env(COVERAGE_FILE, &path)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "the cpu tests failed:\n{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr),
+        );
+        let executed = fs::read_to_string(&path).unwrap_or_default();
+        let _ = fs::remove_file(&path);
+        let executed: BTreeSet<&str> = executed.lines().collect();
+
+        // Every kind of instruction that can be decoded, with the first opcode that decodes to it.
+        let mut decoded = BTreeMap::new();
+        for variant in [Variant::Chip8, Variant::Chip8E, Variant::SuperChip] {
+            for opcode in 0..=0xFFFF {
+                decoded.entry(kind(decode_variant(opcode, variant))).or_insert(opcode);
+            }
+        }
+        let missing: Vec<String> = decoded.iter()
+            .filter(|(kind, _)| !executed.contains(kind.as_str()))
+            .map(|(kind, opcode)| format!("{} ({:04X})", kind, opcode))
+            .collect();
+        assert!(missing.is_empty(), "No test executes these instructions: {}", missing.join(", "));
+    }
+
+    #[test]
+    fn fuzz_memory_keeps_the_font_and_the_program() {
+        let mut cpu = CPU::new(&[0x12, 0x00, 0xAB, 0xCD]);
+        let before = cpu.memory;
+        cpu.fuzz_memory(7, 4);
+
+        assert_eq!(cpu.memory[..LOAD_ADDRESS + 4], before[..LOAD_ADDRESS + 4]);
+        assert!(cpu.memory[LOAD_ADDRESS + 4..].iter().filter(|&&byte| byte != 0).count() > 3000);
+        assert_ne!(cpu.registers, [0; 16]);
+
+        let mut again = CPU::new(&[0x12, 0x00, 0xAB, 0xCD]);
+        again.fuzz_memory(7, 4);
+        assert_eq!(again.memory, cpu.memory);
+        assert_eq!(again.registers, cpu.registers);
+    }
+
+    #[test]
+    fn display_summarises_the_state_on_one_line() {
+        let mut cpu = CPU::new(&[]);
+        cpu.index_register = 0x2A3;
+        cpu.stack[0] = 0x204;
+        cpu.stack_pointer = 1;
+        cpu.delay_timer = 0x3C;
+        cpu.registers[0] = 0x12;
+        cpu.registers[0xF] = 0x01;
+
+        assert_eq!(
+            cpu.to_string(),
+            "PC:0200 I:02A3 SP:1 DT:3C V:12 00 00 00 00 00 00 00 00 00 00 00 00 00 00 01",
+        );
+        assert_eq!(cpu.registers()[0], 0x12);
+        assert_eq!((cpu.pc(), cpu.index()), (0x200, 0x2A3));
+        assert_eq!(cpu.stack(), &[0x204]);
+    }
+
+    #[test]
+    fn key_tests_after_fx0a_see_the_same_keys() {
+        // FX0A, then EX9E and EXA1 on the key that it stored, each followed by a marker that shows
+        // whether it skipped.
+        let mut cpu = known_cpu(&[0xF10A, 0xE19E, 0x6A01, 0xE1A1, 0x6B01]);
+        cpu.set_keys(1 << 5 | 1 << 7);
+        step(&mut cpu);
+        assert_eq!(cpu.waiting_for_key, Some(1));
+
+        // Letting go of 5 while 7 is still held gives FX0A 5, and the key tests then agree that 5
+        // is up. Vx is 7 for the second pass, which is still held.
+        cpu.set_keys(1 << 7);
+        assert_eq!((cpu.waiting_for_key, cpu.registers[1]), (None, 5));
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x204);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x20A);
+        assert_eq!((cpu.registers[0xA], cpu.registers[0xB]), (1, 0xBB));
+
+        cpu.registers[1] = 7;
+        cpu.program_counter = 0x202;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x206);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x208);
+    }
+
+    #[test]
+    fn executing_with_the_pc_below_2_does_not_underflow() {
+        let mut cpu = CPU::new(&[]);
+        cpu.program_counter = 0;
+        assert_eq!(cpu.execute(Instruction::Ret), Err(Error::StackUnderflow { pc: 0xFFE }));
+        cpu.strict = true;
+        assert_eq!(cpu.execute(Instruction::Unknown(0xFFFF)), Err(Error::UnknownOpcode { pc: 0xFFE, opcode: 0xFFFF }));
+        assert_eq!(cpu.execute(Instruction::LdIVx(0)), Err(Error::LowMemoryWrite { pc: 0xFFE, address: 0 }));
+
+        cpu.program_counter = 1;
+        assert_eq!(cpu.execute(Instruction::Drw(0, 0, 1)), Ok(None));
+        assert_eq!(cpu.last_draw.map(|draw| draw.pc), Some(0xFFF));
+        assert_eq!(cpu.execute(Instruction::JpBack(4)), Ok(None));
+        assert_eq!(cpu.pc(), 0xFFB);
+    }
+
+    #[test]
+    fn jp_v0_wraps_the_pc_within_memory() {
+        let mut cpu = known_cpu(&[0xBFFF]);
+        cpu.registers[0] = 0x10;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x00F);
+        // The jump lands in the empty memory below the font, which stops the program rather than
+        // reading past the end of memory.
+        assert_eq!(checked_step(&mut cpu), Ok(Some(RunOutcome::ReachedEmptyMemory)));
+    }
+
+    #[test]
+    fn rpl_flags_are_stored_and_restored_and_only_8_are_kept() {
+        let mut cpu = known_cpu(&[0xFF75, 0xFF85]);
+        step(&mut cpu);
+        assert_eq!(cpu.rpl_flags, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+
+        cpu.registers = [0xEE; 16];
+        step(&mut cpu);
+        assert_eq!(cpu.registers[..8], [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+        assert_eq!(cpu.registers[8..], [0xEE; 8]);
+    }
+
+    #[test]
+    fn add_vx_byte_never_touches_vf() {
+        // Sweeps every value of Vx and kk, with and without an overflow, and with VF holding
+        // values that a carry or borrow flag could be mistaken for.
+        let mut cpu = CPU::new(&[]);
+        for vf in [0, 1, 0xAB] {
+            for vx in 0..=255u8 {
+                for kk in 0..=255u8 {
+                    cpu.registers[3] = vx;
+                    cpu.registers[0xF] = vf;
+                    assert_eq!(cpu.execute(Instruction::AddVxByte(3, kk)), Ok(None));
+                    assert_eq!(cpu.registers[3], vx.wrapping_add(kk));
+                    assert_eq!(cpu.registers[0xF], vf, "V3 = {}, kk = {}", vx, kk);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stores_below_the_load_address_get_the_configured_reaction() {
+        for (protection, strict, refused) in [
+            (LowMemoryProtection::Off, false, false),
+            (LowMemoryProtection::Warn, false, false),
+            (LowMemoryProtection::Error, false, true),
+            (LowMemoryProtection::Off, true, true),
+        ] {
+            // FX55 and FX33 with I in the font.
+            let mut cpu = known_cpu(&[0xF155, 0xF133]);
+            cpu.index_register = FONT_BASE as u16;
+            cpu.low_memory = protection;
+            cpu.strict = strict;
+            let font = cpu.memory[FONT_BASE..FONT_BASE + 3].to_vec();
+
+            for pc in [0x200, 0x202] {
+                if refused {
+                    assert_eq!(checked_step(&mut cpu), Err(Error::LowMemoryWrite { pc, address: FONT_BASE }));
+                    assert_eq!(cpu.memory[FONT_BASE..FONT_BASE + 3], font, "{:?}", protection);
+                } else {
+                    step(&mut cpu);
+                    assert_ne!(cpu.memory[FONT_BASE..FONT_BASE + 3], font, "{:?}", protection);
+                }
+                cpu.program_counter = 0x202;
+            }
+        }
+    }
+
+    #[test]
+    fn stores_above_the_load_address_are_never_refused() {
+        let mut cpu = known_cpu(&[0xF155]);
+        cpu.low_memory = LowMemoryProtection::Error;
+        cpu.strict = true;
+        step(&mut cpu);
+        assert_eq!(cpu.memory[0x300..0x302], [0x00, 0x11]);
+    }
+
+    #[test]
+    fn low_memory_protection_is_parsed_from_its_name() {
+        assert_eq!("warn".parse(), Ok(LowMemoryProtection::Warn));
+        assert_eq!("loud".parse::<LowMemoryProtection>(), Err("expected off, warn or error, found loud".to_string()));
+    }
+
+    #[test]
+    fn stores_are_protected_below_the_programs_own_load_address() {
+        // FX55 with I at 0x300, which is below a program loaded at 0x600.
+        let mut cpu = CPU::with_load_address(&[0xA3, 0x00, 0xF1, 0x55], 0x600);
+        cpu.low_memory = LowMemoryProtection::Error;
+        step(&mut cpu);
+        assert_eq!(checked_step(&mut cpu), Err(Error::LowMemoryWrite { pc: 0x602, address: 0x300 }));
+    }
+
+    #[test]
+    fn shifts_set_vf_from_the_source_register() {
+        // V1 has neither end bit set and V2 has both, so VF shows which of them was shifted.
+        for (shift, opcode, result, flag) in [
+            (ShiftSource::Vx, 0x8126, 0x01, 0),
+            (ShiftSource::Vx, 0x812E, 0x04, 0),
+            (ShiftSource::Vy, 0x8126, 0x40, 1),
+            (ShiftSource::Vy, 0x812E, 0x02, 1),
+        ] {
+            let mut cpu = known_cpu(&[opcode]);
+            cpu.quirks.shift = shift;
+            cpu.registers[1] = 0x02;
+            cpu.registers[2] = 0x81;
+            step(&mut cpu);
+            assert_eq!((cpu.registers[1], cpu.registers[2], cpu.registers[0xF]), (result, 0x81, flag), "{:04X} {:?}", opcode, shift);
+        }
+    }
+
+    #[test]
+    fn shifting_into_vf_leaves_the_flag_rather_than_the_result() {
+        for (shift, opcode) in [(ShiftSource::Vx, 0x8F26), (ShiftSource::Vy, 0x8F2E)] {
+            let mut cpu = known_cpu(&[opcode]);
+            cpu.quirks.shift = shift;
+            cpu.registers[2] = 0x81;
+            step(&mut cpu);
+            // VF starts as 0xFF, which has both end bits set.
+            assert_eq!(cpu.registers[0xF], 1, "{:04X} {:?}", opcode, shift);
+        }
+    }
+
+    #[test]
+    fn a_held_key_is_seen_by_key_tests_every_time_but_only_ends_fx0a_once_let_go() {
+        // EX9E on key 3 in a loop, with FX0A after it.
+        let mut cpu = known_cpu(&[0xE39E, 0x1200, 0xF40A]);
+        cpu.registers[3] = 3;
+        for _ in 0..5 {
+            cpu.set_keys(1 << 3);
+            cpu.program_counter = 0x200;
+            step(&mut cpu);
+            assert_eq!(cpu.pc(), 0x204);
+        }
+
+        // Holding it for more frames does not count as pressing it again.
+        step(&mut cpu);
+        for _ in 0..5 {
+            cpu.set_keys(1 << 3);
+            assert_eq!(cpu.waiting_for_key, Some(4));
+        }
+        cpu.set_keys(0);
+        assert_eq!((cpu.waiting_for_key, cpu.registers[4]), (None, 3));
+    }
+
+    #[test]
+    fn a_tapped_key_ends_fx0a_once_and_is_only_seen_held_while_it_is_down() {
+        let mut cpu = known_cpu(&[0xF40A, 0xF40A, 0xE39E]);
+        cpu.registers[3] = 3;
+        step(&mut cpu);
+
+        // Pressed for one frame and let go on the next.
+        cpu.set_keys(1 << 3);
+        assert_eq!(cpu.waiting_for_key, Some(4));
+        cpu.set_keys(0);
+        assert_eq!((cpu.waiting_for_key, cpu.registers[4]), (None, 3));
+
+        // The release has been used up, so the next FX0A waits for another one.
+        step(&mut cpu);
+        assert_eq!(cpu.waiting_for_key, Some(4));
+        cpu.set_keys(0);
+        assert_eq!(cpu.waiting_for_key, Some(4));
+
+        // The key is up, so EX9E does not skip.
+        cpu.waiting_for_key = None;
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x206);
+    }
+
+    #[test]
+    fn activity_is_worked_out_from_the_next_instruction_and_the_key_wait() {
+        let mut cpu = known_cpu(&[0x6001, 0x1202, 0xF00A]);
+        assert_eq!(cpu.activity(), Activity::Running);
+        step(&mut cpu);
+        assert_eq!(cpu.activity(), Activity::Halted);
+
+        // Waiting for a key wins over the jump, which will not be reached until a key is let go.
+        cpu.waiting_for_key = Some(0);
+        assert_eq!(cpu.activity(), Activity::WaitingForKey);
+
+        cpu.program_counter = 0x204;
+        assert_eq!(cpu.activity(), Activity::WaitingForKey);
+        cpu.set_keys(1);
+        cpu.set_keys(0);
+        assert_eq!(cpu.activity(), Activity::Running);
+    }
+
+    #[test]
+    fn a_jump_elsewhere_is_not_halted() {
+        // A jump back to the previous instruction is a loop, which may still change state.
+        let mut cpu = known_cpu(&[0x7001, 0x1200]);
+        step(&mut cpu);
+        assert_eq!(cpu.activity(), Activity::Running);
+    }
+
+    #[test]
+    fn with_no_keys_held_exa1_skips_for_every_key_and_fx0a_waits() {
+        for key in 0..16 {
+            let mut cpu = known_cpu(&[0xE3A1, 0x0000, 0xF40A]);
+            cpu.registers[3] = key;
+            cpu.set_keys(0);
+            step(&mut cpu);
+            assert_eq!(cpu.pc(), 0x204, "key {:X}", key);
+
+            step(&mut cpu);
+            for _ in 0..3 {
+                cpu.set_keys(0);
+                assert_eq!((cpu.waiting_for_key, cpu.pc()), (Some(4), 0x206), "key {:X}", key);
+                step(&mut cpu);
+            }
+        }
+    }
+
+    #[test]
+    fn with_no_keys_held_ex9e_never_skips() {
+        for key in 0..16 {
+            let mut cpu = known_cpu(&[0xE39E]);
+            cpu.registers[3] = key;
+            cpu.set_keys(0);
+            step(&mut cpu);
+            assert_eq!(cpu.pc(), 0x202, "key {:X}", key);
+        }
+    }
+
+    #[test]
+    fn ld_b_vx_always_writes_all_three_digits() {
+        for value in 0..=255u8 {
+            let mut cpu = known_cpu(&[0xF133]);
+            cpu.registers[1] = value;
+            // Stale bytes that a short write would leave behind.
+            cpu.memory[0x300..0x303].copy_from_slice(&[0xAA; 3]);
+            step(&mut cpu);
+            // The value written out in decimal, padded on the left to three digits with zeros.
+            let mut expected: Vec<u8> = format!("{:03}", value).bytes().map(|digit| digit - b'0').collect();
+            expected.push(0);
+            assert_eq!(cpu.memory[0x300..0x304], expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn chip8e_opcodes_keep_their_chip8_meaning_on_chip8() {
+        // 0188 is a machine code call and 5XY1 is unknown, so both are skipped over.
+        for opcode in [0x0188, 0x5211] {
+            let mut cpu = known_cpu(&[opcode]);
+            step(&mut cpu);
+            assert_eq!(cpu.pc(), 0x202, "{:04X}", opcode);
+        }
+        // BBNN and BFNN are BNNN, which jumps to NNN plus V0.
+        for (opcode, pc) in [(0xBB04, 0xB04), (0xBF06, 0xF06)] {
+            let mut cpu = known_cpu(&[opcode]);
+            step(&mut cpu);
+            assert_eq!(cpu.pc(), pc, "{:04X}", opcode);
+        }
+    }
+
+    /// Runs the cpu until the program stops.
+    fn run(cpu: &mut CPU) -> Option<RunOutcome> {
+        (0..100).find_map(|_| cpu.step().unwrap())
+    }
+
+    #[test]
+    fn writing_over_an_upcoming_instruction_runs_the_new_one_without_a_warning() {
+        // Stores 00FD over the jump at 0x20A, which would otherwise loop forever.
+        let mut cpu = known_cpu(&[0xA20A, 0x6000, 0x61FD, 0xF155, 0x7201, 0x1208]);
+        cpu.executed = Some(Box::new([false; 0x1000]));
+        let warnings = capture_warnings(&mut cpu);
+        assert_eq!(run(&mut cpu), Some(RunOutcome::Halted));
+        assert_eq!(cpu.registers[2], 0x23);
+        assert_eq!(warnings.take(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn writing_over_an_executed_instruction_warns_about_each_byte() {
+        // Stores 00FD over the first instruction, then jumps back to it.
+        let program = [0xA200, 0x6000, 0x61FD, 0xF155, 0x1200];
+        let mut cpu = known_cpu(&program);
+        cpu.executed = Some(Box::new([false; 0x1000]));
+        let warnings = capture_warnings(&mut cpu);
+        assert_eq!(run(&mut cpu), Some(RunOutcome::Halted));
+        assert_eq!(warnings.take(), [
+            "Warning: 0x200, which has been executed, was overwritten by the instruction at 0x206.",
+            "Warning: 0x201, which has been executed, was overwritten by the instruction at 0x206.",
+        ]);
+
+        // Nothing is tracked unless it is asked for.
+        let mut cpu = known_cpu(&program);
+        let warnings = capture_warnings(&mut cpu);
+        assert_eq!(run(&mut cpu), Some(RunOutcome::Halted));
+        assert_eq!(warnings.take(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn ld_i_long_reads_its_address_big_endian_and_moves_the_pc_past_both_words() {
+        let mut cpu = known_cpu(&[0xF000, 0x0E12, 0x6001]);
+        step(&mut cpu);
+        assert_eq!((cpu.index(), cpu.pc()), (0xE12, 0x204));
+        // The next instruction is the one after the address, not the address.
+        step(&mut cpu);
+        assert_eq!((cpu.registers[0], cpu.pc()), (1, 0x206));
+
+        // Addresses past the end of memory wrap around to stay inside it.
+        let mut cpu = known_cpu(&[0xF000, 0x1234]);
+        step(&mut cpu);
+        assert_eq!(cpu.index(), 0x234);
+    }
+
+    #[test]
+    fn skips_jump_over_all_four_bytes_of_ld_i_long() {
+        // V0 is 0, so 3000 skips, and 4000 does not.
+        let mut cpu = known_cpu(&[0x3000, 0xF000, 0x0ABC, 0x4000, 0xF000, 0x0ABC]);
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x206);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!((cpu.index(), cpu.pc()), (0xABC, 0x20C));
+    }
+
+    /// The pixels that are on, as (x, y).
+    fn lit_pixels(cpu: &CPU) -> Vec<(usize, usize)> {
+        (0..WIDTH * HEIGHT).filter(|&i| cpu.framebuffer[i]).map(|i| (i % WIDTH, i / WIDTH)).collect()
+    }
+
+    /// Draws a solid 8x4 sprite with its top left corner 4 pixels from the bottom right corner.
+    fn draw_in_the_corner(edge: Edge, vx: u8, vy: u8) -> CPU {
+        let mut cpu = known_cpu(&[0xD014]);
+        cpu.quirks.edge = edge;
+        cpu.memory[0x300..0x304].copy_from_slice(&[0xFF; 4]);
+        (cpu.registers[0], cpu.registers[1]) = (vx, vy);
+        step(&mut cpu);
+        cpu
+    }
+
+    #[test]
+    fn sprites_are_clipped_at_the_edges_of_the_screen() {
+        let (width, height) = known_cpu(&[]).screen_size();
+        let expected: Vec<_> = (height - 2..height).flat_map(|y| (width - 4..width).map(move |x| (x, y))).collect();
+        // There is no 128x64 mode yet, so only the 64x32 screen is tested.
+        assert_eq!(lit_pixels(&draw_in_the_corner(Edge::Clip, 60, 30)), expected);
+    }
+
+    #[test]
+    fn sprites_wrap_around_the_edges_of_the_screen() {
+        let (width, height) = known_cpu(&[]).screen_size();
+        let mut expected: Vec<_> = [0, 1, height - 2, height - 1].into_iter()
+            .flat_map(|y| [0, 1, 2, 3, width - 4, width - 3, width - 2, width - 1].map(|x| (x, y)))
+            .collect();
+        expected.sort_by_key(|&(x, y)| (y, x));
+        assert_eq!(lit_pixels(&draw_in_the_corner(Edge::Wrap, 60, 30)), expected);
+    }
+
+    #[test]
+    fn only_colliding_draws_are_logged() {
+        // Draws the 0 glyph at (0, 0), then at (8, 0) where nothing is on, then at (0, 0) again.
+        let mut cpu = known_cpu(&[0xD005, 0xD105, 0xD005]);
+        cpu.index_register = FONT_BASE as u16;
+        cpu.registers[1] = 8;
+        cpu.log_collisions = true;
+        let log = capture_log(&mut cpu);
+
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(log.take(), Vec::<String>::new());
+        step(&mut cpu);
+        assert_eq!(log.take(), ["Collision at 0x204: 5-row sprite at (0, 0)."]);
+    }
+
+    #[test]
+    fn collisions_are_not_logged_unless_asked_for() {
+        let mut cpu = known_cpu(&[0xD005, 0xD005]);
+        cpu.index_register = FONT_BASE as u16;
+        let log = capture_log(&mut cpu);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.registers[0xF], 1);
+        assert_eq!(log.take(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn ld_f_vx_points_at_the_a_glyph_50_bytes_into_the_font() {
+        let mut cpu = known_cpu(&[0xF329]);
+        cpu.registers[3] = 0xA;
+        step(&mut cpu);
+        assert_eq!(cpu.index() as usize, FONT_BASE + 50);
+        let i = cpu.index() as usize;
+        // The A glyph.
+        assert_eq!(cpu.memory[i..i + 5], [0xF0, 0x90, 0xF0, 0x90, 0x90]);
+    }
+
+    #[test]
+    fn ld_f_vx_only_uses_the_low_nibble() {
+        let mut cpu = known_cpu(&[0xF329]);
+        cpu.registers[3] = 0xFA;
+        step(&mut cpu);
+        assert_eq!(cpu.index() as usize, FONT_BASE + 50);
+    }
+
+    #[test]
+    fn ld_i_vx_wraps_past_the_end_of_memory_unless_strict() {
+        let mut cpu = known_cpu(&[0xFF55]);
+        cpu.index_register = 0xFF8;
+        step(&mut cpu);
+        assert_eq!(cpu.memory[0xFF8..], [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+        assert_eq!(cpu.memory[..8], [0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+
+        let mut cpu = known_cpu(&[0xFF55]);
+        cpu.index_register = 0xFF8;
+        cpu.strict = true;
+        let start = cpu.memory[..8].to_vec();
+        assert_eq!(checked_step(&mut cpu), Err(Error::MemoryOverrun { pc: 0x200, address: 0x1000 }));
+        assert_eq!(cpu.memory[..8], start);
+    }
+
+    #[test]
+    fn random_memory_never_panics() {
+        // The fuzz target in fuzz/, run over a fixed set of seeds so that it is part of cargo test.
+        for seed in 0..256 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut cpu = CPU::new(&[]);
+            rng.fill(&mut cpu.memory[..]);
+            cpu.strict = seed & 1 != 0;
+            cpu.variant = if seed & 2 != 0 { Variant::Chip8E } else { Variant::Chip8 };
+            cpu.quirks.shift = if seed & 4 != 0 { ShiftSource::Vy } else { ShiftSource::Vx };
+            cpu.quirks.display_wait = seed & 8 != 0;
+
+            for cycle in 0..2000 {
+                cpu.set_keys(if cycle % 64 < 32 { 1 << (cycle / 64 % 16) } else { 0 });
+                match cpu.step() {
+                    Ok(None) => {},
+                    Ok(Some(_)) | Err(_) => break,
+                }
+                if cycle % 10 == 0 {
+                    cpu.tick_timers();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sprites_in_the_bottom_rows_land_where_they_are_drawn() {
+        // At rows 28 to 31, a u8 index would have wrapped round to somewhere near the top.
+        let mut cpu = known_cpu(&[0xD014, 0xD014]);
+        cpu.memory[0x300..0x304].copy_from_slice(&[0x80; 4]);
+        (cpu.registers[0], cpu.registers[1]) = (8, 28);
+        step(&mut cpu);
+        assert_eq!(lit_pixels(&cpu), vec![(8, 28), (8, 29), (8, 30), (8, 31)]);
+        assert_eq!(cpu.registers[0xF], 0);
+        step(&mut cpu);
+        assert_eq!(lit_pixels(&cpu), vec![]);
+        assert_eq!(cpu.registers[0xF], 1);
+    }
+
+    #[test]
+    fn a_collision_at_row_10_is_tested_against_the_pixel_that_is_drawn() {
+        // (10 * 64 + 5) as u8 is 133, i.e. (5, 2), so that pixel is lit too to catch a wrong read.
+        let mut cpu = known_cpu(&[0xD011, 0xD011]);
+        cpu.memory[0x300] = 0x80;
+        (cpu.registers[0], cpu.registers[1]) = (5, 10);
+        cpu.framebuffer[2 * WIDTH + 5] = true;
+        step(&mut cpu);
+        assert_eq!(lit_pixels(&cpu), vec![(5, 2), (5, 10)]);
+        assert_eq!(cpu.registers[0xF], 0);
+        step(&mut cpu);
+        assert_eq!(lit_pixels(&cpu), vec![(5, 2)]);
+        assert_eq!(cpu.registers[0xF], 1);
+    }
+
+    #[test]
+    fn ld_f_vx_points_at_the_small_font_glyphs() {
+        for digit in 0..16 {
+            let mut cpu = known_cpu(&[0xF329]);
+            cpu.registers[3] = digit as u8;
+            step(&mut cpu);
+            let i = cpu.index() as usize;
+            assert_eq!(cpu.memory[i..i + 5], font::SMALL_FONT[digit * 5..digit * 5 + 5], "{:X}", digit);
+        }
+    }
+
+    #[test]
+    fn a_pc_at_the_last_byte_of_memory_or_past_it_is_out_of_range() {
+        for pc in [0xFFF, 0x1000, usize::MAX] {
+            let mut cpu = CPU::new(&[]);
+            cpu.program_counter = pc;
+            assert_eq!(checked_step(&mut cpu), Err(Error::PcOutOfRange { pc }));
+            cpu.next_instruction();
+        }
+    }
+
+    #[test]
+    fn a_sprite_crossing_the_bottom_is_clipped_or_wrapped_to_the_top() {
+        // A 1x4 sprite from row 30, so only its last two rows are off the bottom.
+        for (edge, expected) in [("clip", vec![(10, 30), (10, 31)]), ("wrap", vec![(10, 0), (10, 1), (10, 30), (10, 31)])] {
+            let mut cpu = known_cpu(&[0xD014]);
+            cpu.quirks.set(&format!("edge={}", edge)).unwrap();
+            cpu.memory[0x300..0x304].copy_from_slice(&[0x80; 4]);
+            (cpu.registers[0], cpu.registers[1]) = (10, 30);
+            step(&mut cpu);
+            assert_eq!(lit_pixels(&cpu), expected, "edge={}", edge);
+        }
+    }
+
+    #[test]
+    fn vf_clobbers_are_only_warned_about_when_the_flag_is_read_as_data() {
+        let warnings = |opcodes: &[u16], warn_vf_clobber: bool| {
+            let mut cpu = known_cpu(opcodes);
+            cpu.warn_vf_clobber = warn_vf_clobber;
+            let warnings = capture_warnings(&mut cpu);
+            for _ in opcodes {
+                step(&mut cpu);
+            }
+            warnings.take()
+        };
+
+        // Data is stored in VF, overwritten by the carry of an add, then copied into V0.
+        assert_eq!(warnings(&[0x6F05, 0x8014, 0x80F0], true), vec![
+            "Warning: VF was read as data at 0x204, but it was overwritten by a flag at 0x202 after being set at 0x200.".to_string(),
+        ]);
+        assert_eq!(warnings(&[0x6F05, 0x8014, 0x80F0], false), Vec::<String>::new());
+        // Reading the data before any flag, reading a flag that held no data, and storing over the
+        // flag without reading it are all fine.
+        for opcodes in [&[0x6F05, 0x80F0, 0x8014][..], &[0x8014, 0x80F0], &[0x6F05, 0x8014, 0x6F07, 0x80F0]] {
+            assert_eq!(warnings(opcodes, true), Vec::<String>::new(), "{:04X?}", opcodes);
+        }
+    }
+
+    #[test]
+    fn a_dirty_screen_starts_with_pixels_on_until_it_is_cleared() {
+        let mut cpu = known_cpu(&[0x00E0]);
+        assert!(cpu.framebuffer.iter().all(|&on| !on));
+        cpu.dirty_screen(7);
+        let lit = cpu.framebuffer.iter().filter(|&&on| on).count();
+        assert!(lit > 0 && lit < WIDTH * HEIGHT, "{} pixels on", lit);
+        assert!(cpu.frame_dirty);
+
+        // The same seed makes the same garbage, and another seed makes different garbage.
+        let mut again = CPU::new(&[]);
+        again.dirty_screen(7);
+        assert_eq!(again.framebuffer, cpu.framebuffer);
+        again.dirty_screen(8);
+        assert_ne!(again.framebuffer, cpu.framebuffer);
+
+        step(&mut cpu);
+        assert!(cpu.framebuffer.iter().all(|&on| !on));
+    }
+}
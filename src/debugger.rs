@@ -0,0 +1,425 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use crate::backend::Backend;
+use crate::chip8::Chip8;
+use crate::cpu::{CPU, RunOutcome};
+use crate::error::Error;
+use crate::instruction::{Instruction, MNEMONICS};
+
+/// The most instructions a single command runs before giving up, so that a condition which is
+/// never met does not hang the debugger. This is about half an hour of running at 600Hz.
+pub const STEP_LIMIT: usize = 1_000_000;
+
+/// A command typed at the debugger prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// `step [n]` - execute n instructions, or one if n is not given.
+    Step(usize),
+    /// `nextdraw` - run until the next DXYN is about to be executed.
+    NextDraw,
+    /// `runframe` - run until the display changes, i.e. just after the next DXYN or 00E0.
+    RunFrame,
+    /// `runto <addr>` - run until the instruction at the address is about to be executed.
+    RunTo(usize),
+    /// `break-op <mnemonic>` - run until an instruction with the mnemonic, e.g. DRW or CALL, is
+    /// about to be executed.
+    BreakOp(&'static str),
+    /// `watch mem <addr>` or `watch reg <Vx>` - stop whenever the value at the location changes.
+    Watch(Watch),
+    /// `show` - print the state of the cpu and the next instruction.
+    Show,
+    /// `quit` - stop debugging.
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (Some("step" | "s"), None) => Command::Step(1),
+            (Some("step" | "s"), Some(n)) => Command::Step(n.parse().map_err(|_| format!("not a number: {}", n))?),
+            (Some("nextdraw"), None) => Command::NextDraw,
+            (Some("runframe"), None) => Command::RunFrame,
+            (Some("runto"), Some(address)) => Command::RunTo(parse_address(address)?),
+            (Some("break-op"), Some(mnemonic)) => {
+                let mnemonic = mnemonic.to_uppercase();
+                match MNEMONICS.iter().find(|known| **known == mnemonic) {
+                    Some(known) => Command::BreakOp(known),
+                    None => return Err(format!("not a mnemonic: {}", mnemonic)),
+                }
+            },
+            (Some("watch"), Some("mem")) => {
+                let address = parse_address(words.next().ok_or("watch mem needs an address")?)?;
+                if address >= 0x1000 {
+                    return Err(format!("0x{:X} is past the end of memory", address));
+                }
+                Command::Watch(Watch::Memory(address))
+            },
+            (Some("watch"), Some("reg")) => Command::Watch(Watch::Register(parse_register(words.next().ok_or("watch reg needs a register")?)?)),
+            (Some("show"), None) => Command::Show,
+            (Some("quit" | "q"), None) => Command::Quit,
+            _ => return Err(format!("unknown command: {}", s.trim())),
+        };
+
+        match words.next() {
+            Some(word) => Err(format!("unexpected {}", word)),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Parses an address written in hex with a 0x prefix, or in decimal.
+fn parse_address(s: &str) -> Result<usize, String> {
+    let address = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    address.map_err(|_| format!("not an address: {}", s))
+}
+
+/// Parses a register written as V0 to VF.
+fn parse_register(s: &str) -> Result<u8, String> {
+    s.strip_prefix(['V', 'v'])
+        .filter(|digit| digit.len() == 1)
+        .and_then(|digit| u8::from_str_radix(digit, 16).ok())
+        .ok_or_else(|| format!("not a register: {}", s))
+}
+
+/// A location that the debugger stops on when its value changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    /// A byte of memory.
+    Memory(usize),
+    /// One of the registers V0 to VF.
+    Register(u8),
+}
+
+impl Watch {
+    /// The current value at the location.
+    fn read(self, cpu: &CPU) -> u8 {
+        match self {
+            Watch::Memory(address) => cpu.memory[address],
+            Watch::Register(x) => cpu.registers()[x as usize],
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Memory(address) => write!(f, "0x{:03X}", address),
+            Watch::Register(x) => write!(f, "V{:X}", x),
+        }
+    }
+}
+
+/// Why the debugger stopped running the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The requested number of instructions were executed.
+    Stepped,
+    /// The next instruction is the one that was asked for.
+    Reached,
+    /// FX0A is waiting for a key, so nothing more can run until one is pressed and let go.
+    WaitingForKey,
+    /// The program stopped by itself.
+    Outcome(RunOutcome),
+    /// STEP_LIMIT instructions were executed without reaching the instruction.
+    Limit,
+    /// A watched location changed, in the instruction at pc.
+    Changed { watch: Watch, old: u8, new: u8, pc: usize },
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Stepped => write!(f, "Stepped"),
+            Stop::Reached => write!(f, "Reached"),
+            Stop::WaitingForKey => write!(f, "Waiting for a key"),
+            Stop::Outcome(outcome) => write!(f, "Stopped: {:?}", outcome),
+            Stop::Limit => write!(f, "Gave up after {} instructions", STEP_LIMIT),
+            Stop::Changed { watch, old, new, pc } => {
+                write!(f, "{} changed from 0x{:02X} to 0x{:02X} at 0x{:03X}", watch, old, new, pc)
+            },
+        }
+    }
+}
+
+/// Runs a machine an instruction at a time under the control of the user. The timers are counted
+/// down by the number of instructions executed rather than by real time, so that a program
+/// behaves the same however long the user takes between commands.
+pub struct Debugger<B: Backend> {
+    /// The machine being debugged.
+    pub chip8: Chip8<B>,
+    /// The number of instructions executed since the last timer tick.
+    cycles: u32,
+    /// The locations that stop the program when they change.
+    watches: Vec<Watch>,
+}
+
+impl<B: Backend> Debugger<B> {
+    /// Starts debugging the machine.
+    pub fn new(chip8: Chip8<B>) -> Debugger<B> {
+        Debugger { chip8, cycles: 0, watches: Vec::new() }
+    }
+
+    /// The state of the machine being debugged.
+    pub fn cpu(&self) -> &CPU {
+        &self.chip8.cpu
+    }
+
+    /// Executes a single instruction, counting the timers down once every hz / timer_hz instructions.
+    pub fn step(&mut self) -> Result<Option<RunOutcome>, Error> {
+        let outcome = self.chip8.step()?;
+
+        self.cycles += self.chip8.timer_hz;
+        while self.cycles >= self.chip8.hz {
+            self.chip8.cpu.tick_timers();
+            self.cycles -= self.chip8.hz;
+        }
+        Ok(outcome)
+    }
+
+    /// Stops the program whenever the value at the location changes.
+    pub fn watch(&mut self, watch: Watch) {
+        self.watches.push(watch);
+    }
+
+    /// Executes a single instruction, comparing the watched locations before and after it.
+    /// Returns why to stop if the program stopped or a watched location changed.
+    fn step_watched(&mut self) -> Result<Option<Stop>, Error> {
+        let pc = self.chip8.cpu.pc();
+        let before: Vec<u8> = self.watches.iter().map(|watch| watch.read(&self.chip8.cpu)).collect();
+
+        if let Some(outcome) = self.step()? {
+            return Ok(Some(Stop::Outcome(outcome)));
+        }
+        for (&watch, old) in self.watches.iter().zip(before) {
+            let new = watch.read(&self.chip8.cpu);
+            if new != old {
+                return Ok(Some(Stop::Changed { watch, old, new, pc }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Executes at least one instruction, then keeps going until the next instruction matches
+    /// the condition. The matching instruction is not executed. Stops early if FX0A starts
+    /// waiting for a key or a watched location changes.
+    pub fn run_until(&mut self, condition: impl Fn(&CPU, Instruction) -> bool) -> Result<Stop, Error> {
+        for _ in 0..STEP_LIMIT {
+            if self.chip8.cpu.waiting_for_key.is_some() {
+                return Ok(Stop::WaitingForKey);
+            }
+            if let Some(stop) = self.step_watched()? {
+                return Ok(stop);
+            }
+            if condition(&self.chip8.cpu, self.next_instruction()) {
+                return Ok(Stop::Reached);
+            }
+        }
+        Ok(Stop::Limit)
+    }
+
+    /// Runs a command, other than quit, and returns why it stopped.
+    pub fn execute(&mut self, command: Command) -> Result<Stop, Error> {
+        // The keys are read before each command, so that a program waiting on a key can be fed one.
+        let keys = self.chip8.read_keys();
+        self.chip8.cpu.set_keys(keys);
+
+        let stop = match command {
+            Command::Step(n) => {
+                let mut stop = Stop::Stepped;
+                for _ in 0..n {
+                    if self.chip8.cpu.waiting_for_key.is_some() {
+                        stop = Stop::WaitingForKey;
+                        break;
+                    }
+                    if let Some(watched) = self.step_watched()? {
+                        stop = watched;
+                        break;
+                    }
+                }
+                stop
+            },
+            Command::NextDraw => self.run_until(|_, instruction| matches!(instruction, Instruction::Drw(..)))?,
+            Command::RunFrame => {
+                // Shows anything already drawn, so that only the changes made from here count.
+                self.present();
+                self.run_until(|cpu, _| cpu.frame_dirty)?
+            },
+            Command::RunTo(address) => self.run_until(|cpu, _| cpu.pc() == address)?,
+            Command::BreakOp(mnemonic) => self.run_until(|_, instruction| instruction.mnemonic() == mnemonic)?,
+            Command::Watch(watch) => {
+                self.watch(watch);
+                Stop::Stepped
+            },
+            Command::Show | Command::Quit => Stop::Stepped,
+        };
+
+        self.present();
+        Ok(stop)
+    }
+
+    /// Displays the framebuffer if it has changed since it was last displayed.
+    fn present(&mut self) {
+        if self.chip8.cpu.frame_dirty {
+            self.chip8.backend.present(&self.chip8.cpu.framebuffer);
+            self.chip8.cpu.frame_dirty = false;
+        }
+    }
+
+    /// The instruction that will be executed next.
+    pub fn next_instruction(&self) -> Instruction {
+        self.chip8.cpu.next_instruction()
+    }
+
+    /// Reads commands from the input until quit is typed, the input ends or the program stops,
+    /// printing the state of the cpu after each one.
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        self.show(&mut output)?;
+        write!(output, "> ")?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                match line.parse::<Command>() {
+                    Ok(Command::Quit) => return Ok(()),
+                    Ok(command) => match self.execute(command) {
+                        Ok(Stop::Outcome(outcome)) => {
+                            writeln!(output, "{}", Stop::Outcome(outcome))?;
+                            return Ok(());
+                        },
+                        Ok(stop) => {
+                            match command {
+                                Command::Show => (),
+                                Command::Watch(watch) => writeln!(output, "Watching {}.", watch)?,
+                                _ => writeln!(output, "{}", stop)?,
+                            }
+                            self.show(&mut output)?;
+                        },
+                        Err(error) => {
+                            writeln!(output, "{}.", error)?;
+                            return Ok(());
+                        },
+                    },
+                    Err(error) => writeln!(output, "Error: {}.", error)?,
+                }
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Prints the state of the cpu and the next instruction.
+    fn show(&self, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "{}", self.chip8.cpu)?;
+        writeln!(output, "0x{:03X} {}", self.chip8.cpu.pc(), self.next_instruction())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ascii::assert_frame_eq;
+    use crate::backend::Headless;
+
+    /// Draws the sprite at 0x300 twice, with other instructions before and between the draws.
+    const TWO_DRAWS: [u8; 14] = [0x60, 0x05, 0x70, 0x01, 0xA3, 0x00, 0xD0, 0x15, 0x70, 0x01, 0xD0, 0x15, 0x12, 0x0C];
+
+    fn debugger(program: &[u8]) -> Debugger<Headless> {
+        Debugger::new(Chip8::new(program, Headless))
+    }
+
+    #[test]
+    fn nextdraw_stops_just_before_the_next_draw() {
+        let mut debugger = debugger(&TWO_DRAWS);
+        assert_eq!(debugger.execute(Command::NextDraw), Ok(Stop::Reached));
+        assert_eq!(debugger.cpu().pc(), 0x206);
+        assert_eq!(debugger.cpu().registers()[0], 6);
+        // The draw has not been executed yet.
+        assert_eq!(debugger.cpu().last_draw, None);
+
+        // From a draw, it runs that one and stops before the one after it.
+        assert_eq!(debugger.execute(Command::NextDraw), Ok(Stop::Reached));
+        assert_eq!(debugger.cpu().pc(), 0x20A);
+        assert_eq!(debugger.cpu().last_draw.map(|draw| draw.pc), Some(0x206));
+    }
+
+    #[test]
+    fn nextdraw_gives_up_if_there_is_no_draw() {
+        let mut debugger = debugger(&[0x70, 0x01, 0x12, 0x00]);
+        assert_eq!(debugger.execute(Command::NextDraw), Ok(Stop::Limit));
+    }
+
+    #[test]
+    fn runto_stops_just_before_the_address() {
+        let mut debugger = debugger(&TWO_DRAWS);
+        assert_eq!(debugger.execute(Command::RunTo(0x208)), Ok(Stop::Reached));
+        assert_eq!(debugger.cpu().pc(), 0x208);
+        assert_eq!(debugger.cpu().registers()[0], 6);
+    }
+
+    #[test]
+    fn runframe_stops_just_after_the_display_changes() {
+        // Draws the sprite at 0x300, clears the screen, then goes round doing nothing.
+        let mut debugger = debugger(&[0x70, 0x01, 0xA3, 0x00, 0xD0, 0x11, 0x00, 0xE0, 0x12, 0x08]);
+        debugger.chip8.cpu.memory[0x300] = 0x80;
+
+        assert_eq!(debugger.execute(Command::RunFrame), Ok(Stop::Reached));
+        assert_eq!(debugger.cpu().pc(), 0x206);
+        assert_frame_eq(&debugger.cpu().framebuffer, ".#");
+
+        assert_eq!(debugger.execute(Command::RunFrame), Ok(Stop::Reached));
+        assert_eq!(debugger.cpu().pc(), 0x208);
+        assert_frame_eq(&debugger.cpu().framebuffer, "");
+    }
+
+    #[test]
+    fn commands_are_parsed_with_their_arguments() {
+        assert_eq!("nextdraw".parse(), Ok(Command::NextDraw));
+        assert_eq!("runto 0x20A".parse(), Ok(Command::RunTo(0x20A)));
+        assert_eq!("runto 522".parse(), Ok(Command::RunTo(0x20A)));
+        assert_eq!("runto x".parse::<Command>(), Err("not an address: x".to_string()));
+        assert_eq!("nextdraw 2".parse::<Command>(), Err("unknown command: nextdraw 2".to_string()));
+        assert_eq!("runto 0x20A 3".parse::<Command>(), Err("unexpected 3".to_string()));
+    }
+
+    #[test]
+    fn writing_a_watched_register_stops_with_the_old_and_new_values() {
+        // V3 is set to 5 at 0x202, set to 5 again at 0x204, which is not a change, then added to.
+        let mut debugger = debugger(&[0x60, 0x01, 0x63, 0x05, 0x63, 0x05, 0x73, 0x02, 0x12, 0x08]);
+        assert_eq!("watch reg V3".parse(), Ok(Command::Watch(Watch::Register(3))));
+        assert_eq!(debugger.execute(Command::Watch(Watch::Register(3))), Ok(Stop::Stepped));
+
+        let stop = debugger.execute(Command::Step(10));
+        assert_eq!(stop, Ok(Stop::Changed { watch: Watch::Register(3), old: 0, new: 5, pc: 0x202 }));
+        assert_eq!(debugger.cpu().pc(), 0x204);
+        assert_eq!(stop.unwrap().to_string(), "V3 changed from 0x00 to 0x05 at 0x202");
+
+        let stop = debugger.execute(Command::Step(10));
+        assert_eq!(stop, Ok(Stop::Changed { watch: Watch::Register(3), old: 5, new: 7, pc: 0x206 }));
+    }
+
+    #[test]
+    fn break_op_call_stops_just_before_each_call() {
+        // Calls the subroutine at 0x208 twice. It adds 1 to V0 and returns.
+        let mut debugger = debugger(&[0x60, 0x05, 0x22, 0x08, 0x22, 0x08, 0x12, 0x06, 0x70, 0x01, 0x00, 0xEE]);
+        assert_eq!("break-op call".parse(), Ok(Command::BreakOp("CALL")));
+        assert_eq!("break-op nope".parse::<Command>(), Err("not a mnemonic: NOPE".to_string()));
+
+        assert_eq!(debugger.execute(Command::BreakOp("CALL")), Ok(Stop::Reached));
+        assert_eq!((debugger.cpu().pc(), debugger.cpu().registers()[0]), (0x202, 5));
+        // The call it is stopped at runs first, so this stops at the next one.
+        assert_eq!(debugger.execute(Command::BreakOp("CALL")), Ok(Stop::Reached));
+        assert_eq!((debugger.cpu().pc(), debugger.cpu().registers()[0]), (0x204, 6));
+        assert_eq!(debugger.execute(Command::BreakOp("CALL")), Ok(Stop::Limit));
+        assert_eq!(debugger.cpu().registers()[0], 7);
+    }
+}
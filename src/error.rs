@@ -0,0 +1,47 @@
+use std::fmt;
+
+/// Something that went wrong while running a program, which stops the cpu.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A subroutine was called from the address while every level of the stack was in use.
+    StackOverflow { pc: usize },
+    /// A return was executed at the address with nothing on the stack.
+    StackUnderflow { pc: usize },
+    /// The instruction at pc wrote to an address below the load address while low memory was
+    /// protected.
+    LowMemoryWrite { pc: usize, address: usize },
+    /// The opcode at pc is not a known instruction, in strict mode.
+    UnknownOpcode { pc: usize, opcode: u16 },
+    /// The instruction at pc called the machine code routine at address with 0NNN, which cannot be
+    /// run, in strict mode.
+    MachineCode { pc: usize, address: usize },
+    /// The instruction at pc wrote past the end of memory, in strict mode.
+    MemoryOverrun { pc: usize, address: usize },
+    /// The PC ran off the end of memory.
+    PcOutOfRange { pc: usize },
+    /// A saved state could not be loaded, because part of it is out of range.
+    InvalidState { reason: &'static str },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::StackOverflow { pc } => write!(f, "Stack overflow at 0x{:03X}", pc),
+            Error::StackUnderflow { pc } => write!(f, "Stack underflow at 0x{:03X}", pc),
+            Error::LowMemoryWrite { pc, address } => {
+                write!(f, "Write to protected memory at 0x{:03X} by the instruction at 0x{:03X}", address, pc)
+            },
+            Error::UnknownOpcode { pc, opcode } => write!(f, "Unknown opcode {:04X} at 0x{:03X}", opcode, pc),
+            Error::MachineCode { pc, address } => {
+                write!(f, "Call to the machine code routine at 0x{:03X} by the instruction at 0x{:03X}", address, pc)
+            },
+            Error::MemoryOverrun { pc, address } => {
+                write!(f, "Write past the end of memory to 0x{:X} by the instruction at 0x{:03X}", address, pc)
+            },
+            Error::PcOutOfRange { pc } => write!(f, "The PC ran off the end of memory at 0x{:03X}", pc),
+            Error::InvalidState { reason } => write!(f, "Invalid state: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
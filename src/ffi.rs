@@ -0,0 +1,119 @@
+//! A C interface to a headless machine, for embedding the emulator in programs that are not
+//! written in Rust. The functions are declared in `include/chip8.h`.
+//!
+//! A machine is created with `chip8_create` and must be freed with `chip8_destroy`. The host
+//! steps it and counts its timers down itself, and reads the display with
+//! `chip8_get_framebuffer` when it wants to show it.
+
+use std::ptr;
+use std::slice;
+
+use crate::backend::Headless;
+use crate::chip8::Chip8;
+use crate::cpu::{HEIGHT, WIDTH};
+
+/// A machine created by chip8_create. C code only ever sees a pointer to one.
+pub struct Chip8Handle {
+    chip8: Chip8<Headless>,
+}
+
+/// chip8_step executed the instruction and the program carries on.
+pub const CHIP8_RUNNING: i32 = 0;
+/// chip8_step found that the program has stopped by itself.
+pub const CHIP8_STOPPED: i32 = 1;
+/// chip8_step failed with an error, such as a stack overflow.
+pub const CHIP8_ERROR: i32 = -1;
+
+/// Creates a machine with the len bytes at program loaded at 0x200. Returns null if the program
+/// does not fit in memory.
+///
+/// # Safety
+///
+/// program must point to len readable bytes, or be null if len is 0.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_create(program: *const u8, len: usize) -> *mut Chip8Handle {
+    if len > 0x1000 - crate::cpu::LOAD_ADDRESS {
+        return ptr::null_mut();
+    }
+    let program = if len == 0 { &[][..] } else { slice::from_raw_parts(program, len) };
+    Box::into_raw(Box::new(Chip8Handle { chip8: Chip8::new(program, Headless) }))
+}
+
+/// Frees a machine created by chip8_create. Does nothing if handle is null.
+///
+/// # Safety
+///
+/// handle must have come from chip8_create and not have been destroyed already.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_destroy(handle: *mut Chip8Handle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Executes a single instruction. Returns CHIP8_RUNNING, CHIP8_STOPPED or CHIP8_ERROR. Nothing is
+/// executed while FX0A waits for a key.
+///
+/// # Safety
+///
+/// handle must be a live machine from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_step(handle: *mut Chip8Handle) -> i32 {
+    match (*handle).chip8.step() {
+        Ok(None) => CHIP8_RUNNING,
+        Ok(Some(_)) => CHIP8_STOPPED,
+        Err(_) => CHIP8_ERROR,
+    }
+}
+
+/// Counts the delay and sound timers down once. Call this 60 times a second.
+///
+/// # Safety
+///
+/// handle must be a live machine from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_tick_timers(handle: *mut Chip8Handle) {
+    (*handle).chip8.cpu.tick_timers();
+}
+
+/// Presses key 0x0 to 0xF on the keypad. Other keys are ignored.
+///
+/// # Safety
+///
+/// handle must be a live machine from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_key_down(handle: *mut Chip8Handle, key: u8) {
+    (*handle).chip8.cpu.key_down(key);
+}
+
+/// Lets go of key 0x0 to 0xF on the keypad. Other keys are ignored.
+///
+/// # Safety
+///
+/// handle must be a live machine from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_key_up(handle: *mut Chip8Handle, key: u8) {
+    (*handle).chip8.cpu.key_up(key);
+}
+
+/// Copies the 64x32 display into out row by row, one byte per pixel that is 1 if it is on and 0
+/// if not. Copies at most len bytes and returns the number copied, which is 2048 if out is big
+/// enough, or 0 if out is null.
+///
+/// # Safety
+///
+/// handle must be a live machine from chip8_create, and out must point to len writable bytes or be
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_get_framebuffer(handle: *const Chip8Handle, out: *mut u8, len: usize) -> usize {
+    if out.is_null() || len == 0 {
+        return 0;
+    }
+    let framebuffer = (*handle).chip8.framebuffer();
+    let len = len.min(WIDTH * HEIGHT);
+    let out = slice::from_raw_parts_mut(out, len);
+    for (byte, &pixel) in out.iter_mut().zip(framebuffer.iter()) {
+        *byte = pixel as u8;
+    }
+    len
+}
@@ -0,0 +1,490 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// A decoded CHIP-8 instruction. The names follow the mnemonics in Cowgod's CHIP-8 reference,
+/// with x and y being register numbers, kk a byte, nnn an address and n a nibble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0000 - Empty memory, which is treated as the end of the program.
+    Empty,
+    /// 00E0 - Clear the display.
+    Cls,
+    /// 00EE - Return from a subroutine.
+    Ret,
+    /// 00FD - Exit the interpreter. Added by SUPER-CHIP.
+    Exit,
+    /// 00Dn - Scroll the display up n rows. Added by XO-CHIP.
+    ScrollUp(u8),
+    /// 0nnn - Call the machine code routine at nnn. Only the original interpreters could do this,
+    /// and it is ignored.
+    Sys(u16),
+    /// 1nnn - Jump to nnn.
+    Jp(u16),
+    /// 2nnn - Call the subroutine at nnn.
+    Call(u16),
+    /// 3xkk - Skip the next instruction if Vx == kk.
+    SeVxByte(u8, u8),
+    /// 4xkk - Skip the next instruction if Vx != kk.
+    SneVxByte(u8, u8),
+    /// 5xy0 - Skip the next instruction if Vx == Vy.
+    SeVxVy(u8, u8),
+    /// 6xkk - Set Vx to kk.
+    LdVxByte(u8, u8),
+    /// 7xkk - Add kk to Vx.
+    AddVxByte(u8, u8),
+    /// 8xy0 - Set Vx to Vy.
+    LdVxVy(u8, u8),
+    /// 8xy1 - Set Vx to Vx OR Vy.
+    Or(u8, u8),
+    /// 8xy2 - Set Vx to Vx AND Vy.
+    And(u8, u8),
+    /// 8xy3 - Set Vx to Vx XOR Vy.
+    Xor(u8, u8),
+    /// 8xy4 - Add Vy to Vx, setting VF on a carry.
+    AddVxVy(u8, u8),
+    /// 8xy5 - Subtract Vy from Vx, setting VF if there is no borrow.
+    Sub(u8, u8),
+    /// 8xy6 - Shift Vx right, setting VF to the bit shifted out.
+    Shr(u8, u8),
+    /// 8xy7 - Set Vx to Vy minus Vx, setting VF if there is no borrow.
+    Subn(u8, u8),
+    /// 8xyE - Shift Vx left, setting VF to the bit shifted out.
+    Shl(u8, u8),
+    /// 9xy0 - Skip the next instruction if Vx != Vy.
+    SneVxVy(u8, u8),
+    /// Annn - Set the index register to nnn.
+    LdI(u16),
+    /// Bnnn - Jump to nnn plus V0.
+    JpV0(u16),
+    /// Cxkk - Set Vx to a random byte AND kk.
+    Rnd(u8, u8),
+    /// Dxyn - Draw the n-byte sprite at the index register at (Vx, Vy).
+    Drw(u8, u8, u8),
+    /// Ex9E - Skip the next instruction if the key in Vx is pressed.
+    Skp(u8),
+    /// ExA1 - Skip the next instruction if the key in Vx is not pressed.
+    Sknp(u8),
+    /// F000 nnnn - Set the index register to the 16-bit address in the next two bytes. Added by
+    /// XO-CHIP. This is the only four-byte instruction.
+    LdILong,
+    /// F002 - Load the XO-CHIP audio pattern from the index register.
+    Audio,
+    /// Fx07 - Set Vx to the delay timer.
+    LdVxDt(u8),
+    /// Fx0A - Wait for a key press and store it in Vx.
+    LdVxK(u8),
+    /// Fx15 - Set the delay timer to Vx.
+    LdDtVx(u8),
+    /// Fx18 - Set the sound timer to Vx.
+    LdStVx(u8),
+    /// Fx1E - Add Vx to the index register.
+    AddIVx(u8),
+    /// Fx29 - Point the index register at the font sprite for the digit in Vx.
+    LdFVx(u8),
+    /// Fx33 - Store the decimal digits of Vx at the index register.
+    LdBVx(u8),
+    /// Fx3A - Set the XO-CHIP pitch register to Vx.
+    Pitch(u8),
+    /// Fx55 - Store V0 to Vx in memory starting at the index register.
+    LdIVx(u8),
+    /// Fx65 - Load V0 to Vx from memory starting at the index register.
+    LdVxI(u8),
+    /// Fx75 - Store V0 to Vx in the RPL user flags. Added by SUPER-CHIP.
+    LdRVx(u8),
+    /// Fx85 - Load V0 to Vx from the RPL user flags. Added by SUPER-CHIP.
+    LdVxR(u8),
+    /// Fx4D - Set Vx and the register after it to the position of the mouse on the display. Not
+    /// part of any CHIP-8 variant, and only works when the mouse is turned on.
+    LdMouse(u8),
+    /// 0188 - Skip the next instruction. Added by CHIP-8E.
+    Skip,
+    /// 5xy1 - Skip the next instruction if Vx > Vy. Added by CHIP-8E.
+    SgtVxVy(u8, u8),
+    /// BBnn - Jump back nn bytes from this instruction. Added by CHIP-8E.
+    JpBack(u8),
+    /// BFnn - Jump forward nn bytes from this instruction. Added by CHIP-8E.
+    JpForward(u8),
+    /// An opcode that is not recognised.
+    Unknown(u16),
+}
+
+/// A CHIP-8 variant with its own instructions, which take the place of opcodes that are unused or
+/// mean something else on CHIP-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    /// The original instruction set.
+    #[default]
+    Chip8,
+    /// CHIP-8E, Gilles Detillieux's extension for the COSMAC VIP. Adds 0188, 5XY1, BBNN and BFNN.
+    /// BBNN and BFNN replace BNNN jumps to 0xBxx and 0xFxx.
+    Chip8E,
+    /// SUPER-CHIP, where DXY0 draws a 16x16 sprite. Its other instructions do not clash with
+    /// CHIP-8, so they are understood on every variant.
+    #[serde(rename = "schip")]
+    SuperChip,
+}
+
+impl FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chip8" => Ok(Variant::Chip8),
+            "chip8e" => Ok(Variant::Chip8E),
+            "schip" => Ok(Variant::SuperChip),
+            _ => Err(format!("expected chip8, chip8e or schip, found {}", s)),
+        }
+    }
+}
+
+/// Decodes a two-byte opcode into the CHIP-8 instruction that it represents.
+pub fn decode(opcode: u16) -> Instruction {
+    decode_variant(opcode, Variant::Chip8)
+}
+
+/// Decodes a two-byte opcode into the instruction that it represents on the variant.
+pub fn decode_variant(opcode: u16, variant: Variant) -> Instruction {
+    // Splits the opcode into 6 different parts. 0xcxyd, 0x_nnn, and 0x__kk.
+    let c = ((opcode & 0xF000) >> 12) as u8;
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let d = (opcode & 0x000F) as u8;
+
+    let nnn = opcode & 0x0FFF;
+    let kk = (opcode & 0x00FF) as u8;
+
+    if variant == Variant::Chip8E {
+        match (c, x, y, d) {
+            (0, 0x1, 0x8, 0x8) => return Instruction::Skip,
+            (0x5, _, _, 0x1) => return Instruction::SgtVxVy(x, y),
+            (0xB, 0xB, _, _) => return Instruction::JpBack(kk),
+            (0xB, 0xF, _, _) => return Instruction::JpForward(kk),
+            _ => (),
+        }
+    }
+
+    match (c, x, y, d) {
+        (0, 0, 0, 0) => Instruction::Empty,
+        (0, 0, 0xE, 0) => Instruction::Cls,
+        (0, 0, 0xE, 0xE) => Instruction::Ret,
+        (0, 0, 0xF, 0xD) => Instruction::Exit,
+        (0, 0, 0xD, _) => Instruction::ScrollUp(d),
+        (0, _, _, _) => Instruction::Sys(nnn),
+        (0x1, _, _, _) => Instruction::Jp(nnn),
+        (0x2, _, _, _) => Instruction::Call(nnn),
+        (0x3, _, _, _) => Instruction::SeVxByte(x, kk),
+        (0x4, _, _, _) => Instruction::SneVxByte(x, kk),
+        (0x5, _, _, 0) => Instruction::SeVxVy(x, y),
+        (0x6, _, _, _) => Instruction::LdVxByte(x, kk),
+        (0x7, _, _, _) => Instruction::AddVxByte(x, kk),
+        (0x8, _, _, 0) => Instruction::LdVxVy(x, y),
+        (0x8, _, _, 0x1) => Instruction::Or(x, y),
+        (0x8, _, _, 0x2) => Instruction::And(x, y),
+        (0x8, _, _, 0x3) => Instruction::Xor(x, y),
+        (0x8, _, _, 0x4) => Instruction::AddVxVy(x, y),
+        (0x8, _, _, 0x5) => Instruction::Sub(x, y),
+        (0x8, _, _, 0x6) => Instruction::Shr(x, y),
+        (0x8, _, _, 0x7) => Instruction::Subn(x, y),
+        (0x8, _, _, 0xE) => Instruction::Shl(x, y),
+        (0x9, _, _, 0) => Instruction::SneVxVy(x, y),
+        (0xA, _, _, _) => Instruction::LdI(nnn),
+        (0xB, _, _, _) => Instruction::JpV0(nnn),
+        (0xC, _, _, _) => Instruction::Rnd(x, kk),
+        (0xD, _, _, _) => Instruction::Drw(x, y, d),
+        (0xE, _, 0x9, 0xE) => Instruction::Skp(x),
+        (0xE, _, 0xA, 0x1) => Instruction::Sknp(x),
+        (0xF, 0, 0, 0) => Instruction::LdILong,
+        (0xF, 0, 0, 0x2) => Instruction::Audio,
+        (0xF, _, 0, 0x7) => Instruction::LdVxDt(x),
+        (0xF, _, 0, 0xA) => Instruction::LdVxK(x),
+        (0xF, _, 0x1, 0x5) => Instruction::LdDtVx(x),
+        (0xF, _, 0x1, 0x8) => Instruction::LdStVx(x),
+        (0xF, _, 0x1, 0xE) => Instruction::AddIVx(x),
+        (0xF, _, 0x2, 0x9) => Instruction::LdFVx(x),
+        (0xF, _, 0x3, 0x3) => Instruction::LdBVx(x),
+        (0xF, _, 0x3, 0xA) => Instruction::Pitch(x),
+        (0xF, _, 0x4, 0xD) => Instruction::LdMouse(x),
+        (0xF, _, 0x5, 0x5) => Instruction::LdIVx(x),
+        (0xF, _, 0x6, 0x5) => Instruction::LdVxI(x),
+        (0xF, _, 0x7, 0x5) => Instruction::LdRVx(x),
+        (0xF, _, 0x8, 0x5) => Instruction::LdVxR(x),
+        _ => Instruction::Unknown(opcode),
+    }
+}
+
+/// Every mnemonic that Instruction::mnemonic can return.
+pub const MNEMONICS: [&str; 27] = [
+    "DW", "CLS", "RET", "EXIT", "SCU", "SYS", "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB",
+    "SHR", "SUBN", "SHL", "RND", "DRW", "SKP", "SKNP", "AUDIO", "PITCH", "SKIP", "SGT",
+];
+
+impl Instruction {
+    /// The opcode that decodes to the instruction, on the variant that has it.
+    pub fn encode(&self) -> u16 {
+        let xy = |c: u16, x: u8, y: u8, d: u16| c << 12 | (x as u16) << 8 | (y as u16) << 4 | d;
+        let xkk = |c: u16, x: u8, kk: u8| c << 12 | (x as u16) << 8 | kk as u16;
+        let fx = |x: u8, kk: u16| 0xF000 | (x as u16) << 8 | kk;
+        match *self {
+            Instruction::Empty => 0x0000,
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::Exit => 0x00FD,
+            Instruction::ScrollUp(n) => 0x00D0 | n as u16,
+            Instruction::Sys(nnn) => nnn,
+            Instruction::Jp(nnn) => 0x1000 | nnn,
+            Instruction::Call(nnn) => 0x2000 | nnn,
+            Instruction::SeVxByte(x, kk) => xkk(0x3, x, kk),
+            Instruction::SneVxByte(x, kk) => xkk(0x4, x, kk),
+            Instruction::SeVxVy(x, y) => xy(0x5, x, y, 0x0),
+            Instruction::LdVxByte(x, kk) => xkk(0x6, x, kk),
+            Instruction::AddVxByte(x, kk) => xkk(0x7, x, kk),
+            Instruction::LdVxVy(x, y) => xy(0x8, x, y, 0x0),
+            Instruction::Or(x, y) => xy(0x8, x, y, 0x1),
+            Instruction::And(x, y) => xy(0x8, x, y, 0x2),
+            Instruction::Xor(x, y) => xy(0x8, x, y, 0x3),
+            Instruction::AddVxVy(x, y) => xy(0x8, x, y, 0x4),
+            Instruction::Sub(x, y) => xy(0x8, x, y, 0x5),
+            Instruction::Shr(x, y) => xy(0x8, x, y, 0x6),
+            Instruction::Subn(x, y) => xy(0x8, x, y, 0x7),
+            Instruction::Shl(x, y) => xy(0x8, x, y, 0xE),
+            Instruction::SneVxVy(x, y) => xy(0x9, x, y, 0x0),
+            Instruction::LdI(nnn) => 0xA000 | nnn,
+            Instruction::JpV0(nnn) => 0xB000 | nnn,
+            Instruction::Rnd(x, kk) => xkk(0xC, x, kk),
+            Instruction::Drw(x, y, n) => xy(0xD, x, y, n as u16),
+            Instruction::Skp(x) => xkk(0xE, x, 0x9E),
+            Instruction::Sknp(x) => xkk(0xE, x, 0xA1),
+            Instruction::LdILong => 0xF000,
+            Instruction::Audio => 0xF002,
+            Instruction::LdVxDt(x) => fx(x, 0x07),
+            Instruction::LdVxK(x) => fx(x, 0x0A),
+            Instruction::LdDtVx(x) => fx(x, 0x15),
+            Instruction::LdStVx(x) => fx(x, 0x18),
+            Instruction::AddIVx(x) => fx(x, 0x1E),
+            Instruction::LdFVx(x) => fx(x, 0x29),
+            Instruction::LdBVx(x) => fx(x, 0x33),
+            Instruction::Pitch(x) => fx(x, 0x3A),
+            Instruction::LdMouse(x) => fx(x, 0x4D),
+            Instruction::LdIVx(x) => fx(x, 0x55),
+            Instruction::LdVxI(x) => fx(x, 0x65),
+            Instruction::LdRVx(x) => fx(x, 0x75),
+            Instruction::LdVxR(x) => fx(x, 0x85),
+            Instruction::Skip => 0x0188,
+            Instruction::SgtVxVy(x, y) => xy(0x5, x, y, 0x1),
+            Instruction::JpBack(nn) => 0xBB00 | nn as u16,
+            Instruction::JpForward(nn) => 0xBF00 | nn as u16,
+            Instruction::Unknown(opcode) => opcode,
+        }
+    }
+
+    /// The mnemonic that the instruction is written with, e.g. `LD` for every kind of load.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Empty | Instruction::Unknown(_) => "DW",
+            Instruction::Cls => "CLS",
+            Instruction::Ret => "RET",
+            Instruction::Exit => "EXIT",
+            Instruction::ScrollUp(_) => "SCU",
+            Instruction::Sys(_) => "SYS",
+            Instruction::Jp(_) | Instruction::JpV0(_) | Instruction::JpBack(_) | Instruction::JpForward(_) => "JP",
+            Instruction::Call(_) => "CALL",
+            Instruction::SeVxByte(..) | Instruction::SeVxVy(..) => "SE",
+            Instruction::SneVxByte(..) | Instruction::SneVxVy(..) => "SNE",
+            Instruction::LdVxByte(..)
+            | Instruction::LdVxVy(..)
+            | Instruction::LdI(_)
+            | Instruction::LdILong
+            | Instruction::LdVxDt(_)
+            | Instruction::LdVxK(_)
+            | Instruction::LdDtVx(_)
+            | Instruction::LdStVx(_)
+            | Instruction::LdFVx(_)
+            | Instruction::LdBVx(_)
+            | Instruction::LdIVx(_)
+            | Instruction::LdVxI(_)
+            | Instruction::LdRVx(_)
+            | Instruction::LdVxR(_)
+            | Instruction::LdMouse(_) => "LD",
+            Instruction::AddVxByte(..) | Instruction::AddVxVy(..) | Instruction::AddIVx(_) => "ADD",
+            Instruction::Or(..) => "OR",
+            Instruction::And(..) => "AND",
+            Instruction::Xor(..) => "XOR",
+            Instruction::Sub(..) => "SUB",
+            Instruction::Shr(..) => "SHR",
+            Instruction::Subn(..) => "SUBN",
+            Instruction::Shl(..) => "SHL",
+            Instruction::Rnd(..) => "RND",
+            Instruction::Drw(..) => "DRW",
+            Instruction::Skp(_) => "SKP",
+            Instruction::Sknp(_) => "SKNP",
+            Instruction::Audio => "AUDIO",
+            Instruction::Pitch(_) => "PITCH",
+            Instruction::Skip => "SKIP",
+            Instruction::SgtVxVy(..) => "SGT",
+        }
+    }
+}
+
+/// Formats the instruction as assembly, e.g. `LD V1, 0x0A` or `DRW V0, V1, 5`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Empty => write!(f, "DW 0x0000"),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::ScrollUp(n) => write!(f, "SCU {}", n),
+            Instruction::Sys(nnn) => write!(f, "SYS 0x{:03X}", nnn),
+            Instruction::Jp(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            Instruction::SeVxByte(x, kk) => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SneVxByte(x, kk) => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SeVxVy(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LdVxByte(x, kk) => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            Instruction::AddVxByte(x, kk) => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            Instruction::LdVxVy(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddVxVy(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneVxVy(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            Instruction::JpV0(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Instruction::Rnd(x, kk) => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Instruction::Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdILong => write!(f, "LD I, LONG"),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIVx(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFVx(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdBVx(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::LdMouse(x) => write!(f, "LD V{:X}, MOUSE", x),
+            Instruction::Skip => write!(f, "SKIP"),
+            Instruction::SgtVxVy(x, y) => write!(f, "SGT V{:X}, V{:X}", x, y),
+            Instruction::JpBack(nn) => write!(f, "JP -0x{:02X}", nn),
+            Instruction::JpForward(nn) => write!(f, "JP +0x{:02X}", nn),
+            Instruction::Unknown(opcode) => write!(f, "DW 0x{:04X}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_opcode_decodes_to_an_instruction_that_encodes_back_to_it() {
+        for variant in [Variant::Chip8, Variant::Chip8E, Variant::SuperChip] {
+            for opcode in 0..=u16::MAX {
+                let instruction = decode_variant(opcode, variant);
+                assert_eq!(instruction.encode(), opcode, "{:04X} decoded to {:?} on {:?}", opcode, instruction, variant);
+            }
+        }
+    }
+
+    #[test]
+    fn chip8e_opcodes_are_only_decoded_on_chip8e() {
+        for opcode in [0x0188, 0x5121, 0xBB04, 0xBF04] {
+            assert_eq!(decode_variant(opcode, Variant::Chip8E).encode(), opcode);
+            assert_ne!(decode_variant(opcode, Variant::Chip8), decode_variant(opcode, Variant::Chip8E));
+        }
+    }
+
+    #[test]
+    fn opcodes_decode_to_their_instructions() {
+        let cases = [
+            (0x0000, Instruction::Empty),
+            (0x00E0, Instruction::Cls),
+            (0x00EE, Instruction::Ret),
+            (0x00FD, Instruction::Exit),
+            (0x00D3, Instruction::ScrollUp(3)),
+            (0x0123, Instruction::Sys(0x123)),
+            (0x1ABC, Instruction::Jp(0xABC)),
+            (0x2ABC, Instruction::Call(0xABC)),
+            (0x3A12, Instruction::SeVxByte(0xA, 0x12)),
+            (0x4A12, Instruction::SneVxByte(0xA, 0x12)),
+            (0x5AB0, Instruction::SeVxVy(0xA, 0xB)),
+            (0x6A12, Instruction::LdVxByte(0xA, 0x12)),
+            (0x7A12, Instruction::AddVxByte(0xA, 0x12)),
+            (0x8AB0, Instruction::LdVxVy(0xA, 0xB)),
+            (0x8AB1, Instruction::Or(0xA, 0xB)),
+            (0x8AB2, Instruction::And(0xA, 0xB)),
+            (0x8AB3, Instruction::Xor(0xA, 0xB)),
+            (0x8AB4, Instruction::AddVxVy(0xA, 0xB)),
+            (0x8AB5, Instruction::Sub(0xA, 0xB)),
+            (0x8AB6, Instruction::Shr(0xA, 0xB)),
+            (0x8AB7, Instruction::Subn(0xA, 0xB)),
+            (0x8ABE, Instruction::Shl(0xA, 0xB)),
+            (0x9AB0, Instruction::SneVxVy(0xA, 0xB)),
+            (0xAABC, Instruction::LdI(0xABC)),
+            (0xBABC, Instruction::JpV0(0xABC)),
+            (0xCA12, Instruction::Rnd(0xA, 0x12)),
+            (0xDAB5, Instruction::Drw(0xA, 0xB, 5)),
+            (0xEA9E, Instruction::Skp(0xA)),
+            (0xEAA1, Instruction::Sknp(0xA)),
+            (0xF000, Instruction::LdILong),
+            (0xF002, Instruction::Audio),
+            (0xFA07, Instruction::LdVxDt(0xA)),
+            (0xFA0A, Instruction::LdVxK(0xA)),
+            (0xFA15, Instruction::LdDtVx(0xA)),
+            (0xFA18, Instruction::LdStVx(0xA)),
+            (0xFA1E, Instruction::AddIVx(0xA)),
+            (0xFA29, Instruction::LdFVx(0xA)),
+            (0xFA33, Instruction::LdBVx(0xA)),
+            (0xFA3A, Instruction::Pitch(0xA)),
+            (0xFA4D, Instruction::LdMouse(0xA)),
+            (0xFA55, Instruction::LdIVx(0xA)),
+            (0xFA65, Instruction::LdVxI(0xA)),
+            (0xFA75, Instruction::LdRVx(0xA)),
+            (0xFA85, Instruction::LdVxR(0xA)),
+            (0x0188, Instruction::Sys(0x188)),
+            (0x5AB1, Instruction::Unknown(0x5AB1)),
+            (0x8AB8, Instruction::Unknown(0x8AB8)),
+            (0x9AB1, Instruction::Unknown(0x9AB1)),
+            (0xEA00, Instruction::Unknown(0xEA00)),
+            (0xFAFF, Instruction::Unknown(0xFAFF)),
+        ];
+        for (opcode, instruction) in cases {
+            assert_eq!(decode(opcode), instruction, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn chip8e_opcodes_decode_to_their_instructions() {
+        let cases = [
+            (0x0188, Instruction::Skip),
+            (0x5AB1, Instruction::SgtVxVy(0xA, 0xB)),
+            (0xBB12, Instruction::JpBack(0x12)),
+            (0xBF12, Instruction::JpForward(0x12)),
+            (0xBA12, Instruction::JpV0(0xA12)),
+        ];
+        for (opcode, instruction) in cases {
+            assert_eq!(decode_variant(opcode, Variant::Chip8E), instruction, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn instructions_are_shown_as_assembly() {
+        assert_eq!(decode(0x6A12).to_string(), "LD VA, 0x12");
+        assert_eq!(decode(0xD015).to_string(), "DRW V0, V1, 5");
+        assert_eq!(decode(0xFA55).to_string(), "LD [I], VA");
+        assert_eq!(decode(0xFFFF).to_string(), "DW 0xFFFF");
+    }
+}
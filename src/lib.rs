@@ -0,0 +1,29 @@
+//! A CHIP-8 emulator. The [`Chip8`] machine can be displayed in a window or run headlessly.
+
+pub mod analysis;
+pub mod ascii;
+pub mod audio;
+pub mod backend;
+pub mod chip8;
+pub mod clock;
+pub mod cpu;
+pub mod debugger;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod font;
+pub mod instruction;
+pub mod quirks;
+pub mod settings;
+#[cfg(feature = "sound")]
+pub mod sdl;
+pub mod state;
+#[cfg(feature = "terminal")]
+pub mod terminal;
+pub mod trace;
+pub mod window;
+
+pub use backend::{Backend, Headless};
+pub use chip8::Chip8;
+pub use cpu::{CPU, RunOutcome};
+pub use error::Error;
@@ -1,78 +1,1134 @@
 // std::fs used to read the program file.
-use std::{fs, path::PathBuf, sync::Mutex, sync::Arc};
+use std::{fs, io::{self, Read, Write}, ops::RangeInclusive, panic, path::{Path, PathBuf}, process, str::FromStr, sync::atomic::Ordering, time::{Duration, Instant}};
 // clap library used to parse command line arguments.
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, parser::ValueSource};
+// directories library used to find the platform's data directory.
+use directories::ProjectDirs;
+// flate2 library used to decompress gzipped programs.
+use flate2::read::GzDecoder;
 
-mod cpu;
+use cpu_emulator::{analysis::{self, Warning}, ascii, audio::{Waveform, DEFAULT_FREQUENCY}, Backend, Chip8, CPU, Headless, RunOutcome, chip8::{DEFAULT_FPS, DEFAULT_HZ, TIMER_HZ}, cpu::{LowMemoryProtection, LOAD_ADDRESS, STACK_DEPTH}, debugger::Debugger, state::State, trace::TraceWriter, instruction::Variant, quirks::{Edge, ShiftSource}, settings::{self, Settings}, window::{Aspect, WindowBackend}};
+
+/// Where the program is loaded in memory.
+#[derive(Clone, Copy)]
+enum LoadAddress {
+    /// Guessed from the contents of the program.
+    Auto,
+    Fixed(usize),
+}
+
+impl FromStr for LoadAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(LoadAddress::Auto);
+        }
+
+        match parse_number(s) {
+            Some(address) if address < 0x1000 => Ok(LoadAddress::Fixed(address)),
+            _ => Err(format!("expected auto or an address below 0x1000, found {}", s)),
+        }
+    }
+}
+
+/// A byte to write into memory after the program is loaded.
+#[derive(Clone, Copy)]
+struct Poke {
+    address: usize,
+    value: u8,
+}
+
+impl FromStr for Poke {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, value) = s.split_once('=').ok_or_else(|| format!("expected ADDR=BYTE, found {}", s))?;
+        let address = match parse_number(address) {
+            Some(address) if address < 0x1000 => address,
+            _ => return Err(format!("expected an address below 0x1000, found {}", address)),
+        };
+        let value = match parse_number(value) {
+            Some(value) if value <= 0xFF => value as u8,
+            _ => return Err(format!("expected a byte, found {}", value)),
+        };
+        Ok(Poke { address, value })
+    }
+}
+
+/// A range of addresses that --disassemble writes as bytes of data, e.g. 0x300-0x30F.
+#[derive(Clone)]
+struct DataRegion(RangeInclusive<usize>);
+
+impl FromStr for DataRegion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or_else(|| format!("expected START-END, found {}", s))?;
+        let (start, end) = (parse_address(start)?, parse_address(end)?);
+        if start > end {
+            return Err(format!("expected the start to come before the end, found {}", s));
+        }
+        Ok(DataRegion(start..=end))
+    }
+}
+
+/// Parses a number written in hex with a 0x prefix, or in decimal.
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parses an address below 0x1000.
+fn parse_address(s: &str) -> Result<usize, String> {
+    match parse_number(s) {
+        Some(address) if address < 0x1000 => Ok(address),
+        _ => Err(format!("expected an address below 0x1000, found {}", s)),
+    }
+}
+
+/// Parses a number from 0 to 1.
+fn parse_intensity(s: &str) -> Result<f32, String> {
+    match s.parse() {
+        Ok(intensity) if (0.0..=1.0).contains(&intensity) => Ok(intensity),
+        _ => Err(format!("expected a number from 0 to 1, found {}", s)),
+    }
+}
+
+/// Parses a frequency in Hz that is above 0.
+fn parse_frequency(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(frequency) if frequency > 0.0 && frequency.is_finite() => Ok(frequency),
+        _ => Err(format!("expected a frequency above 0Hz, found {}", s)),
+    }
+}
 
 /// Allows for programs to be selected from the command line.
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    #[arg(short, long, value_name = "FILE")]
-    program: PathBuf,
+    #[arg(short, long, value_name = "FILE", required_unless_present_any = ["dump_font", "batch"])]
+    program: Option<PathBuf>,
+    /// Fills memory above the program and the registers with random bytes before execution.
+    /// A random seed is chosen and printed if one is not given.
+    #[arg(long, value_name = "SEED")]
+    fuzz_memory: Option<Option<u64>>,
+    /// Starts with random pixels on the display instead of a blank one, to check that the program
+    /// clears the screen. Uses the --fuzz-memory seed if one is given.
+    #[arg(long)]
+    dirty_screen: bool,
+    /// The number of window pixels used for each CHIP-8 pixel, from 1 to 32.
+    #[arg(long, value_name = "N", default_value_t = 16, value_parser = clap::value_parser!(u8).range(1..=32))]
+    scale: u8,
+    /// How the display is fitted into the window: stretch, integer or square.
+    #[arg(long, value_name = "MODE", default_value = "stretch")]
+    aspect: Aspect,
+    /// Draws faint lines between the pixels. Can be toggled while running with G.
+    #[arg(long)]
+    grid: bool,
+    /// Draws the display like an old CRT, with dark scanlines and lit pixels glowing onto their
+    /// neighbours. The strength is from 0 to 1, and 0.5 if not given.
+    #[arg(long, value_name = "INTENSITY", num_args = 0..=1, default_missing_value = "0.5", value_parser = parse_intensity)]
+    crt: Option<f32>,
+    /// The number of instructions executed each second.
+    #[arg(long, value_name = "HZ", default_value_t = DEFAULT_HZ, value_parser = clap::value_parser!(u32).range(1..))]
+    hz: u32,
+    /// The number of times the display is refreshed each second. Timers run at --timer-hz.
+    #[arg(long, value_name = "FPS", default_value_t = DEFAULT_FPS, value_parser = clap::value_parser!(u32).range(1..))]
+    fps: u32,
+    /// The number of times the delay and sound timers count down each second.
+    #[arg(long, value_name = "HZ", default_value_t = TIMER_HZ, value_parser = clap::value_parser!(u32).range(1..))]
+    timer_hz: u32,
+    /// Only displays every N+1th frame, for slow terminals and remote displays.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    frameskip: u32,
+    /// Runs every .ch8 program in the directory headlessly for up to --max-cycles instructions and
+    /// prints which ones stopped with an error, then exits.
+    #[arg(long, value_name = "DIR")]
+    batch: Option<PathBuf>,
+    /// Prints the sprites of the built-in small and large fonts, then exits.
+    #[arg(long)]
+    dump_font: bool,
+    /// Prints --count sprites of --height bytes each from memory at the --at address, after the
+    /// program is loaded, as rows of '#' and '.', then exits.
+    #[arg(long, requires = "at")]
+    extract_sprites: bool,
+    /// The address of the first sprite printed by --extract-sprites.
+    #[arg(long, value_name = "ADDR", value_parser = parse_address, requires = "extract_sprites")]
+    at: Option<usize>,
+    /// The number of sprites printed by --extract-sprites.
+    #[arg(long, value_name = "N", default_value_t = 1, requires = "extract_sprites")]
+    count: usize,
+    /// The height in bytes of each sprite printed by --extract-sprites.
+    #[arg(long, value_name = "N", default_value_t = 5, value_parser = clap::value_parser!(u8).range(1..=15), requires = "extract_sprites")]
+    height: u8,
+    /// Writes a listing of every instruction in the program to the file, or to stdout if it is -,
+    /// with the address, the bytes and the instruction on each line, then exits. Unlike --analyze,
+    /// every two bytes are decoded unless they are in one of the --data-regions.
+    #[arg(long, value_name = "FILE")]
+    disassemble: Option<PathBuf>,
+    /// Ranges of addresses, e.g. 0x300-0x30F, that --disassemble writes as DB bytes instead of
+    /// decoding. Both ends are included.
+    #[arg(long, value_name = "START-END", value_delimiter = ',', requires = "disassemble")]
+    data_regions: Vec<DataRegion>,
+    /// Prints which parts of the program are reachable code and which are data, then exits.
+    #[arg(long)]
+    analyze: bool,
+    /// Checks the program for unknown opcodes, addresses outside the program or memory and calls
+    /// and returns that do not match up, without running it. Exits with an error if any are found.
+    #[arg(long)]
+    validate: bool,
+    /// Keeps the SUPER-CHIP RPL user flags in a .rpl file in the data directory between runs.
+    #[arg(long)]
+    persist_rpl: bool,
+    /// The directory that .rpl files are kept in. Defaults to the platform's data directory, e.g.
+    /// ~/.local/share/chip8-emulator on Linux, or the program's directory if there is not one.
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+    /// Waits for the window to be focused and a key to be pressed before starting the program.
+    #[arg(long)]
+    wait_for_focus: bool,
+    /// Prints the platform and quirks that the program most likely expects.
+    #[arg(long)]
+    suggest_quirks: bool,
+    /// Warns about, or stops on, FX33 and FX55 writes below the load address, where the font lives.
+    #[arg(long, value_name = "off|warn|error", default_value = "off")]
+    protect_lowmem: LowMemoryProtection,
+    /// Writes the full machine state as JSON to the file when the program stops.
+    #[arg(long, value_name = "FILE")]
+    dump_json: Option<PathBuf>,
+    /// Writes all 4096 bytes of memory to the file when the program stops or the user quits, to
+    /// see what the program wrote. This also works with --benchmark-rom, and with --compare, which
+    /// writes the memory of the first run. With --verbose, the hash of the memory is printed.
+    #[arg(long, value_name = "FILE")]
+    dump_memory_on_exit: Option<PathBuf>,
+    /// The address to load the program at, e.g. 0x600 for ETI-660 programs. With auto, it is
+    /// guessed from the program. --analyze, --validate and --suggest-quirks also use it.
+    #[arg(long, value_name = "ADDR|auto", default_value = "0x200")]
+    load_addr: LoadAddress,
+    /// The instruction set to run the program with: chip8, chip8e for CHIP-8E's 0188, 5XY1, BBNN
+    /// and BFNN, or schip for SUPER-CHIP's 16x16 sprites drawn by DXY0.
+    #[arg(long, value_name = "VARIANT", default_value = "chip8")]
+    variant: Variant,
+    /// Lets the program read the position of the mouse on the display with the non-standard Fx4D,
+    /// which sets Vx to the column and the next register to the row.
+    #[arg(long)]
+    mouse: bool,
+    /// Ends each frame early while the program polls the keypad in a tight EX9E or EXA1 loop
+    /// instead of waiting with FX0A, to use less power. Only the pacing changes: the keys are read
+    /// once a frame either way.
+    #[arg(long)]
+    idle_polling: bool,
+    /// Shows the display and stops for this many milliseconds after every DXYN, to watch the
+    /// sprites being drawn one at a time. Instructions other than DXYN run at the usual speed.
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    draw_delay: u64,
+    /// Ticks whenever a sprite collides with pixels that are on, as a cue that can be heard. The
+    /// tick is played over the beep, or by ringing the terminal bell when there is no sound.
+    #[arg(long)]
+    click_on_draw: bool,
+    /// The shape of the beep played while the sound timer runs, unless the program has loaded its
+    /// own audio pattern with F002.
+    #[arg(long, value_name = "square|sine|triangle|noise", default_value = "square")]
+    tone: Waveform,
+    /// The pitch of the beep in Hz. Noise has no pitch, so it ignores this.
+    #[arg(long, value_name = "HZ", default_value_t = DEFAULT_FREQUENCY, value_parser = parse_frequency)]
+    tone_freq: f64,
+    /// Ignores a key going up or down again less than this many milliseconds after it last did,
+    /// for keyboards whose keys chatter and register one press as several.
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    input_delay: u64,
+    /// Keeps the last --rewind-frames frames so that holding Backspace goes back in time.
+    #[arg(long)]
+    rewindable: bool,
+    /// The number of frames kept by --rewindable.
+    #[arg(long, value_name = "N", default_value_t = 600, requires = "rewindable")]
+    rewind_frames: usize,
+    /// What DXYN does with the parts of a sprite past the right or bottom edge: clip leaves them
+    /// out, wrap draws them on the opposite side.
+    #[arg(long, value_name = "clip|wrap", default_value = "clip")]
+    edge: Edge,
+    /// Approximates the timing of the COSMAC VIP: 60 frames a second, each instruction taking as
+    /// long as it did on the VIP, and DXYN waiting for the next frame.
+    #[arg(long, conflicts_with_all = ["hz", "fps"])]
+    authentic: bool,
+    /// The register shifted by 8XY6 and 8XYE: vx shifts Vx in place, vy shifts Vy into Vx.
+    #[arg(long, value_name = "vx|vy", default_value = "vx")]
+    shift_source: ShiftSource,
+    /// Makes DXYN wait for the next frame before the program carries on, as the COSMAC VIP did, so
+    /// at most one sprite is drawn each frame. --authentic turns this on too.
+    #[arg(long)]
+    display_wait: bool,
+    /// Steps through the program from a prompt on stdin instead of running it. Type step [n],
+    /// nextdraw, runframe, runto <addr>, break-op <mnemonic>, watch mem <addr>, watch reg <Vx>,
+    /// show or quit.
+    #[arg(long)]
+    debug: bool,
+    /// Runs the program headlessly twice in lockstep, once with the quirks given and once with
+    /// these changed, e.g. shift=vy,edge=wrap, and prints where the two first differ.
+    #[arg(long, value_name = "QUIRK=VALUE", value_delimiter = ',')]
+    compare: Option<Vec<String>>,
+    /// Runs the program headlessly as fast as possible until it stops, halts on a jump to itself
+    /// or reaches --max-cycles, then prints how long it took.
+    #[arg(long)]
+    benchmark_rom: bool,
+    /// The number of instructions after which --benchmark-rom and --batch give up.
+    #[arg(long, value_name = "N", default_value_t = 10_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    max_cycles: u64,
+    /// Writes a byte into memory after the program is loaded, e.g. --poke 0x2A0=0x12. Can be given
+    /// more than once.
+    #[arg(long, value_name = "ADDR=BYTE")]
+    poke: Vec<Poke>,
+    /// Prints the position and height of every sprite that collides with pixels that are on, to
+    /// stdout, or to stderr with --terminal.
+    #[arg(long)]
+    log_collisions: bool,
+    /// Prints the number of DXYN executed, the pixels they flipped, the collisions and the average
+    /// sprite height on exit, and suggests --display-wait if sprites are drawn over each other so
+    /// many times a frame that the program probably flickers.
+    #[arg(long)]
+    profile_draw: bool,
+    /// Warns when a byte that has already been executed as an instruction is overwritten.
+    #[arg(long)]
+    warn_self_modifying: bool,
+    /// Warns when data stored in VF is overwritten by a flag, e.g. the carry of an 8XY4, and then
+    /// read as if it were still the data.
+    #[arg(long)]
+    warn_vf_clobber: bool,
+    /// Writes a 5-byte record for every instruction executed to the file: the address and the
+    /// opcode, both big-endian, and a flags byte. See the trace module for the layout.
+    #[arg(long, value_name = "FILE")]
+    trace_binary: Option<PathBuf>,
+    /// The number of nested subroutine calls that fit on the stack before 2NNN overflows it. Most
+    /// interpreters have room for 16, but some programs recurse deeper.
+    #[arg(long, value_name = "N", default_value_t = STACK_DEPTH as u16, value_parser = clap::value_parser!(u16).range(1..))]
+    stack_depth: u16,
+    /// Stops with an error on unknown opcodes, writes below 0x200 and writes past the end of
+    /// memory, for catching bugs while writing a program. Stack overflows and underflows are
+    /// always errors.
+    #[arg(long)]
+    strict: bool,
+    /// Saves --hz, --fps, --authentic, --variant, --shift-source, --edge and --display-wait for this
+    /// program, so that they are used the next time it is run without having to give them. Flags
+    /// that are given still override the saved settings.
+    #[arg(long)]
+    remember: bool,
+    /// Prints a summary of the loaded program and settings on startup.
+    #[arg(short, long)]
+    verbose: bool,
+    /// Draws the display in the terminal instead of a window.
+    #[cfg(feature = "terminal")]
+    #[arg(long, conflicts_with = "debug")]
+    terminal: bool,
+}
+
+/// Works out where a file belonging to the program is kept, e.g. its RPL flags. Files are named
+/// after the program and kept in the data directory if one is given, otherwise in the platform's
+/// data directory, and if there is not one of those, next to the program.
+fn data_path(data_dir: Option<&Path>, program: &Path, extension: &str) -> PathBuf {
+    // Strips both extensions from a gzipped program, so that it shares files with the original.
+    let name = program.file_name().unwrap_or_default().to_string_lossy();
+    let stem = name.trim_end_matches(".gz").trim_end_matches(".ch8");
+    data_file(data_dir, program, format!("{}.{}", stem, extension))
+}
+
+/// Works out where a file with the given name is kept, in the same place as data_path.
+fn data_file(data_dir: Option<&Path>, program: &Path, file_name: String) -> PathBuf {
+    match data_dir {
+        Some(dir) => dir.join(file_name),
+        None => match ProjectDirs::from("", "", "chip8-emulator") {
+            Some(dirs) => dirs.data_dir().join(file_name),
+            None => program.with_file_name(file_name),
+        },
+    }
+}
+
+/// Works out where the settings for the program are kept. They are named after its hash, so that
+/// they are found however the program's file is named.
+fn settings_file(data_dir: Option<&Path>, path: &Path, program: &[u8]) -> PathBuf {
+    data_file(data_dir, path, format!("{:016x}.settings.json", settings::hash(program)))
+}
+
+/// Uses the saved settings for each flag that was not given on the command line.
+fn apply_settings(cli: &mut Cli, matches: &ArgMatches, saved: Settings) {
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if let (false, Some(hz)) = (given("hz"), saved.hz) {
+        cli.hz = hz;
+    }
+    if let (false, Some(fps)) = (given("fps"), saved.fps) {
+        cli.fps = fps;
+    }
+    if let (false, Some(variant)) = (given("variant"), saved.variant) {
+        cli.variant = variant;
+    }
+    if let (false, Some(shift)) = (given("shift_source"), saved.shift) {
+        cli.shift_source = shift;
+    }
+    if let (false, Some(edge)) = (given("edge"), saved.edge) {
+        cli.edge = edge;
+    }
+    if let (false, Some(display_wait)) = (given("display_wait"), saved.display_wait) {
+        cli.display_wait = display_wait;
+    }
+    // --authentic cannot be used with --hz or --fps, so it is only brought back without them.
+    if let (false, false, false, Some(authentic)) = (given("authentic"), given("hz"), given("fps"), saved.authentic) {
+        cli.authentic = authentic;
+    }
+}
+
+/// The settings that --remember saves for the program, as they are used. --authentic replaces the
+/// speed and turns the display wait on.
+fn remembered_settings(cli: &Cli) -> Settings {
+    Settings {
+        hz: (!cli.authentic).then_some(cli.hz),
+        fps: (!cli.authentic).then_some(cli.fps),
+        variant: Some(cli.variant),
+        shift: Some(cli.shift_source),
+        edge: Some(cli.edge),
+        display_wait: Some(cli.display_wait || cli.authentic),
+        authentic: Some(cli.authentic),
+    }
+}
+
+/// Reads a program, decompressing it first if it is gzipped. Files are recognised as gzipped by
+/// their magic bytes rather than their extension.
+fn read_program(path: &Path) -> io::Result<Vec<u8>> {
+    let bytes = fs::read(path)?;
+    if !bytes.starts_with(&[0x1F, 0x8B]) {
+        return Ok(bytes);
+    }
+
+    let mut program = Vec::new();
+    GzDecoder::new(&bytes[..]).read_to_end(&mut program)?;
+    Ok(program)
+}
+
+/// Runs the cpu without a window or any pacing until it stops, halts or has executed max_cycles
+/// instructions. There is no input, and the timers count down once every hz / 60 instructions, as
+/// they would when running at hz. Returns why it stopped and the machine, which has counted the
+/// instructions executed.
+fn run_headless(cpu: CPU, hz: u32, max_cycles: u64) -> (Result<String, cpu_emulator::Error>, Chip8<Headless>) {
+    let mut chip8 = Chip8::from_cpu(cpu, Headless);
+    chip8.fps = hz;
+
+    let result = loop {
+        if chip8.cycles() >= max_cycles {
+            break Ok("reached the maximum number of cycles".to_string());
+        }
+        if chip8.cpu.waiting_for_key.is_some() {
+            break Ok("waited for a key".to_string());
+        }
+        if chip8.cpu.is_halted() {
+            break Ok("halted".to_string());
+        }
+        match chip8.run_frame() {
+            Ok(None) => (),
+            Ok(Some(outcome)) => break Ok(format!("stopped: {:?}", outcome)),
+            Err(error) => break Err(error),
+        }
+    };
+    (result, chip8)
+}
+
+/// Runs the program headlessly and prints the instructions per second that were achieved. Returns
+/// the cpu as it was when the program stopped.
+fn benchmark(cpu: CPU, hz: u32, max_cycles: u64) -> CPU {
+    let start = Instant::now();
+    let (result, chip8) = run_headless(cpu, hz, max_cycles);
+    let elapsed = start.elapsed().as_secs_f64();
+    let cycles = chip8.cycles();
+
+    match result {
+        Ok(reason) => println!("Program {} after {} instructions.", reason, cycles),
+        Err(error) => println!("{} after {} instructions.", error, cycles),
+    }
+    println!("Took {:.3}s, {:.0} instructions per second.", elapsed, cycles as f64 / elapsed);
+    chip8.cpu
+}
+
+/// Runs two cpus headlessly, one instruction at a time, until their states differ, they stop or
+/// max_cycles instructions have been executed. The timers count down as in run_headless. Writes
+/// the instruction where they first differed, and both states, to the output. Returns the first cpu
+/// as it was when they stopped.
+fn compare(first: CPU, second: CPU, hz: u32, max_cycles: u64, mut output: impl Write) -> io::Result<CPU> {
+    let mut first = Chip8::from_cpu(first, Headless);
+    let mut second = Chip8::from_cpu(second, Headless);
+    first.fps = hz;
+    second.fps = hz;
+
+    while first.cycles() < max_cycles {
+        if first.cpu.waiting_for_key.is_some() {
+            writeln!(output, "No differences before waiting for a key after {} instructions.", first.cycles())?;
+            return Ok(first.cpu);
+        }
+        if first.cpu.is_halted() {
+            writeln!(output, "No differences before halting after {} instructions.", first.cycles())?;
+            return Ok(first.cpu);
+        }
+
+        let (pc, instruction) = (first.cpu.pc(), first.cpu.next_instruction());
+        let results = (first.run_frame(), second.run_frame());
+        let (first_state, second_state) = (State::from(&first.cpu), State::from(&second.cpu));
+        if results.0 != results.1 || first_state != second_state {
+            writeln!(output, "Differed after {} instructions, at 0x{:03X} {}.", first.cycles(), pc, instruction)?;
+            if results.0 != results.1 {
+                writeln!(output, "First result:  {:?}", results.0)?;
+                writeln!(output, "Second result: {:?}", results.1)?;
+            }
+            writeln!(output, "First:  {}", first.cpu)?;
+            writeln!(output, "Second: {}", second.cpu)?;
+            if let Some(address) = (0..first_state.memory.len()).find(|&i| first_state.memory[i] != second_state.memory[i]) {
+                writeln!(
+                    output,
+                    "Memory first differs at 0x{:03X}: 0x{:02X} and 0x{:02X}.",
+                    address, first_state.memory[address], second_state.memory[address],
+                )?;
+            }
+            if first_state.framebuffer != second_state.framebuffer {
+                writeln!(output, "First display:\n{}", ascii::framebuffer(&first.cpu.framebuffer))?;
+                writeln!(output, "Second display:\n{}", ascii::framebuffer(&second.cpu.framebuffer))?;
+            }
+            return Ok(first.cpu);
+        }
+
+        match results.0 {
+            Ok(None) => (),
+            Ok(Some(outcome)) => {
+                writeln!(output, "No differences before stopping ({:?}) after {} instructions.", outcome, first.cycles())?;
+                return Ok(first.cpu);
+            },
+            Err(error) => {
+                writeln!(output, "No differences before {} after {} instructions.", error, first.cycles())?;
+                return Ok(first.cpu);
+            },
+        }
+    }
+    writeln!(output, "No differences in {} instructions.", first.cycles())?;
+    Ok(first.cpu)
+}
+
+/// Runs every .ch8 and .ch8.gz program in the directory headlessly and writes a table of which
+/// ones ran without an error to the output. A program that panics the emulator is reported rather
+/// than stopping the batch.
+fn batch(dir: &Path, hz: u32, max_cycles: u64, mut output: impl Write) -> io::Result<()> {
+    let mut paths = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.to_string_lossy();
+            name.ends_with(".ch8") || name.ends_with(".ch8.gz")
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let width = paths.iter().map(|path| path.file_name().unwrap().len()).max().unwrap_or(0);
+    for path in paths {
+        let status = match read_program(&path) {
+            Err(error) => format!("ERROR could not be read: {}", error),
+            Ok(program) if LOAD_ADDRESS + program.len() > 0x1000 => "ERROR too big to fit in memory".to_string(),
+            Ok(program) => {
+                let run = panic::catch_unwind(|| run_headless(CPU::new(&program), hz, max_cycles));
+                match run {
+                    Ok((Ok(reason), chip8)) => format!("OK    {} after {} instructions", reason, chip8.cycles()),
+                    Ok((Err(error), chip8)) => format!("ERROR {} after {} instructions", error, chip8.cycles()),
+                    Err(_) => "ERROR the emulator panicked".to_string(),
+                }
+            },
+        };
+        writeln!(output, "{:<width$}  {}", path.file_name().unwrap().to_string_lossy(), status, width = width)?;
+    }
+    Ok(())
 }
 
 #[tokio::main]
-/// Parses the cli arguments, reads the program into bytes, assembles the memory with the font,
-/// program, and correct spacing, initates the cpu loop.
+/// Parses the cli arguments, reads the program into bytes, creates the machine with the program
+/// loaded and initates the cpu loop.
 async fn main() {
     // Read the value of the program flag.
-    let cli = Cli::parse();
-    let program_buf = cli.program;
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+    if cli.dump_font {
+        println!("{}", ascii::font(&CPU::new(&[]).memory));
+        return;
+    }
 
-    // Check that the file provided is a CHIP-8 program.
-    if program_buf.extension().unwrap() != "ch8" {
-        panic!("Please provide a .ch8 file.");
+    if let Some(dir) = &cli.batch {
+        if let Err(error) = batch(dir, cli.hz, cli.max_cycles, io::stdout()) {
+            eprintln!("Could not read {}: {}.", dir.display(), error);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let program_buf = cli.program.take().unwrap();
+
+    // Check that the file provided is a CHIP-8 program, which may be gzipped.
+    let name = program_buf.to_string_lossy();
+    if !name.ends_with(".ch8") && !name.ends_with(".ch8.gz") {
+        panic!("Please provide a .ch8 or .ch8.gz file.");
     }
 
     // Reads the file into a vector of bytes.
-    let program = fs::read(program_buf).unwrap();
-
-    // Contains the font sprites that are used by some programs.
-    let font: [u8; 80] = [
-		0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-		0x20, 0x60, 0x20, 0x20, 0x70, // 1
-		0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-		0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-		0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-		0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-		0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-		0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-		0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-		0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-		0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-		0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-		0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-		0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-		0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-		0xF0, 0x80, 0xF0, 0x80, 0x80  // F
-	];
-
-    // Initialises and empty memory that is 4kiB in length.
-    let mut memory = [0 as u8; 4096];
-
-    // Insert the font into memory.
-    for (i, byte) in font.iter().enumerate() {
-        memory[i] = *byte;
-    }
-
-    // Insert the program into memory at 0x200.
-    for (i, byte) in program.iter().enumerate() {
-        memory[i + 0x200] = *byte;
-    }
-
-    // Creates an empty cpu with the program and font loaded into memory.
-    let mut cpu = cpu::CPU {
-        registers: [0; 16],
-        program_counter: 0x200,
-        memory,
-        stack: [0; 16],
-        stack_pointer: 0,
-        index_register: 0,
-        delay_timer: Arc::new(Mutex::new(0)),
+    let program = read_program(&program_buf).unwrap();
+    let program_len = program.len();
+
+    // Uses the settings remembered for the program for any flags that were not given.
+    let settings_path = settings_file(cli.data_dir.as_deref(), &program_buf, &program);
+    apply_settings(&mut cli, &matches, Settings::load(&settings_path).unwrap_or_default());
+    if cli.remember {
+        let settings = remembered_settings(&cli);
+        if let Err(error) = settings.save(&settings_path) {
+            eprintln!("Could not save the settings to {}: {}.", settings_path.display(), error);
+        }
+    }
+
+    // Works out where the program goes, which the analysis needs as well as the cpu.
+    let load_address = match cli.load_addr {
+        LoadAddress::Auto => {
+            let (address, reason) = analysis::explain_load_address(&program);
+            eprintln!("Loading at 0x{:03X}, as {}.", address, reason);
+            address
+        },
+        LoadAddress::Fixed(address) => address,
     };
 
-    // Starts the cpu.
-    cpu.run().await;
+    if cli.analyze {
+        println!("{}", analysis::listing(&program, load_address));
+        return;
+    }
+
+    if cli.validate {
+        let warnings = analysis::validate(&program, load_address);
+        for warning in &warnings {
+            println!("{}", warning);
+        }
+        let count = |matches: fn(&Warning) -> bool| warnings.iter().filter(|warning| matches(warning)).count();
+        println!(
+            "{} unknown opcodes, {} addresses outside the program or memory, {} possible stack imbalances.",
+            count(|warning| matches!(warning, Warning::UnknownOpcode { .. })),
+            count(|warning| matches!(warning, Warning::OutsideProgram { .. } | Warning::OutsideMemory { .. })),
+            count(|warning| matches!(warning, Warning::ReturnWithoutCall { .. } | Warning::NeverReturns { .. })),
+        );
+        if !warnings.is_empty() {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if cli.suggest_quirks {
+        let suggestion = analysis::suggest_quirks(&program, load_address);
+        println!("Suggested platform: {:?}", suggestion.platform);
+        for note in suggestion.notes {
+            println!("  {}", note);
+        }
+    }
+
+    if let Some(path) = &cli.disassemble {
+        let data: Vec<_> = cli.data_regions.iter().map(|region| region.0.clone()).collect();
+        let listing = analysis::disassemble(&program, load_address, &data) + "\n";
+        if path.as_os_str() == "-" {
+            print!("{}", listing);
+        } else if let Err(error) = fs::write(path, listing) {
+            eprintln!("Could not write the disassembly to {}: {}.", path.display(), error);
+            process::exit(1);
+        }
+        return;
+    }
+    if load_address + program_len > 0x1000 {
+        eprintln!("The program does not fit in memory when loaded at 0x{:03X}.", load_address);
+        process::exit(1);
+    }
+    // Creates a cpu with the program and font loaded into memory.
+    let mut cpu = CPU::with_load_address(&program, load_address);
+    cpu.low_memory = cli.protect_lowmem;
+    cpu.strict = cli.strict;
+    cpu.set_stack_depth(cli.stack_depth as usize);
+    cpu.variant = cli.variant;
+    cpu.log_collisions = cli.log_collisions;
+    // The terminal display is drawn on stdout, so the log goes to stderr where it can be sent
+    // elsewhere without garbling the display.
+    #[cfg(feature = "terminal")]
+    if cli.terminal {
+        cpu.log = cpu_emulator::cpu::Sink::new(io::stderr());
+    }
+    if cli.profile_draw {
+        cpu.draw_stats = Some(Default::default());
+    }
+    cpu.warn_vf_clobber = cli.warn_vf_clobber;
+    if cli.warn_self_modifying {
+        cpu.executed = Some(Box::new([false; 0x1000]));
+    }
+    cpu.quirks.shift = cli.shift_source;
+    cpu.quirks.edge = cli.edge;
+    cpu.quirks.display_wait = cli.display_wait;
+
+    // Restores the RPL user flags saved by a previous run.
+    let rpl_path = data_path(cli.data_dir.as_deref(), &program_buf, "rpl");
+    if cli.persist_rpl {
+        load_rpl_flags(&mut cpu, &rpl_path);
+    }
+
+    // Randomises the uninitialised memory and registers if requested.
+    let seed = cli.fuzz_memory.map(|seed| seed.unwrap_or_else(rand::random));
+    if let Some(seed) = seed {
+        println!("Fuzzing memory with seed {}.", seed);
+        cpu.fuzz_memory(seed, program_len);
+    }
+    // Fills the display with garbage, from the same seed as the memory if it was fuzzed.
+    if cli.dirty_screen {
+        let seed = seed.unwrap_or_else(|| {
+            let seed = rand::random();
+            println!("Dirtying the screen with seed {}.", seed);
+            seed
+        });
+        cpu.dirty_screen(seed);
+    }
+
+    // Patches memory as asked, after the fuzzing so that the pokes are not overwritten.
+    apply_pokes(&mut cpu, &cli.poke);
+
+    if let (true, Some(address)) = (cli.extract_sprites, cli.at) {
+        println!("{}", ascii::sprites(&cpu.memory, address, cli.count, cli.height as usize));
+        return;
+    }
+
+    if cli.benchmark_rom {
+        let cpu = benchmark(cpu, cli.hz, cli.max_cycles);
+        dump_memory_on_exit(&cli, &cpu);
+        return;
+    }
+
+    if let Some(settings) = &cli.compare {
+        let mut second = cpu.clone();
+        for setting in settings {
+            if let Err(error) = second.quirks.set(setting) {
+                eprintln!("Could not compare with {}: {}.", setting, error);
+                process::exit(1);
+            }
+        }
+        let cpu = compare(cpu, second, cli.hz, cli.max_cycles, io::stdout()).unwrap_or_else(|error| {
+            eprintln!("Could not write the comparison: {}.", error);
+            process::exit(1);
+        });
+        dump_memory_on_exit(&cli, &cpu);
+        return;
+    }
+
+    #[cfg(feature = "terminal")]
+    let backend_name = if cli.terminal { "terminal" } else { "window" };
+    #[cfg(not(feature = "terminal"))]
+    let backend_name = "window";
+
+    // Prints the setup before the backend takes over the screen.
+    if cli.verbose {
+        println!("Program: {} ({} bytes)", program_buf.display(), program_len);
+        println!("Load address: 0x{:03X}", cpu.load_address);
+        println!("Backend: {} (scale {})", backend_name, cli.scale);
+        if cli.authentic {
+            println!("Clock speed: COSMAC VIP instruction costs at {} frames per second, with the display wait", TIMER_HZ);
+        } else {
+            println!("Clock speed: {}Hz at {} frames per second", cli.hz, cli.fps);
+        }
+        // --authentic turns the display wait on once the machine is made.
+        let mut quirks = cpu.quirks;
+        quirks.display_wait |= cli.authentic;
+        println!("Quirks: {}", quirks);
+        println!("Palette: white on black");
+        println!("Initial state: {}", cpu);
+    }
+
+    // Displays the machine in a window, or in the terminal if asked to.
+    let window = || {
+        let mut window = WindowBackend::new(cli.scale as usize);
+        window.aspect = cli.aspect;
+        window.grid = cli.grid;
+        window.crt = cli.crt;
+        window
+    };
+    #[cfg(feature = "terminal")]
+    let backend: Box<dyn Backend> = if cli.terminal {
+        Box::new(cpu_emulator::terminal::TerminalBackend::new())
+    } else {
+        Box::new(window())
+    };
+    #[cfg(not(feature = "terminal"))]
+    let backend: Box<dyn Backend> = Box::new(window());
+
+    let mut chip8 = Chip8::from_cpu(cpu, backend);
+    chip8.hz = cli.hz;
+    chip8.fps = cli.fps;
+    chip8.timer_hz = cli.timer_hz;
+    chip8.frameskip = cli.frameskip;
+    chip8.wait_for_focus = cli.wait_for_focus;
+    if cli.authentic {
+        chip8.authentic();
+    }
+    chip8.mouse = cli.mouse;
+    chip8.idle_polling = cli.idle_polling;
+    chip8.click_on_draw = cli.click_on_draw;
+    chip8.input_delay = Duration::from_millis(cli.input_delay);
+    chip8.draw_delay = Duration::from_millis(cli.draw_delay);
+    if let Some(path) = &cli.trace_binary {
+        match fs::File::create(path) {
+            Ok(file) => chip8.trace = Some(TraceWriter::new(io::BufWriter::new(file))),
+            Err(error) => {
+                eprintln!("Could not create {}: {}.", path.display(), error);
+                process::exit(1);
+            },
+        }
+    }
+    if cli.rewindable {
+        chip8.rewind_frames = cli.rewind_frames;
+    }
+    // Plays the sound through SDL if it can be opened, and carries on silently if not.
+    #[cfg(feature = "sound")]
+    match cpu_emulator::sdl::SdlOutput::open() {
+        Ok(output) => {
+            let tone = cpu_emulator::audio::Tone { waveform: cli.tone, frequency: cli.tone_freq };
+            chip8.audio = Some(cpu_emulator::audio::Audio::new(Box::new(output), tone));
+        },
+        Err(error) => eprintln!("Playing without sound: {}.", error),
+    }
+
+    // Stops the cpu at the end of the frame on Ctrl-C, so that everything is saved and the
+    // backend is shut down properly.
+    let stop = chip8.stop_handle();
+    ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed)).unwrap();
+
+    // Starts the cpu, or hands it over to the debugger.
+    let (mut chip8, result) = if cli.debug {
+        let mut debugger = Debugger::new(chip8);
+        debugger.repl(io::stdin().lock(), io::stdout()).unwrap();
+        (debugger.chip8, Ok(RunOutcome::QuitByUser))
+    } else {
+        let result = chip8.run().await;
+        (chip8, result)
+    };
+
+    if let Some(trace) = &mut chip8.trace {
+        if let Err(error) = trace.flush() {
+            eprintln!("Could not finish writing the trace: {}.", error);
+        }
+    }
+
+    if cli.persist_rpl {
+        if let Err(error) = save_rpl_flags(&chip8.cpu, &rpl_path) {
+            eprintln!("Could not save the RPL flags to {}: {}.", rpl_path.display(), error);
+        }
+    }
+
+    if let Some(path) = &cli.dump_json {
+        if let Err(error) = fs::write(path, chip8.cpu.to_json()) {
+            eprintln!("Could not write the machine state to {}: {}.", path.display(), error);
+        }
+    }
+
+    dump_memory_on_exit(&cli, &chip8.cpu);
+
+    if let Some(stats) = &chip8.cpu.draw_stats {
+        println!("Drawing: {}.", stats);
+        if stats.flickers() && !chip8.cpu.quirks.display_wait {
+            println!(
+                "{} of {} frames drew sprites over each other, so the program may flicker less with --display-wait, which waits for the next frame after each draw.",
+                stats.overdrawn_frames, stats.frames,
+            );
+        }
+    }
+
+    if cli.verbose && !cli.debug {
+        println!("Effective clock speed: {:.0}Hz", chip8.effective_hz());
+    }
+
+    match result {
+        Ok(RunOutcome::ReachedEmptyMemory) => {
+            println!("Reached empty memory at 0x{:03X}.", chip8.cpu.pc() - 2);
+        },
+        Ok(_) => (),
+        Err(error) => {
+            eprintln!("{}.", error);
+            process::exit(1);
+        },
+    }
+}
+
+/// Writes the bytes given by --poke into memory, in order, so a later poke to the same address wins.
+fn apply_pokes(cpu: &mut CPU, pokes: &[Poke]) {
+    for poke in pokes {
+        cpu.memory[poke.address] = poke.value;
+    }
+}
+
+/// Restores the RPL user flags from the file written by save_rpl_flags. A missing file leaves them
+/// as they are, and a short one only restores the flags that it has.
+fn load_rpl_flags(cpu: &mut CPU, path: &Path) {
+    if let Ok(flags) = fs::read(path) {
+        let len = flags.len().min(cpu.rpl_flags.len());
+        cpu.rpl_flags[..len].copy_from_slice(&flags[..len]);
+    }
+}
+
+/// Writes the RPL user flags to the file, creating its directory if needed.
+fn save_rpl_flags(cpu: &CPU, path: &Path) -> io::Result<()> {
+    path.parent().map_or(Ok(()), fs::create_dir_all)?;
+    fs::write(path, cpu.rpl_flags)
+}
+
+/// Writes the memory image to the file given by --dump-memory-on-exit, if there is one. With
+/// --verbose, the hash of the image is printed too, to tell at a glance whether two runs wrote the
+/// same memory.
+fn dump_memory_on_exit(cli: &Cli, cpu: &CPU) {
+    let Some(path) = &cli.dump_memory_on_exit else { return };
+    match dump_memory(cpu, path) {
+        Ok(()) if cli.verbose => println!("Memory hash: {:016x}", settings::hash(&cpu.memory)),
+        Ok(()) => (),
+        Err(error) => eprintln!("Could not write the memory to {}: {}.", path.display(), error),
+    }
+}
+
+/// Writes the raw memory image to the file.
+fn dump_memory(cpu: &CPU, path: &Path) -> io::Result<()> {
+    fs::write(path, cpu.memory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumped_memory_matches_the_cpu() {
+        let mut cpu = CPU::new(&[0x60, 0x2A, 0x12, 0x02]);
+        cpu.memory[0xFFF] = 0x5A;
+        let path = std::env::temp_dir().join(format!("chip8-dump-{}.bin", process::id()));
+
+        dump_memory(&cpu, &path).unwrap();
+        let dumped = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(dumped, cpu.memory);
+    }
+
+    #[test]
+    fn rpl_flags_are_restored_by_the_next_run() {
+        let dir = std::env::temp_dir().join(format!("chip8-rpl-{}", process::id()));
+        let path = dir.join("program.rpl");
+
+        // FX75 stores V0 to V7, then a new run loads them back with FX85.
+        let mut first = CPU::new(&[0xF7, 0x75]);
+        first.registers[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(first.step(), Ok(None));
+        save_rpl_flags(&first, &path).unwrap();
+
+        let mut second = CPU::new(&[0xF7, 0x85]);
+        load_rpl_flags(&mut second, &path);
+        assert_eq!(second.step(), Ok(None));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(second.registers[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn missing_rpl_flags_leave_the_flags_clear() {
+        let mut cpu = CPU::new(&[]);
+        load_rpl_flags(&mut cpu, Path::new("/nonexistent/program.rpl"));
+        assert_eq!(cpu.rpl_flags, [0; 8]);
+    }
+
+    #[test]
+    fn benchmarks_and_comparisons_return_the_memory_the_program_wrote() {
+        // Stores 0x2A at 0x300 and halts.
+        let program = [0x60, 0x2A, 0xA3, 0x00, 0xF0, 0x55, 0x00, 0xFD];
+        assert_eq!(benchmark(CPU::new(&program), 600, 100).memory[0x300], 0x2A);
+        assert_eq!(compare(CPU::new(&program), CPU::new(&program), 600, 100, io::sink()).unwrap().memory[0x300], 0x2A);
+    }
+
+    #[test]
+    fn gzipped_programs_are_loaded_decompressed() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let program = [0x00, 0xE0, 0x60, 0x2A, 0xA2, 0x0A, 0xD0, 0x15, 0x12, 0x08];
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&program).unwrap();
+        let path = std::env::temp_dir().join(format!("chip8-gzip-{}.ch8.gz", process::id()));
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let read = read_program(&path);
+        fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+        assert_eq!(read, program);
+
+        let cpu = CPU::with_load_address(&read, 0x600);
+        assert_eq!(cpu.memory[0x600..0x600 + program.len()], program);
+        assert_eq!(CPU::new(&read).memory[LOAD_ADDRESS..LOAD_ADDRESS + program.len() + 1], [&program[..], &[0]].concat());
+    }
+
+    #[test]
+    fn programs_that_are_not_gzipped_are_read_as_they_are() {
+        let path = std::env::temp_dir().join(format!("chip8-plain-{}.ch8.gz", process::id()));
+        fs::write(&path, [0x1F, 0x00, 0x8B]).unwrap();
+        let read = read_program(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), [0x1F, 0x00, 0x8B]);
+    }
+
+    #[test]
+    fn poked_bytes_are_written_over_the_program() {
+        let cli = Cli::try_parse_from(["cpu-emulator", "--program", "game.ch8", "--poke", "0x202=0x00", "--poke", "515=0xE0", "--poke", "0xFFF=7"]).unwrap();
+        let mut cpu = CPU::new(&[0x60, 0x01, 0x71, 0x02]);
+        apply_pokes(&mut cpu, &cli.poke);
+        assert_eq!(cpu.memory[0x200..0x204], [0x60, 0x01, 0x00, 0xE0]);
+        assert_eq!(cpu.memory[0xFFF], 7);
+    }
+
+    #[test]
+    fn later_pokes_to_the_same_address_win() {
+        let pokes: Vec<Poke> = ["0x300=1", "0x300=2"].iter().map(|poke| poke.parse().unwrap()).collect();
+        let mut cpu = CPU::new(&[]);
+        apply_pokes(&mut cpu, &pokes);
+        assert_eq!(cpu.memory[0x300], 2);
+    }
+
+    #[test]
+    fn pokes_outside_memory_or_a_byte_are_refused() {
+        assert_eq!("0x1000=1".parse::<Poke>().err(), Some("expected an address below 0x1000, found 0x1000".to_string()));
+        assert_eq!("0x300=0x100".parse::<Poke>().err(), Some("expected a byte, found 0x100".to_string()));
+        assert_eq!("0x300".parse::<Poke>().err(), Some("expected ADDR=BYTE, found 0x300".to_string()));
+    }
+
+    #[test]
+    fn the_tone_defaults_to_a_440hz_square_wave() {
+        let cli = Cli::try_parse_from(["cpu-emulator", "--program", "game.ch8"]).unwrap();
+        assert_eq!((cli.tone, cli.tone_freq), (Waveform::Square, 440.0));
+
+        let cli = Cli::try_parse_from(["cpu-emulator", "--program", "game.ch8", "--tone", "sine", "--tone-freq", "220.5"]).unwrap();
+        assert_eq!((cli.tone, cli.tone_freq), (Waveform::Sine, 220.5));
+        assert!(Cli::try_parse_from(["cpu-emulator", "--program", "game.ch8", "--tone-freq", "-5"]).is_err());
+    }
+
+    #[test]
+    fn batches_report_each_program_and_carry_on_past_broken_ones() {
+        let dir = std::env::temp_dir().join(format!("chip8-batch-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("good.ch8"), [0x60, 0x01, 0x00, 0xFD]).unwrap();
+        // Returns from a subroutine that was never called.
+        fs::write(dir.join("broken.ch8"), [0x00, 0xEE]).unwrap();
+        fs::write(dir.join("huge.ch8"), vec![0; 0x1000]).unwrap();
+        fs::write(dir.join("notes.txt"), "not a program").unwrap();
+
+        let mut output = Vec::new();
+        let result = batch(&dir, 600, 1000, &mut output);
+        fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), [
+            "broken.ch8  ERROR Stack underflow at 0x200 after 1 instructions\n",
+            "good.ch8    OK    stopped: Halted after 2 instructions\n",
+            "huge.ch8    ERROR too big to fit in memory\n",
+        ].concat());
+    }
+
+    #[test]
+    fn data_files_go_in_the_data_dir_when_one_is_given() {
+        let program = Path::new("roms/pong.ch8.gz");
+        assert_eq!(data_path(Some(Path::new("/tmp/saves")), program, "rpl"), Path::new("/tmp/saves/pong.rpl"));
+        assert_eq!(data_path(Some(Path::new("saves")), Path::new("tetris.ch8"), "state"), Path::new("saves/tetris.state"));
+        assert_eq!(data_file(Some(Path::new("saves")), program, "1234.settings.json".to_string()), Path::new("saves/1234.settings.json"));
+    }
+
+    #[test]
+    fn data_files_fall_back_to_the_platform_data_dir_or_next_to_the_program() {
+        let program = Path::new("roms/pong.ch8");
+        let expected = match ProjectDirs::from("", "", "chip8-emulator") {
+            Some(dirs) => dirs.data_dir().join("pong.rpl"),
+            None => PathBuf::from("roms/pong.rpl"),
+        };
+        assert_eq!(data_path(None, program, "rpl"), expected);
+    }
+
+    #[test]
+    fn comparisons_only_differ_under_the_shift_quirk_for_a_shift() {
+        // Shifts V0 = 3 right, or V1 = 4 right when the shift quirk reads Vy.
+        let program = [0x60, 0x03, 0x61, 0x04, 0x80, 0x16, 0x00, 0xFD];
+        let run = |setting: &str| {
+            let mut second = CPU::new(&program);
+            second.quirks.set(setting).unwrap();
+            let mut output = Vec::new();
+            compare(CPU::new(&program), second, 600, 100, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(run("shift=vy"), "\
+            Differed after 3 instructions, at 0x204 SHR V0, V1.\n\
+            First:  PC:0206 I:0000 SP:0 DT:00 V:01 04 00 00 00 00 00 00 00 00 00 00 00 00 00 01\n\
+            Second: PC:0206 I:0000 SP:0 DT:00 V:02 04 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n");
+        for setting in ["shift=vx", "edge=wrap", "display-wait=on"] {
+            assert_eq!(run(setting), "No differences before stopping (Halted) after 4 instructions.\n", "{}", setting);
+        }
+    }
+
+    #[test]
+    fn remembered_settings_are_reloaded_for_the_same_program() {
+        let dir = std::env::temp_dir().join(format!("chip8-settings-{}", process::id()));
+        let dir_arg = dir.to_str().unwrap();
+        let run = |args: &[&str], program: &[u8]| {
+            let matches = Cli::command().get_matches_from([&["cpu-emulator", "--data-dir", dir_arg][..], args].concat());
+            let mut cli = Cli::from_arg_matches(&matches).unwrap();
+            let path = settings_file(cli.data_dir.as_deref(), cli.program.as_deref().unwrap(), program);
+            apply_settings(&mut cli, &matches, Settings::load(&path).unwrap_or_default());
+            if cli.remember {
+                remembered_settings(&cli).save(&path).unwrap();
+            }
+            cli
+        };
+
+        run(&["-p", "pong.ch8", "--hz", "900", "--edge", "wrap", "--remember"], &[0x12, 0x00]);
+        // The same program under another name gets the saved settings, except for flags given again.
+        let cli = run(&["-p", "renamed.ch8", "--edge", "clip"], &[0x12, 0x00]);
+        assert_eq!((cli.hz, cli.edge), (900, Edge::Clip));
+        // A different program does not.
+        let cli = run(&["-p", "pong.ch8"], &[0x12, 0x02]);
+        assert_eq!((cli.hz, cli.edge), (DEFAULT_HZ, Edge::Clip));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_display_wait_and_authentic_timing_are_remembered_as_they_were_used() {
+        let dir = std::env::temp_dir().join(format!("chip8-authentic-settings-{}", process::id()));
+        let dir_arg = dir.to_str().unwrap();
+        let run = |args: &[&str]| {
+            let matches = Cli::command().get_matches_from([&["cpu-emulator", "--data-dir", dir_arg, "-p", "pong.ch8"][..], args].concat());
+            let mut cli = Cli::from_arg_matches(&matches).unwrap();
+            let path = settings_file(cli.data_dir.as_deref(), cli.program.as_deref().unwrap(), &[0x12, 0x00]);
+            apply_settings(&mut cli, &matches, Settings::load(&path).unwrap_or_default());
+            if cli.remember {
+                remembered_settings(&cli).save(&path).unwrap();
+            }
+            (cli, Settings::load(&path).unwrap_or_default())
+        };
+
+        let (_, saved) = run(&["--display-wait", "--remember"]);
+        assert_eq!(saved.display_wait, Some(true));
+        let (cli, _) = run(&[]);
+        assert!(cli.display_wait);
+
+        // The VIP's timing is used in place of --hz and --fps, so they are not saved.
+        let (_, saved) = run(&["--authentic", "--remember"]);
+        assert_eq!((saved.authentic, saved.display_wait, saved.hz, saved.fps), (Some(true), Some(true), None, None));
+        let (cli, _) = run(&[]);
+        assert!(cli.authentic);
+        // Giving a speed turns it off again.
+        let (cli, _) = run(&["--hz", "900"]);
+        assert_eq!((cli.authentic, cli.hz), (false, 900));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extract_sprites_needs_an_address() {
+        let cli = Cli::try_parse_from(["cpu-emulator", "-p", "a.ch8", "--extract-sprites", "--at", "0x300", "--count", "2"]).unwrap();
+        assert_eq!((cli.extract_sprites, cli.at, cli.count, cli.height), (true, Some(0x300), 2, 5));
+        assert!(Cli::try_parse_from(["cpu-emulator", "-p", "a.ch8", "--extract-sprites"]).is_err());
+        assert!(Cli::try_parse_from(["cpu-emulator", "-p", "a.ch8", "--at", "0x300"]).is_err());
+    }
 }
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Behaviours that differ between CHIP-8 interpreters, which programs written for one of them may
+/// rely on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quirks {
+    /// Which register 8XY6 and 8XYE shift.
+    pub shift: ShiftSource,
+    /// DXYN waits for the next frame before the program carries on, as the COSMAC VIP waited for
+    /// the display interrupt, so at most one sprite is drawn each frame.
+    pub display_wait: bool,
+    /// What happens to the parts of a sprite that go past the right or bottom edge of the screen.
+    pub edge: Edge,
+}
+
+impl Quirks {
+    /// Changes one quirk from a setting written as name=value: shift=vx|vy, edge=clip|wrap or
+    /// display-wait=on|off.
+    pub fn set(&mut self, setting: &str) -> Result<(), String> {
+        let (name, value) = setting.split_once('=').ok_or_else(|| format!("expected name=value, found {}", setting))?;
+        match name {
+            "shift" => self.shift = value.parse()?,
+            "edge" => self.edge = value.parse()?,
+            "display-wait" => self.display_wait = match value {
+                "on" => true,
+                "off" => false,
+                _ => return Err(format!("expected on or off, found {}", value)),
+            },
+            _ => return Err(format!("unknown quirk {}", name)),
+        }
+        Ok(())
+    }
+}
+
+/// Lists the quirks as the name=value settings that Quirks::set takes, e.g.
+/// `shift=vx edge=clip display-wait=off`.
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let shift = match self.shift {
+            ShiftSource::Vx => "vx",
+            ShiftSource::Vy => "vy",
+        };
+        let edge = match self.edge {
+            Edge::Clip => "clip",
+            Edge::Wrap => "wrap",
+        };
+        let display_wait = if self.display_wait { "on" } else { "off" };
+        write!(f, "shift={} edge={} display-wait={}", shift, edge, display_wait)
+    }
+}
+
+/// The register that is shifted by 8XY6 and 8XYE. In both cases VF is set to the bit shifted out of
+/// the source register, and the result is stored in Vx.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShiftSource {
+    /// Vx is shifted in place and Vy is ignored, as on SUPER-CHIP.
+    #[default]
+    Vx,
+    /// Vy is shifted and the result stored in Vx, as on the COSMAC VIP.
+    Vy,
+}
+
+impl FromStr for ShiftSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vx" => Ok(ShiftSource::Vx),
+            "vy" => Ok(ShiftSource::Vy),
+            _ => Err(format!("expected vx or vy, found {}", s)),
+        }
+    }
+}
+
+/// What DXYN does with the parts of a sprite that go past the edge of the screen. The starting
+/// position always wraps around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Edge {
+    /// They are not drawn, as on most interpreters.
+    #[default]
+    Clip,
+    /// They wrap around and are drawn on the opposite side of the screen.
+    Wrap,
+}
+
+impl FromStr for Edge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clip" => Ok(Edge::Clip),
+            "wrap" => Ok(Edge::Wrap),
+            _ => Err(format!("expected clip or wrap, found {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quirks_are_shown_as_the_settings_that_set_them() {
+        let quirks = Quirks { shift: ShiftSource::Vy, display_wait: true, edge: Edge::Wrap };
+        assert_eq!(quirks.to_string(), "shift=vy edge=wrap display-wait=on");
+
+        let mut parsed = Quirks::default();
+        for setting in quirks.to_string().split(' ') {
+            parsed.set(setting).unwrap();
+        }
+        assert_eq!(parsed, quirks);
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        let mut quirks = Quirks::default();
+        assert_eq!(quirks.set("shift"), Err("expected name=value, found shift".to_string()));
+        assert_eq!(quirks.set("shift=vz"), Err("expected vx or vy, found vz".to_string()));
+        assert_eq!(quirks.set("speed=fast"), Err("unknown quirk speed".to_string()));
+        assert_eq!(quirks, Quirks::default());
+    }
+}
@@ -0,0 +1,43 @@
+//! Plays sound through SDL's audio queue, on any platform that SDL supports.
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+use crate::audio::Output;
+
+/// The rate that samples are asked to be played at. The device may choose another.
+pub const SAMPLE_RATE: i32 = 44100;
+
+/// How far ahead of the speaker samples can be queued, in seconds. Samples queued further ahead
+/// than this are dropped, so that a slow frame cannot build up a delay.
+const LATENCY: f64 = 0.1;
+
+/// The default SDL playback device, playing mono samples.
+pub struct SdlOutput {
+    queue: AudioQueue<f32>,
+}
+
+impl SdlOutput {
+    /// Starts SDL's audio and opens the default playback device. Errors if either is missing.
+    pub fn open() -> Result<SdlOutput, String> {
+        let audio = sdl2::init()?.audio()?;
+        let desired = AudioSpecDesired { freq: Some(SAMPLE_RATE), channels: Some(1), samples: None };
+        let queue = audio.open_queue::<f32, _>(None, &desired)?;
+        queue.resume();
+        Ok(SdlOutput { queue })
+    }
+}
+
+impl Output for SdlOutput {
+    fn sample_rate(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    /// Queues the samples unless more than LATENCY is already waiting to be played.
+    fn write(&mut self, samples: &[f32]) {
+        let queued = self.queue.size() as usize / std::mem::size_of::<f32>();
+        if (queued as f64) < LATENCY * self.sample_rate() as f64 {
+            // A device that has gone away leaves the emulator silent rather than stopping it.
+            let _ = self.queue.queue_audio(samples);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::instruction::Variant;
+use crate::quirks::{Edge, ShiftSource};
+
+/// The settings remembered for a program, so that they do not have to be given again each time it
+/// is run. Settings that were never remembered are None and left as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Settings {
+    pub hz: Option<u32>,
+    pub fps: Option<u32>,
+    pub variant: Option<Variant>,
+    pub shift: Option<ShiftSource>,
+    pub edge: Option<Edge>,
+    pub display_wait: Option<bool>,
+    /// Whether the COSMAC VIP's timing was used, in which case hz and fps are not saved, as they
+    /// were not used.
+    pub authentic: Option<bool>,
+}
+
+impl Settings {
+    /// Reads the settings saved at the path. Returns None if there are none, or they cannot be
+    /// read.
+    pub fn load(path: &Path) -> Option<Settings> {
+        serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+
+    /// Saves the settings to the path, creating its directory if needed.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self).unwrap())
+    }
+}
+
+/// A 64-bit FNV-1a hash of the program, which identifies it however its file is named. This is
+/// written out rather than using std's hasher, which may change between Rust releases.
+pub fn hash(program: &[u8]) -> u64 {
+    program.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::Backend;
+use crate::chip8::Chip8;
+use crate::cpu::{CPU, HEIGHT, WIDTH};
+use crate::error::Error;
+
+/// The version of the state format written by to_json. Bump this when a change means old states
+/// can no longer be loaded.
+pub const STATE_VERSION: u32 = 1;
+
+/// States written before the version was added are the same as version 1.
+fn first_version() -> u32 {
+    1
+}
+
+/// A copy of everything that makes up the state of a running machine, in a form that can be
+/// written out for external debuggers and test harnesses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    /// The version of the format, which must be STATE_VERSION to be loaded.
+    #[serde(default = "first_version")]
+    pub version: u32,
+    pub registers: [u8; 16],
+    pub program_counter: usize,
+    pub load_address: usize,
+    pub index_register: u16,
+    /// As long as the stack's depth, which is 16 unless it was made deeper.
+    pub stack: Vec<u16>,
+    pub stack_pointer: usize,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    /// All 4096 bytes of memory.
+    pub memory: Vec<u8>,
+    /// The 64x32 display, stored row by row.
+    pub framebuffer: Vec<bool>,
+    pub keys: u16,
+    pub waiting_for_key: Option<u8>,
+    pub audio_pattern: [u8; 16],
+    pub pitch: u8,
+    pub rpl_flags: [u8; 8],
+}
+
+impl From<&CPU> for State {
+    fn from(cpu: &CPU) -> State {
+        State {
+            version: STATE_VERSION,
+            registers: cpu.registers,
+            program_counter: cpu.program_counter,
+            load_address: cpu.load_address,
+            index_register: cpu.index_register,
+            stack: cpu.stack.clone(),
+            stack_pointer: cpu.stack_pointer,
+            delay_timer: cpu.delay_timer,
+            sound_timer: cpu.sound_timer,
+            memory: cpu.memory.to_vec(),
+            framebuffer: cpu.framebuffer.to_vec(),
+            keys: cpu.keys,
+            waiting_for_key: cpu.waiting_for_key,
+            audio_pattern: cpu.audio_pattern,
+            pitch: cpu.pitch,
+            rpl_flags: cpu.rpl_flags,
+        }
+    }
+}
+
+impl State {
+    /// Creates a cpu in this state. Errors if the memory or the framebuffer is the wrong size, or
+    /// if the program counter, load address, stack pointer or key being waited for is out of
+    /// range, so that a hand-edited state cannot make the cpu panic. The version is not checked.
+    pub fn to_cpu(&self) -> Result<CPU, Error> {
+        let mut cpu = CPU::new(&[]);
+        let invalid = |reason| Err(Error::InvalidState { reason });
+        if self.memory.len() != cpu.memory.len() {
+            return invalid("the memory is the wrong size");
+        }
+        if self.framebuffer.len() != WIDTH * HEIGHT {
+            return invalid("the framebuffer is the wrong size");
+        }
+        if self.program_counter >= cpu.memory.len() {
+            return invalid("the program counter is past the end of memory");
+        }
+        if self.load_address >= cpu.memory.len() {
+            return invalid("the load address is past the end of memory");
+        }
+        if self.stack_pointer > self.stack.len() {
+            return invalid("the stack pointer is past the end of the stack");
+        }
+        if self.waiting_for_key.is_some_and(|x| x > 0xF) {
+            return invalid("the register waiting for a key does not exist");
+        }
+
+        cpu.registers = self.registers;
+        cpu.program_counter = self.program_counter;
+        cpu.load_address = self.load_address;
+        cpu.index_register = self.index_register;
+        cpu.stack = self.stack.clone();
+        cpu.stack_pointer = self.stack_pointer;
+        cpu.delay_timer = self.delay_timer;
+        cpu.sound_timer = self.sound_timer;
+        cpu.memory.copy_from_slice(&self.memory);
+        cpu.framebuffer.copy_from_slice(&self.framebuffer);
+        cpu.frame_dirty = true;
+        cpu.keys = self.keys;
+        cpu.waiting_for_key = self.waiting_for_key;
+        cpu.audio_pattern = self.audio_pattern;
+        cpu.pitch = self.pitch;
+        cpu.rpl_flags = self.rpl_flags;
+        Ok(cpu)
+    }
+}
+
+impl CPU {
+    /// Dumps the full machine state as JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&State::from(self)).unwrap()
+    }
+
+    /// Creates a cpu from a state dumped by to_json.
+    pub fn from_json(json: &str) -> Result<CPU, String> {
+        let state: State = serde_json::from_str(json).map_err(|error| error.to_string())?;
+        if state.version != STATE_VERSION {
+            return Err(format!("the state is version {}, but only version {} can be loaded", state.version, STATE_VERSION));
+        }
+        state.to_cpu().map_err(|error| error.to_string())
+    }
+}
+
+impl<B: Backend> Chip8<B> {
+    /// Creates a machine from a state file written by CPU::to_json, e.g. with --dump-json, so that
+    /// it carries on from where the state was captured rather than from the start of a program.
+    /// The cpu settings, such as the quirks, are not part of the state and start as the defaults.
+    pub fn from_snapshot(path: impl AsRef<Path>, backend: B) -> Result<Chip8<B>, String> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).map_err(|error| format!("could not read {}: {}", path.display(), error))?;
+        Ok(Chip8::from_cpu(CPU::from_json(&json)?, backend))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cpu part way through a program, with every part of the state away from its default.
+    fn busy_cpu() -> CPU {
+        let mut cpu = CPU::new(&[0x60, 0x12, 0xA3, 0x00, 0x22, 0x08, 0x00, 0xFD, 0xD0, 0x05]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        cpu.registers[0xE] = 0xEE;
+        cpu.delay_timer = 30;
+        cpu.sound_timer = 4;
+        cpu.framebuffer[5] = true;
+        cpu.keys = 0b1010;
+        cpu.waiting_for_key = Some(7);
+        cpu.audio_pattern[3] = 0xF0;
+        cpu.pitch = 112;
+        cpu.rpl_flags[2] = 9;
+        cpu.memory[0xFFF] = 0x42;
+        cpu
+    }
+
+    #[test]
+    fn states_round_trip_through_json() {
+        let cpu = busy_cpu();
+        let loaded = CPU::from_json(&cpu.to_json()).unwrap();
+        assert_eq!(State::from(&loaded), State::from(&cpu));
+        assert_eq!(loaded.program_counter, 0x208);
+        assert_eq!(loaded.stack[0], 0x206);
+    }
+
+    #[test]
+    fn other_versions_are_not_loaded() {
+        let mut state = State::from(&busy_cpu());
+        state.version = STATE_VERSION + 1;
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(
+            CPU::from_json(&json).err(),
+            Some(format!("the state is version {}, but only version {} can be loaded", STATE_VERSION + 1, STATE_VERSION))
+        );
+    }
+
+    #[test]
+    fn states_without_a_version_are_version_1() {
+        let json = CPU::new(&[]).to_json().replacen(&format!("\"version\":{},", STATE_VERSION), "", 1);
+        assert!(!json.contains("version"));
+        assert!(CPU::from_json(&json).is_ok());
+    }
+
+    #[test]
+    fn truncated_memory_is_not_loaded() {
+        let mut state = State::from(&busy_cpu());
+        state.memory.truncate(100);
+        assert_eq!(state.to_cpu().err(), Some(Error::InvalidState { reason: "the memory is the wrong size" }));
+    }
+
+    #[test]
+    fn out_of_range_states_are_not_loaded() {
+        let error = |change: fn(&mut State)| {
+            let mut state = State::from(&busy_cpu());
+            change(&mut state);
+            state.to_cpu().err()
+        };
+        let invalid = |reason| Some(Error::InvalidState { reason });
+        assert_eq!(error(|state| state.waiting_for_key = Some(16)), invalid("the register waiting for a key does not exist"));
+        assert_eq!(error(|state| state.program_counter = usize::MAX), invalid("the program counter is past the end of memory"));
+        assert_eq!(error(|state| state.program_counter = 0x1000), invalid("the program counter is past the end of memory"));
+        assert_eq!(error(|state| state.load_address = 0x1000), invalid("the load address is past the end of memory"));
+        assert_eq!(error(|state| state.stack_pointer = 17), invalid("the stack pointer is past the end of the stack"));
+        assert_eq!(error(|state| state.framebuffer.truncate(100)), invalid("the framebuffer is the wrong size"));
+        assert_eq!(error(|state| state.waiting_for_key = Some(15)), None);
+    }
+
+    #[test]
+    fn invalid_states_are_reported_by_from_json() {
+        let mut state = State::from(&busy_cpu());
+        state.program_counter = usize::MAX;
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(CPU::from_json(&json).err(), Some("Invalid state: the program counter is past the end of memory".to_string()));
+    }
+
+    #[test]
+    fn a_loaded_state_runs_on_from_where_it_was_saved() {
+        let mut cpu = busy_cpu();
+        cpu.waiting_for_key = None;
+        let mut loaded = State::from(&cpu).to_cpu().unwrap();
+        for _ in 0..2 {
+            assert_eq!(loaded.step(), cpu.step());
+        }
+        assert_eq!(State::from(&loaded), State::from(&cpu));
+    }
+}
@@ -0,0 +1,205 @@
+use std::io::{stdout, Stdout, Write};
+use std::time::Duration;
+use crossterm::{cursor, event, execute, queue, style, terminal};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+
+use crate::backend::Backend;
+use crate::cpu::{WIDTH, HEIGHT};
+
+/// A backend that draws the display in the terminal, for use over SSH or in tmux.
+///
+/// If the terminal is too short to fit all 32 rows, two rows of pixels are packed into each row
+/// of text using half-block characters so that the whole display stays visible. If it is too
+/// short even for that, the rows that fit are shown, and the up and down arrows scroll the rest
+/// into view.
+pub struct TerminalBackend {
+    stdout: Stdout,
+    quit: bool,
+    /// The first row of text shown when the display does not fit.
+    scroll: usize,
+}
+
+impl TerminalBackend {
+    /// Switches the terminal into raw mode on the alternate screen.
+    pub fn new() -> TerminalBackend {
+        let mut stdout = stdout();
+        terminal::enable_raw_mode().unwrap();
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide).unwrap();
+
+        TerminalBackend {
+            stdout,
+            quit: false,
+            scroll: 0,
+        }
+    }
+}
+
+impl Default for TerminalBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TerminalBackend {
+    /// Restores the terminal to how it was before the backend was created.
+    fn drop(&mut self) {
+        let _ = execute!(self.stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Returns true if a terminal with the given number of rows is too short to show one row of
+/// pixels per row of text.
+fn needs_half_blocks(rows: u16) -> bool {
+    (rows as usize) < HEIGHT
+}
+
+/// Works out the rows of text that show the framebuffer in a terminal with the given number of
+/// rows, starting scroll rows down if they do not all fit. scroll is clamped so that the last
+/// rows of the display can be scrolled to but not past.
+fn layout(framebuffer: &[bool; WIDTH * HEIGHT], rows: u16, scroll: usize) -> Vec<String> {
+    let lines: Vec<String> = if needs_half_blocks(rows) {
+        // Packs each pair of pixel rows into one row of text.
+        framebuffer.chunks(WIDTH * 2)
+            .map(|pair| {
+                let (top, bottom) = pair.split_at(WIDTH);
+                top.iter().zip(bottom).map(|(&t, &b)| half_block(t, b)).collect()
+            })
+            .collect()
+    } else {
+        framebuffer.chunks(WIDTH).map(|row| row.iter().map(|&on| if on { '█' } else { ' ' }).collect()).collect()
+    };
+
+    let shown = (rows as usize).clamp(1, lines.len());
+    let scroll = scroll.min(lines.len() - shown);
+    lines[scroll..scroll + shown].to_vec()
+}
+
+/// Picks the half-block character that shows the top and bottom pixels of a pair of rows.
+fn half_block(top: bool, bottom: bool) -> char {
+    match (top, bottom) {
+        (true, true) => '█',
+        (true, false) => '▀',
+        (false, true) => '▄',
+        (false, false) => ' ',
+    }
+}
+
+impl Backend for TerminalBackend {
+    /// Draws the rows that fit in the terminal. No newline follows the last row, so that a
+    /// terminal exactly as tall as the display does not scroll.
+    fn present(&mut self, framebuffer: &[bool; WIDTH * HEIGHT]) {
+        let (_, rows) = terminal::size().unwrap_or((WIDTH as u16, HEIGHT as u16));
+        let lines = layout(framebuffer, rows, self.scroll);
+        // Stops the arrows scrolling further than the display goes.
+        let text_rows = if needs_half_blocks(rows) { HEIGHT / 2 } else { HEIGHT };
+        self.scroll = self.scroll.min(text_rows - lines.len());
+
+        queue!(self.stdout, cursor::MoveTo(0, 0), style::Print(lines.join("\r\n"))).unwrap();
+        self.stdout.flush().unwrap();
+    }
+
+    /// Reads any key presses that are waiting. Terminals do not report when a key is released, so
+    /// a key only counts as held on frames where the terminal sends a press or repeat for it.
+    fn keys(&mut self) -> u16 {
+        let mut keys = 0;
+        while event::poll(Duration::ZERO).unwrap_or(false) {
+            if let Ok(Event::Key(KeyEvent { code, modifiers, kind: KeyEventKind::Press, .. })) = event::read() {
+                match code {
+                    KeyCode::Esc => self.quit = true,
+                    KeyCode::Up => self.scroll = self.scroll.saturating_sub(1),
+                    KeyCode::Down => self.scroll += 1,
+                    // Raw mode stops Ctrl-C from sending a signal, so it is handled as a key.
+                    KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+                    KeyCode::Char(c) => if let Some(key) = keypad(c) {
+                        keys |= 1 << key;
+                    },
+                    _ => (),
+                }
+            }
+        }
+        keys
+    }
+
+    fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Rings the terminal bell, which most terminals play as a short tick or flash.
+    fn click(&mut self) {
+        queue!(self.stdout, style::Print('\u{7}')).unwrap();
+    }
+}
+
+/// Maps a character typed in the terminal to a key on the keypad, using the same layout as the
+/// window.
+fn keypad(c: char) -> Option<u8> {
+    match c {
+        '1' => Some(0x1),
+        '2' => Some(0x2),
+        '3' => Some(0x3),
+        '4' => Some(0xC),
+        'q' => Some(0x4),
+        'w' => Some(0x5),
+        'e' => Some(0x6),
+        'r' => Some(0xD),
+        'a' => Some(0x7),
+        's' => Some(0x8),
+        'd' => Some(0x9),
+        'f' => Some(0xE),
+        'z' => Some(0xA),
+        'x' => Some(0x0),
+        'c' => Some(0xB),
+        'v' => Some(0xF),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A display with the top row of pixels and the left half of the bottom row on.
+    fn frame() -> [bool; WIDTH * HEIGHT] {
+        let mut framebuffer = [false; WIDTH * HEIGHT];
+        framebuffer[..WIDTH].fill(true);
+        framebuffer[(HEIGHT - 1) * WIDTH..(HEIGHT - 1) * WIDTH + WIDTH / 2].fill(true);
+        framebuffer
+    }
+
+    #[test]
+    fn a_tall_terminal_gets_a_row_of_text_per_row_of_pixels() {
+        for rows in [32, 50] {
+            let lines = layout(&frame(), rows, 0);
+            assert_eq!(lines.len(), HEIGHT);
+            assert_eq!(lines[0], "█".repeat(WIDTH));
+            assert_eq!(lines[HEIGHT - 1], "█".repeat(WIDTH / 2) + &" ".repeat(WIDTH / 2));
+        }
+    }
+
+    #[test]
+    fn a_short_terminal_packs_two_rows_of_pixels_into_each_row_of_text() {
+        let lines = layout(&frame(), 31, 0);
+        assert_eq!(lines.len(), HEIGHT / 2);
+        assert_eq!(lines[0], "▀".repeat(WIDTH));
+        assert_eq!(lines[HEIGHT / 2 - 1], "▄".repeat(WIDTH / 2) + &" ".repeat(WIDTH / 2));
+    }
+
+    #[test]
+    fn a_very_short_terminal_shows_the_rows_that_are_scrolled_to() {
+        let lines = layout(&frame(), 10, 0);
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], "▀".repeat(WIDTH));
+
+        let lines = layout(&frame(), 10, 100);
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[9], "▄".repeat(WIDTH / 2) + &" ".repeat(WIDTH / 2));
+    }
+
+    #[test]
+    fn keys_map_to_the_same_layout_as_the_window() {
+        assert_eq!(keypad('1'), Some(0x1));
+        assert_eq!(keypad('v'), Some(0xF));
+        assert_eq!(keypad('p'), None);
+    }
+}
@@ -0,0 +1,155 @@
+use std::io::{self, Read, Write};
+
+/// The length of each record in a binary trace.
+pub const RECORD_LEN: usize = 5;
+
+/// The flags byte of a record: VF is not zero after the instruction.
+pub const FLAG_VF: u8 = 1 << 0;
+/// The flags byte of a record: the instruction drew a sprite.
+pub const FLAG_DRAW: u8 = 1 << 1;
+/// The flags byte of a record: the cpu is waiting for a key after the instruction.
+pub const FLAG_WAITING: u8 = 1 << 2;
+
+/// One executed instruction in a binary trace. Each record is 5 bytes with no header or padding:
+///
+/// | Bytes | Contents                                           |
+/// |-------|----------------------------------------------------|
+/// | 0-1   | The address of the instruction, big-endian.        |
+/// | 2-3   | The opcode, big-endian, as it is stored in memory. |
+/// | 4     | Flags: FLAG_VF, FLAG_DRAW and FLAG_WAITING.        |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub pc: u16,
+    pub opcode: u16,
+    pub flags: u8,
+}
+
+impl Record {
+    /// The record as it is written to a trace.
+    pub fn to_bytes(self) -> [u8; RECORD_LEN] {
+        let [pc_high, pc_low] = self.pc.to_be_bytes();
+        let [opcode_high, opcode_low] = self.opcode.to_be_bytes();
+        [pc_high, pc_low, opcode_high, opcode_low, self.flags]
+    }
+
+    /// Reads a record written by to_bytes.
+    pub fn from_bytes(bytes: [u8; RECORD_LEN]) -> Record {
+        Record {
+            pc: u16::from_be_bytes([bytes[0], bytes[1]]),
+            opcode: u16::from_be_bytes([bytes[2], bytes[3]]),
+            flags: bytes[4],
+        }
+    }
+}
+
+/// Writes records to a binary trace.
+pub struct TraceWriter {
+    output: Box<dyn Write>,
+}
+
+impl TraceWriter {
+    /// Writes the trace to the output, which should be buffered as records are written one at a
+    /// time.
+    pub fn new(output: impl Write + 'static) -> TraceWriter {
+        TraceWriter { output: Box::new(output) }
+    }
+
+    /// Appends a record to the trace.
+    pub fn write(&mut self, record: Record) -> io::Result<()> {
+        self.output.write_all(&record.to_bytes())
+    }
+
+    /// Writes out anything that is still buffered.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Reads the records from a binary trace, stopping at the end of the input. A partial record at
+/// the end, e.g. from a trace that was cut off, is an error.
+pub fn read(mut input: impl Read) -> impl Iterator<Item = io::Result<Record>> {
+    std::iter::from_fn(move || {
+        let mut bytes = [0; RECORD_LEN];
+        let mut len = 0;
+        while len < RECORD_LEN {
+            match input.read(&mut bytes[len..]) {
+                Ok(0) if len == 0 => return None,
+                Ok(0) => return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the trace ends part way through a record"))),
+                Ok(n) => len += n,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        Some(Ok(Record::from_bytes(bytes)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Headless;
+    use crate::chip8::Chip8;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// An output that can still be read after a TraceWriter has taken it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn written_records_are_read_back() {
+        let records = [
+            Record { pc: 0x200, opcode: 0x6001, flags: 0 },
+            Record { pc: 0x202, opcode: 0xD015, flags: FLAG_VF | FLAG_DRAW },
+            Record { pc: 0xFFE, opcode: 0xF00A, flags: FLAG_WAITING },
+        ];
+        let output = Shared::default();
+        let mut writer = TraceWriter::new(output.clone());
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let bytes = output.0.borrow().clone();
+        assert_eq!(bytes.len(), 3 * RECORD_LEN);
+        assert_eq!(bytes[5..10], [0x02, 0x02, 0xD0, 0x15, 0b011]);
+        assert_eq!(read(&bytes[..]).collect::<io::Result<Vec<_>>>().unwrap(), records);
+    }
+
+    #[test]
+    fn a_cut_off_record_is_an_error() {
+        let mut bytes = Record { pc: 0x200, opcode: 0x1200, flags: 0 }.to_bytes().to_vec();
+        bytes.extend([0x02, 0x02]);
+        let mut records = read(&bytes[..]);
+        assert_eq!(records.next().unwrap().unwrap(), Record { pc: 0x200, opcode: 0x1200, flags: 0 });
+        assert_eq!(records.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn each_executed_instruction_is_traced() {
+        // Sets VF, draws the 0 glyph, then waits for a key.
+        let output = Shared::default();
+        let mut chip8 = Chip8::new(&[0x6F, 0x01, 0xD0, 0x05, 0xF0, 0x0A], Headless);
+        chip8.trace = Some(TraceWriter::new(output.clone()));
+        for _ in 0..3 {
+            chip8.step().unwrap();
+        }
+
+        let bytes = output.0.borrow().clone();
+        assert_eq!(read(&bytes[..]).collect::<io::Result<Vec<_>>>().unwrap(), [
+            Record { pc: 0x200, opcode: 0x6F01, flags: FLAG_VF },
+            Record { pc: 0x202, opcode: 0xD005, flags: FLAG_DRAW },
+            Record { pc: 0x204, opcode: 0xF00A, flags: FLAG_WAITING },
+        ]);
+    }
+}
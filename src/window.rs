@@ -0,0 +1,497 @@
+use minifb::{Window, WindowOptions, Scale, Key, KeyRepeat, MouseMode};
+use std::str::FromStr;
+
+use crate::ascii;
+use crate::backend::Backend;
+use crate::cpu::{WIDTH, HEIGHT};
+
+/// The number of window pixels used for each CHIP-8 pixel if a scale is not given.
+pub const DEFAULT_SCALE: usize = 16;
+
+/// The title of the window, which the status of the cpu is added to.
+const TITLE: &str = "CHIP-8 Emulator";
+
+/// How the display is fitted into a window that is not exactly the size of the scaled display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Aspect {
+    /// Fill the whole window, even if the pixels end up stretched.
+    #[default]
+    Stretch,
+    /// Scale by the largest whole number that fits, so every pixel is the same size, and centre
+    /// the display with black bars around it.
+    Integer,
+    /// Keep the pixels square, scaling as large as fits, and centre the display with black bars.
+    Square,
+}
+
+impl FromStr for Aspect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stretch" => Ok(Aspect::Stretch),
+            "integer" => Ok(Aspect::Integer),
+            "square" => Ok(Aspect::Square),
+            _ => Err(format!("expected stretch, integer or square, found {}", s)),
+        }
+    }
+}
+
+/// The part of the window that the display is drawn in, in window pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Works out where the display goes in a window of the given size.
+pub fn target_rect(aspect: Aspect, window_width: usize, window_height: usize) -> Rect {
+    let (width, height) = match aspect {
+        Aspect::Stretch => (window_width, window_height),
+        Aspect::Integer => {
+            let scale = (window_width / WIDTH).min(window_height / HEIGHT).max(1);
+            (WIDTH * scale, HEIGHT * scale)
+        },
+        Aspect::Square => {
+            if window_width * HEIGHT > window_height * WIDTH {
+                (window_height * WIDTH / HEIGHT, window_height)
+            } else {
+                (window_width, window_width * HEIGHT / WIDTH)
+            }
+        },
+    };
+
+    Rect {
+        x: window_width.saturating_sub(width) / 2,
+        y: window_height.saturating_sub(height) / 2,
+        width,
+        height,
+    }
+}
+
+/// Works out which display pixel a point in the window is over, given where the display is drawn.
+/// Returns None if the point is outside the display.
+pub fn display_position(rect: Rect, x: f32, y: f32) -> Option<(u8, u8)> {
+    if x < rect.x as f32 || y < rect.y as f32 {
+        return None;
+    }
+    let column = (x as usize - rect.x) * WIDTH / rect.width.max(1);
+    let row = (y as usize - rect.y) * HEIGHT / rect.height.max(1);
+    if column >= WIDTH || row >= HEIGHT {
+        return None;
+    }
+    Some((column as u8, row as u8))
+}
+
+/// A backend that displays the framebuffer in a minifb window.
+pub struct WindowBackend {
+    window: Window,
+    /// Drawn into by present and sized to the window, which the user can resize.
+    buffer: Vec<u32>,
+    /// The size of the window when buffer was last drawn.
+    size: (usize, usize),
+    /// How the display is fitted into the window.
+    pub aspect: Aspect,
+    /// Draws faint lines between the CHIP-8 pixels. Toggled with G.
+    pub grid: bool,
+    /// Darkens every other line of the window and lets lit pixels glow onto the dark ones next to
+    /// them, like an old CRT, by this much from 0 to 1. None draws the pixels plainly.
+    pub crt: Option<f32>,
+    /// The last framebuffer presented, so that it can be drawn again when the grid is toggled.
+    last_frame: [bool; WIDTH * HEIGHT],
+    /// Toggled with Space.
+    paused: bool,
+    /// Set when the right arrow is pressed while paused, until the step is taken.
+    step: bool,
+}
+
+/// The colour of the grid lines.
+const GRID_COLOUR: u32 = 0x404040;
+
+/// Works out the colour of a window pixel with the CRT effect. lit is whether its CHIP-8 pixel
+/// is on, glowing whether one next to it is, and scanline whether the window pixel is on one of the
+/// darkened lines.
+pub fn crt_colour(intensity: f32, lit: bool, glowing: bool, scanline: bool) -> u32 {
+    let mut brightness = if lit {
+        1.0
+    } else if glowing {
+        0.25 * intensity
+    } else {
+        0.0
+    };
+    if scanline {
+        brightness *= 1.0 - 0.5 * intensity;
+    }
+    let level = (brightness.clamp(0.0, 1.0) * 255.0).round() as u32;
+    level << 16 | level << 8 | level
+}
+
+/// Draws the framebuffer into a buffer of window pixels that is width pixels across, fitting it
+/// with the aspect and adding the grid and the CRT effect if they are turned on. Only the window
+/// pixels are changed by the effects, never the framebuffer that the program draws on.
+pub fn render(
+    buffer: &mut [u32],
+    width: usize,
+    framebuffer: &[bool; WIDTH * HEIGHT],
+    aspect: Aspect,
+    grid: bool,
+    crt: Option<f32>,
+) {
+    let height = buffer.len() / width.max(1);
+    let rect = target_rect(aspect, width, height);
+
+    // Below 3 window pixels per CHIP-8 pixel the grid would cover most of the display.
+    let grid = grid && rect.width >= WIDTH * 3 && rect.height >= HEIGHT * 3;
+
+    // Each window pixel in the display takes the colour of the CHIP-8 pixel that it is part of,
+    // and the rest of the window is black.
+    for (i, pixel) in buffer.iter_mut().enumerate() {
+        let (x, y) = (i % width, i / width);
+        let inside = (rect.x..rect.x + rect.width).contains(&x) && (rect.y..rect.y + rect.height).contains(&y);
+        if !inside {
+            *pixel = 0;
+            continue;
+        }
+
+        let (column, row) = ((x - rect.x) * WIDTH / rect.width, (y - rect.y) * HEIGHT / rect.height);
+        // A window pixel is on a grid line if it is the first one in its column or row.
+        let on_grid = (x > rect.x && (x - rect.x - 1) * WIDTH / rect.width != column)
+            || (y > rect.y && (y - rect.y - 1) * HEIGHT / rect.height != row);
+
+        let lit = framebuffer[row * WIDTH + column];
+        *pixel = if grid && on_grid {
+            GRID_COLOUR
+        } else if let Some(intensity) = crt {
+            let glowing = (column > 0 && framebuffer[row * WIDTH + column - 1])
+                || (column + 1 < WIDTH && framebuffer[row * WIDTH + column + 1])
+                || (row > 0 && framebuffer[(row - 1) * WIDTH + column])
+                || (row + 1 < HEIGHT && framebuffer[(row + 1) * WIDTH + column]);
+            crt_colour(intensity, lit, glowing, (y - rect.y) % 2 == 1)
+        } else if lit {
+            u32::MAX
+        } else {
+            0
+        };
+    }
+}
+
+/// Returns the size of the window in pixels when each CHIP-8 pixel is drawn as a scale*scale square.
+pub fn window_size(scale: usize) -> (usize, usize) {
+    (WIDTH * scale, HEIGHT * scale)
+}
+
+impl WindowBackend {
+    /// Initialises the window, drawing each CHIP-8 pixel as a scale*scale square.
+    pub fn new(scale: usize) -> WindowBackend {
+        let (width, height) = window_size(scale);
+
+        // The framebuffer is scaled up by the backend rather than minifb, which only supports
+        // scales that are powers of two. This also lets the display be refitted when the window
+        // is resized.
+        let options = WindowOptions {
+            scale: Scale::X1,
+            resize: true,
+            ..WindowOptions::default()
+        };
+
+        let window = Window::new(
+            TITLE,
+            width,
+            height,
+            options,
+        ).unwrap();
+
+        WindowBackend {
+            window,
+            buffer: vec![0; width * height],
+            size: (width, height),
+            aspect: Aspect::default(),
+            grid: false,
+            crt: None,
+            last_frame: [false; WIDTH * HEIGHT],
+            paused: false,
+            step: false,
+        }
+    }
+}
+
+impl Default for WindowBackend {
+    fn default() -> Self {
+        Self::new(DEFAULT_SCALE)
+    }
+}
+
+impl Backend for WindowBackend {
+    fn present(&mut self, framebuffer: &[bool; WIDTH * HEIGHT]) {
+        self.last_frame = *framebuffer;
+
+        // A minimised window has no pixels to draw into.
+        let (width, height) = self.window.get_size();
+        if width == 0 || height == 0 {
+            self.window.update();
+            return;
+        }
+        self.buffer.resize(width * height, 0);
+        self.size = (width, height);
+        render(&mut self.buffer, width, framebuffer, self.aspect, self.grid, self.crt);
+
+        // Displays the canvas.
+        self.window.update_with_buffer(&self.buffer, width, height).unwrap();
+    }
+
+    /// Polls the window for events and returns the keys that are held.
+    fn keys(&mut self) -> u16 {
+        // Processes the window events so the keys are up to date on frames with no drawing.
+        self.window.update();
+
+        // The display is redrawn to fit when the window has been resized, as the program may not
+        // draw again for a while.
+        let resized = self.window.get_size() != self.size;
+        if self.window.is_key_pressed(Key::G, KeyRepeat::No) {
+            self.grid = !self.grid;
+            let frame = self.last_frame;
+            self.present(&frame);
+        } else if resized {
+            let frame = self.last_frame;
+            self.present(&frame);
+        }
+        // Prints the display for pasting into bug reports.
+        if self.window.is_key_pressed(Key::P, KeyRepeat::No) {
+            println!("{}", ascii::framebuffer(&self.last_frame));
+        }
+        if self.window.is_key_pressed(Key::Space, KeyRepeat::No) {
+            self.paused = !self.paused;
+        }
+        // Holding the right arrow keeps stepping at the key repeat rate.
+        if self.paused && self.window.is_key_pressed(Key::Right, KeyRepeat::Yes) {
+            self.step = true;
+        }
+
+        self.window.get_keys().into_iter()
+            .filter_map(keypad)
+            .fold(0, |keys, key| keys | 1 << key)
+    }
+
+    fn should_quit(&self) -> bool {
+        self.window.is_key_down(Key::Escape)
+    }
+
+    fn mouse(&mut self) -> Option<(u8, u8)> {
+        let (width, height) = self.window.get_size();
+        let (x, y) = self.window.get_mouse_pos(MouseMode::Discard)?;
+        display_position(target_rect(self.aspect, width, height), x, y)
+    }
+
+    fn should_rewind(&self) -> bool {
+        self.window.is_key_down(Key::Backspace)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn should_step(&mut self) -> bool {
+        std::mem::take(&mut self.step)
+    }
+
+    fn is_focused(&mut self) -> bool {
+        self.window.is_active()
+    }
+
+    fn set_status(&mut self, status: &str) {
+        self.window.set_title(&format!("{} - {}", TITLE, status));
+    }
+}
+
+/// Maps a key on the keyboard to a key on the keypad. Mimics the old 16-key keyboard that CHIP-8
+/// programs use.
+fn keypad(key: Key) -> Option<u8> {
+    match key {
+        Key::Key1 => Some(0x1),
+        Key::Key2 => Some(0x2),
+        Key::Key3 => Some(0x3),
+        Key::Key4 => Some(0xC),
+        Key::Q => Some(0x4),
+        Key::W => Some(0x5),
+        Key::E => Some(0x6),
+        Key::R => Some(0xD),
+        Key::A => Some(0x7),
+        Key::S => Some(0x8),
+        Key::D => Some(0x9),
+        Key::F => Some(0xE),
+        Key::Z => Some(0xA),
+        Key::X => Some(0x0),
+        Key::C => Some(0xB),
+        Key::V => Some(0xF),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_window_is_the_display_times_the_scale() {
+        for scale in [1, 10, DEFAULT_SCALE, 32] {
+            assert_eq!(window_size(scale), (scale * 64, scale * 32));
+        }
+    }
+
+    #[test]
+    fn the_display_is_fitted_to_the_window_for_each_aspect() {
+        let rect = |x, y, width, height| Rect { x, y, width, height };
+        assert_eq!(target_rect(Aspect::Stretch, 1000, 600), rect(0, 0, 1000, 600));
+        // 15 is the largest whole scale that fits across, and 18 fits down.
+        assert_eq!(target_rect(Aspect::Integer, 1000, 600), rect(20, 60, 960, 480));
+        assert_eq!(target_rect(Aspect::Square, 1000, 600), rect(0, 50, 1000, 500));
+    }
+
+    #[test]
+    fn square_pixels_are_limited_by_the_shorter_side() {
+        assert_eq!(target_rect(Aspect::Square, 1600, 400), Rect { x: 400, y: 0, width: 800, height: 400 });
+        assert_eq!(target_rect(Aspect::Square, 640, 1000), Rect { x: 0, y: 340, width: 640, height: 320 });
+    }
+
+    #[test]
+    fn integer_scaling_never_goes_below_1() {
+        assert_eq!(target_rect(Aspect::Integer, 50, 20), Rect { x: 0, y: 0, width: 64, height: 32 });
+    }
+
+    #[test]
+    fn aspects_are_parsed_from_their_names() {
+        assert_eq!("integer".parse(), Ok(Aspect::Integer));
+        assert_eq!("wide".parse::<Aspect>(), Err("expected stretch, integer or square, found wide".to_string()));
+    }
+
+    /// A framebuffer with a mix of pixels on and off.
+    fn pattern() -> [bool; WIDTH * HEIGHT] {
+        std::array::from_fn(|i| (i * 7 + i / WIDTH).is_multiple_of(3))
+    }
+
+    /// Renders the framebuffer into a 640x320 window, 10 window pixels to a CHIP-8 pixel.
+    fn render_at_10x(framebuffer: &[bool; WIDTH * HEIGHT], grid: bool) -> Vec<u32> {
+        let mut buffer = vec![0; 640 * 320];
+        render(&mut buffer, 640, framebuffer, Aspect::Stretch, grid, None);
+        buffer
+    }
+
+    #[test]
+    fn the_grid_leaves_the_framebuffer_and_the_pixels_off_the_lines_as_they_were() {
+        let framebuffer = pattern();
+        let before = framebuffer;
+        let plain = render_at_10x(&framebuffer, false);
+        let grid = render_at_10x(&framebuffer, true);
+        assert_eq!(framebuffer, before);
+
+        for (i, (&plain, &grid)) in plain.iter().zip(&grid).enumerate() {
+            let (x, y) = (i % 640, i / 640);
+            // The first window pixel of every CHIP-8 pixel but those on the top and left edges.
+            let on_line = (x > 0 && x % 10 == 0) || (y > 0 && y % 10 == 0);
+            assert_eq!(grid, if on_line { GRID_COLOUR } else { plain }, "at {}, {}", x, y);
+            let lit = framebuffer[y / 10 * WIDTH + x / 10];
+            assert_eq!(plain, if lit { u32::MAX } else { 0 }, "at {}, {}", x, y);
+        }
+    }
+
+    #[test]
+    fn the_grid_is_not_drawn_at_small_scales() {
+        let framebuffer = pattern();
+        let mut plain = vec![0; 128 * 64];
+        let mut grid = vec![0; 128 * 64];
+        render(&mut plain, 128, &framebuffer, Aspect::Stretch, false, None);
+        render(&mut grid, 128, &framebuffer, Aspect::Stretch, true, None);
+        assert_eq!(grid, plain);
+    }
+
+    #[test]
+    fn the_crt_effect_darkens_scanlines_and_lets_lit_pixels_glow() {
+        let level = |level: u32| level << 16 | level << 8 | level;
+        assert_eq!(crt_colour(0.5, true, false, false), level(255));
+        assert_eq!(crt_colour(0.5, true, true, true), level(191));
+        assert_eq!(crt_colour(0.5, false, true, false), level(32));
+        assert_eq!(crt_colour(0.5, false, true, true), level(24));
+        assert_eq!(crt_colour(0.5, false, false, false), 0);
+        // At 0 nothing glows and the scanlines are as bright as the lines between them.
+        assert_eq!(crt_colour(0.0, true, false, true), level(255));
+        assert_eq!(crt_colour(0.0, false, true, false), 0);
+    }
+
+    #[test]
+    fn the_crt_effect_changes_the_window_but_not_the_framebuffer() {
+        let mut framebuffer = [false; WIDTH * HEIGHT];
+        framebuffer[WIDTH + 1] = true;
+        let before = framebuffer;
+        let plain = render_at_10x(&framebuffer, false);
+        let mut crt = vec![0; 640 * 320];
+        render(&mut crt, 640, &framebuffer, Aspect::Stretch, false, Some(0.5));
+        assert_eq!(framebuffer, before);
+        assert_ne!(crt, plain);
+
+        // The lit pixel is darker on the odd lines, and the one to its right glows.
+        let at = |x: usize, y: usize| crt[y * 640 + x];
+        assert_eq!(at(15, 14), crt_colour(0.5, true, false, false));
+        assert_eq!(at(15, 15), crt_colour(0.5, true, false, true));
+        assert_eq!(at(25, 14), crt_colour(0.5, false, true, false));
+        assert_eq!(at(35, 14), 0);
+    }
+
+    #[test]
+    fn the_window_outside_the_display_is_black() {
+        let mut buffer = vec![0x123456; 100 * 32];
+        render(&mut buffer, 100, &[true; WIDTH * HEIGHT], Aspect::Integer, false, None);
+        // The display is 64 pixels wide, centred with 18 pixels either side.
+        assert!(buffer.iter().enumerate().all(|(i, &pixel)| pixel == if (18..82).contains(&(i % 100)) { u32::MAX } else { 0 }));
+    }
+
+    #[test]
+    fn the_mouse_is_scaled_back_to_display_pixels() {
+        // A 640x320 window at scale 10, then the same display letterboxed into a 1000x600 window.
+        let scaled = target_rect(Aspect::Integer, 640, 320);
+        assert_eq!(display_position(scaled, 0.0, 0.0), Some((0, 0)));
+        assert_eq!(display_position(scaled, 325.0, 165.0), Some((32, 16)));
+        assert_eq!(display_position(scaled, 639.0, 319.0), Some((63, 31)));
+        assert_eq!(display_position(scaled, 640.0, 100.0), None);
+
+        let letterboxed = target_rect(Aspect::Integer, 1000, 600);
+        assert_eq!(display_position(letterboxed, 20.0, 60.0), Some((0, 0)));
+        assert_eq!(display_position(letterboxed, 979.0, 539.0), Some((63, 31)));
+        assert_eq!(display_position(letterboxed, 10.0, 300.0), None);
+        assert_eq!(display_position(letterboxed, 500.0, 550.0), None);
+    }
+
+    #[test]
+    fn the_display_fits_inside_and_is_centred_in_windows_of_any_size() {
+        let sizes = [(640, 320), (641, 321), (1000, 600), (600, 1000), (1920, 1080), (64, 32), (100, 40), (3840, 400)];
+        for (window_width, window_height) in sizes {
+            for aspect in [Aspect::Stretch, Aspect::Integer, Aspect::Square] {
+                let rect = target_rect(aspect, window_width, window_height);
+                let at = format!("{:?} in {}x{}", aspect, window_width, window_height);
+                assert!(rect.x + rect.width <= window_width && rect.y + rect.height <= window_height, "{}: {:?}", at, rect);
+                // Centred, to within a pixel when the bars cannot be split evenly.
+                assert!(window_width - rect.width - 2 * rect.x <= 1, "{}: {:?}", at, rect);
+                assert!(window_height - rect.height - 2 * rect.y <= 1, "{}: {:?}", at, rect);
+                // Touches two opposite edges of the window, so no bigger rect would fit.
+                assert!(rect.width == window_width || rect.height == window_height || aspect == Aspect::Integer, "{}: {:?}", at, rect);
+                match aspect {
+                    Aspect::Stretch => assert_eq!((rect.width, rect.height), (window_width, window_height)),
+                    Aspect::Integer => {
+                        assert_eq!((rect.width % WIDTH, rect.height % HEIGHT), (0, 0), "{}: {:?}", at, rect);
+                        assert_eq!(rect.width / WIDTH, rect.height / HEIGHT, "{}: {:?}", at, rect);
+                        assert!(rect.width + WIDTH > window_width || rect.height + HEIGHT > window_height, "{}: {:?}", at, rect);
+                    },
+                    Aspect::Square => assert!(rect.width.abs_diff(rect.height * 2) <= 2, "{}: {:?}", at, rect),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_minimised_window_gets_an_empty_or_the_smallest_rect() {
+        assert_eq!(target_rect(Aspect::Stretch, 0, 0), Rect { x: 0, y: 0, width: 0, height: 0 });
+        assert_eq!(target_rect(Aspect::Square, 0, 0), Rect { x: 0, y: 0, width: 0, height: 0 });
+        assert_eq!(target_rect(Aspect::Integer, 0, 0), Rect { x: 0, y: 0, width: 64, height: 32 });
+    }
+}
@@ -0,0 +1,13 @@
+//! Checks --disassemble's listing format against a listing kept next to a tiny ROM.
+
+use cpu_emulator::analysis;
+
+/// Clears the screen, points I at a sprite with both forms of LD I, draws it and stops. The
+/// sprite's three rows at 0x20C-0x20E are data, and the last byte is left over.
+const TINY: &[u8] = include_bytes!("fixtures/tiny.ch8");
+
+#[test]
+fn a_tiny_rom_disassembles_to_the_expected_listing() {
+    let listing = analysis::disassemble(TINY, 0x200, &[0x20C..=0x20E]) + "\n";
+    assert_eq!(listing, include_str!("fixtures/tiny.lst"));
+}
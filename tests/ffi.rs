@@ -0,0 +1,70 @@
+//! Drives a machine through the C interface, as a C host would.
+#![cfg(feature = "ffi")]
+
+use std::ptr;
+
+use cpu_emulator::ffi::*;
+
+/// Waits for a key, draws its font glyph in the top left corner, then exits.
+const DRAW_KEY: [u8; 8] = [0xF0, 0x0A, 0xF0, 0x29, 0xD1, 0x15, 0x00, 0xFD];
+
+#[test]
+fn a_program_is_stepped_fed_a_key_and_its_display_read() {
+    unsafe {
+        let handle = chip8_create(DRAW_KEY.as_ptr(), DRAW_KEY.len());
+        assert!(!handle.is_null());
+
+        // Nothing runs while FX0A waits, until the key is let go.
+        assert_eq!(chip8_step(handle), CHIP8_RUNNING);
+        chip8_key_down(handle, 5);
+        assert_eq!(chip8_step(handle), CHIP8_RUNNING);
+        chip8_key_up(handle, 5);
+        let results: Vec<i32> = (0..3).map(|_| chip8_step(handle)).collect();
+        assert_eq!(results, [CHIP8_RUNNING, CHIP8_RUNNING, CHIP8_STOPPED]);
+
+        let mut framebuffer = [0xAA; 64 * 32];
+        assert_eq!(chip8_get_framebuffer(handle, framebuffer.as_mut_ptr(), framebuffer.len()), 64 * 32);
+        // The 5 glyph is F0 80 F0 10 F0.
+        let rows: Vec<&[u8]> = (0..5).map(|y| &framebuffer[y * 64..y * 64 + 4]).collect();
+        assert_eq!(rows, [[1, 1, 1, 1], [1, 0, 0, 0], [1, 1, 1, 1], [0, 0, 0, 1], [1, 1, 1, 1]]);
+        assert!(framebuffer[5 * 64..].iter().all(|&pixel| pixel == 0));
+
+        chip8_destroy(handle);
+    }
+}
+
+#[test]
+fn the_framebuffer_is_only_copied_into_the_room_given() {
+    unsafe {
+        let handle = chip8_create(ptr::null(), 0);
+        let mut out = [0xAA; 10];
+        assert_eq!(chip8_get_framebuffer(handle, out.as_mut_ptr(), 4), 4);
+        assert_eq!(out, [0, 0, 0, 0, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA]);
+        assert_eq!(chip8_get_framebuffer(handle, ptr::null_mut(), 2048), 0);
+        assert_eq!(chip8_get_framebuffer(handle, out.as_mut_ptr(), 0), 0);
+        chip8_destroy(handle);
+    }
+}
+
+#[test]
+fn programs_too_big_for_memory_are_not_loaded() {
+    let program = vec![0; 0x1000 - 0x200 + 1];
+    unsafe {
+        assert!(chip8_create(program.as_ptr(), program.len()).is_null());
+        let handle = chip8_create(program.as_ptr(), program.len() - 1);
+        assert!(!handle.is_null());
+        chip8_destroy(handle);
+        chip8_destroy(ptr::null_mut());
+    }
+}
+
+#[test]
+fn errors_are_reported() {
+    // Returns with nothing on the stack.
+    let program = [0x00, 0xEE];
+    unsafe {
+        let handle = chip8_create(program.as_ptr(), program.len());
+        assert_eq!(chip8_step(handle), CHIP8_ERROR);
+        chip8_destroy(handle);
+    }
+}